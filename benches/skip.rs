@@ -0,0 +1,35 @@
+use actix_mw::{SkipRule, SkipSet};
+use actix_web_4::test::TestRequest;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn rules() -> Vec<SkipRule> {
+    (0..50)
+        .flat_map(|i| {
+            [
+                SkipRule::path(format!("/api/v1/service{}/webhooks", i)),
+                SkipRule::pattern(format!("/api/v1/service{}/{{id}}/events", i)),
+            ]
+        })
+        .collect()
+}
+
+fn skip_check(c: &mut Criterion) {
+    let rules = rules();
+    let set = rules.iter().cloned().collect::<SkipSet>();
+    let miss = TestRequest::with_uri("/api/v1/orders/42").to_srv_request();
+    let hit = TestRequest::with_uri("/api/v1/service49/7/events").to_srv_request();
+
+    let mut group = c.benchmark_group("skip");
+    group.bench_function("linear/miss", |b| {
+        b.iter(|| rules.iter().any(|rule| rule.matches(black_box(&miss))))
+    });
+    group.bench_function("set/miss", |b| b.iter(|| set.matches(black_box(&miss))));
+    group.bench_function("linear/hit", |b| {
+        b.iter(|| rules.iter().any(|rule| rule.matches(black_box(&hit))))
+    });
+    group.bench_function("set/hit", |b| b.iter(|| set.matches(black_box(&hit))));
+    group.finish();
+}
+
+criterion_group!(benches, skip_check);
+criterion_main!(benches);
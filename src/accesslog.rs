@@ -0,0 +1,382 @@
+use std::{
+    fmt::Write as _,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    Error, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+
+use crate::{json, CallInfo, Handler, MwContext, SkipRule, SkipSet};
+
+/// One request as recorded by `AccessLog`.
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry {
+    pub method: Method,
+    pub path: String,
+    pub status: StatusCode,
+    pub latency: Duration,
+    /// `None` for streaming bodies.
+    pub size: Option<u64>,
+    pub remote_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    /// The inner service missed its `Handler::timeout`.
+    pub timed_out: bool,
+}
+
+impl AccessLogEntry {
+    /// The entry as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut line = String::with_capacity(256);
+        line.push('{');
+        push_field(&mut line, "method", Some(self.method.as_str()));
+        line.push(',');
+        push_field(&mut line, "path", Some(&self.path));
+        let _ = write!(
+            line,
+            r#","status":{},"latency_ms":{:.3},"size":"#,
+            self.status.as_u16(),
+            self.latency.as_secs_f64() * 1000.0
+        );
+        match self.size {
+            Some(size) => {
+                let _ = write!(line, "{}", size);
+            }
+            None => line.push_str("null"),
+        }
+        line.push(',');
+        push_field(&mut line, "remote_ip", self.remote_ip.as_deref());
+        line.push(',');
+        push_field(&mut line, "user_agent", self.user_agent.as_deref());
+        line.push(',');
+        push_field(&mut line, "request_id", self.request_id.as_deref());
+        let _ = write!(line, r#","timed_out":{}}}"#, self.timed_out);
+        line
+    }
+}
+
+fn push_field(line: &mut String, name: &str, value: Option<&str>) {
+    let _ = write!(line, r#""{}":"#, name);
+    let value = match value {
+        Some(value) => value,
+        None => {
+            line.push_str("null");
+            return;
+        }
+    };
+
+    json::push_str(line, value);
+}
+
+/// Destination of access log entries.
+pub trait LogSink: Send + Sync {
+    fn write(&self, entry: &AccessLogEntry);
+}
+
+/// Writes one JSON object per line.
+pub struct JsonLines<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        JsonLines { out: Mutex::new(out) }
+    }
+}
+
+impl JsonLines<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        JsonLines::new(std::io::stdout())
+    }
+}
+
+impl<W: Write + Send> LogSink for JsonLines<W> {
+    fn write(&self, entry: &AccessLogEntry) {
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", entry.to_json());
+    }
+}
+
+/// Emits every entry as an `INFO` event with target `access_log`.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingSink;
+
+#[cfg(feature = "tracing")]
+impl LogSink for TracingSink {
+    fn write(&self, entry: &AccessLogEntry) {
+        tracing::info!(
+            target: "access_log",
+            method = %entry.method,
+            path = %entry.path,
+            status = entry.status.as_u16(),
+            latency_ms = entry.latency.as_secs_f64() * 1000.0,
+            size = entry.size,
+            remote_ip = entry.remote_ip.as_deref(),
+            user_agent = entry.user_agent.as_deref(),
+            request_id = entry.request_id.as_deref(),
+            timed_out = entry.timed_out,
+        );
+    }
+}
+
+/// Marks requests that passed the exclusion rules.
+#[derive(Clone, Copy)]
+struct Logged;
+
+/// What `on_error` logs about a request that never got a response.
+#[derive(Clone)]
+struct RequestLine {
+    method: Method,
+    path: String,
+    remote_ip: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+/// Records one `AccessLogEntry` per request. Entries are written in `finalize`, so
+/// requests short-circuited by handlers further in are logged too, and in `on_error` for
+/// calls that failed, e.g. with a `504` after a `Handler::timeout`.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<dyn LogSink>,
+    excluded: SkipSet,
+    sample_every: u64,
+    always_log_errors: bool,
+    counter: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn new<S: LogSink + 'static>(sink: S) -> Self {
+        AccessLog {
+            sink: Arc::new(sink),
+            excluded: SkipSet::new(),
+            sample_every: 1,
+            always_log_errors: true,
+            counter: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn tracing() -> Self {
+        AccessLog::new(TracingSink)
+    }
+
+    /// Requests matching `rule` are never logged, e.g. health checks.
+    pub fn exclude(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.excluded.push(rule.into());
+        self
+    }
+
+    /// Logs one in `n` requests. Server errors are still logged every time unless
+    /// `always_log_errors(false)` is set.
+    pub fn sample_every(mut self, n: u64) -> Self {
+        self.sample_every = n.max(1);
+        self
+    }
+
+    pub fn always_log_errors(mut self, always: bool) -> Self {
+        self.always_log_errors = always;
+        self
+    }
+
+    fn sampled(&self, status: StatusCode) -> bool {
+        if self.always_log_errors && status.is_server_error() {
+            return true;
+        }
+        self.counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_every)
+    }
+
+    /// The `MwContext::pin` slot of the `RequestLine`, shared by clones.
+    fn slot(&self) -> usize {
+        Arc::as_ptr(&self.counter) as usize
+    }
+}
+
+fn body_size(size: BodySize) -> Option<u64> {
+    match size {
+        BodySize::None => Some(0),
+        BodySize::Sized(size) => Some(size),
+        BodySize::Stream => None,
+    }
+}
+
+fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn request_id(req: &HttpRequest) -> Option<String> {
+    #[cfg(feature = "request-id")]
+    {
+        if let Some(id) = req.extensions().get::<crate::request_id::RequestId>() {
+            return Some(id.0.clone());
+        }
+    }
+
+    req.headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn remote_ip(req: &HttpRequest) -> Option<String> {
+    crate::client_addr(req).map(|ip| ip.to_string())
+}
+
+impl<B: MessageBody> Handler<B> for AccessLog {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.excluded.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        req.extensions_mut().insert(Logged);
+        let line = RequestLine {
+            method: req.method().clone(),
+            path: req.path().to_string(),
+            remote_ip: remote_ip(req.request()),
+            user_agent: user_agent(req.request()),
+            request_id: request_id(req.request()),
+        };
+        MwContext::of(&req).pin(self.slot(), || Some(line));
+        Either::Right(req)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        let req = resp.request();
+        if req.extensions().get::<Logged>().is_none() || !self.sampled(resp.status()) {
+            return resp;
+        }
+
+        let entry = AccessLogEntry {
+            method: req.method().clone(),
+            path: req.path().to_string(),
+            status: resp.status(),
+            latency: info.elapsed,
+            size: body_size(resp.response().body().size()),
+            remote_ip: remote_ip(req),
+            user_agent: user_agent(req),
+            request_id: request_id(req),
+            timed_out: info.context.timed_out(),
+        };
+        self.sink.write(&entry);
+        resp
+    }
+
+    fn on_error(&self, err: &Error, info: &CallInfo) {
+        let line = match info.context.pin(self.slot(), || None::<RequestLine>) {
+            Some(line) => line,
+            None => return,
+        };
+        let resp = err.error_response();
+        if !self.sampled(resp.status()) {
+            return;
+        }
+
+        let entry = AccessLogEntry {
+            method: line.method,
+            path: line.path,
+            status: resp.status(),
+            latency: info.elapsed,
+            size: body_size(resp.body().size()),
+            remote_ip: line.remote_ip,
+            user_agent: line.user_agent,
+            request_id: info.context.request_id().or(line.request_id),
+            timed_out: info.context.timed_out(),
+        };
+        self.sink.write(&entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{http::header, test, web, App, HttpResponse};
+
+    use super::{AccessLog, AccessLogEntry, LogSink};
+    use crate::Factory;
+
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<AccessLogEntry>>>);
+
+    impl LogSink for Collect {
+        fn write(&self, entry: &AccessLogEntry) {
+            self.0.lock().unwrap().push(entry.clone());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_access_log() {
+        let sink = Collect::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(AccessLog::new(sink.clone()).exclude("/health")))
+                .route("/", web::get().to(|| async { "hello" }))
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header((header::USER_AGENT, "curl/8.0"))
+            .insert_header(("x-forwarded-for", "203.0.113.9"))
+            .to_request();
+        test::call_service(&app, req).await;
+        test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+
+        let entries = sink.0.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, 200);
+        assert_eq!(entries[0].size, Some(5));
+        assert_eq!(entries[0].remote_ip.as_deref(), Some("10.0.0.1"));
+        assert!(entries[0].to_json().contains(r#""user_agent":"curl/8.0""#));
+    }
+
+    #[actix_web::test]
+    #[cfg(feature = "timeout")]
+    async fn test_timed_out() {
+        use std::time::Duration;
+
+        use actix_web::{dev::Service, rt};
+
+        use crate::timeout::Timeout;
+
+        let sink = Collect::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(Timeout::new(Duration::from_millis(10))))
+                .wrap(Factory::new(AccessLog::new(sink.clone())))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        rt::time::sleep(Duration::from_millis(50)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        assert!(app.call(req).await.is_err());
+
+        let entries = sink.0.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "/slow");
+        assert_eq!(entries[0].status, 504);
+        assert!(entries[0].timed_out);
+        assert!(entries[0].to_json().ends_with(r#""timed_out":true}"#));
+    }
+}
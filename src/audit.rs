@@ -0,0 +1,520 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::HeaderName, Method, StatusCode},
+    rt, HttpMessage, HttpRequest,
+};
+use futures_core::future::BoxFuture;
+use futures_util::future::Either;
+
+use crate::{json, CallInfo, Handler, SkipRule, SkipSet};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// The authenticated principal, for authentication schemes this module does not know.
+/// Insert it into the request extensions from a handler or route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditPrincipal(pub String);
+
+/// Extra fields a route wants in its audit event, e.g. the id of the changed record.
+/// Insert it into the request extensions; names listed in `Audit::redact_fields` are masked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditDetails(pub Vec<(String, String)>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    /// `401` or `403`.
+    Denied,
+    /// Any other `4xx`.
+    Rejected,
+    /// `5xx`.
+    Failed,
+}
+
+impl AuditOutcome {
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AuditOutcome::Denied,
+            s if s.is_client_error() => AuditOutcome::Rejected,
+            s if s.is_server_error() => AuditOutcome::Failed,
+            _ => AuditOutcome::Success,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Denied => "denied",
+            AuditOutcome::Rejected => "rejected",
+            AuditOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// One security-relevant request as recorded by `Audit`.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub timestamp: SystemTime,
+    pub principal: Option<String>,
+    /// Method and route pattern, e.g. `DELETE /users/{id}`; the path when no route matched.
+    pub action: String,
+    pub outcome: AuditOutcome,
+    pub status: StatusCode,
+    pub client_ip: Option<String>,
+    pub request_id: Option<String>,
+    /// Recorded headers, secrets already masked.
+    pub headers: Vec<(String, String)>,
+    /// From `AuditDetails`, secrets already masked.
+    pub details: Vec<(String, String)>,
+}
+
+fn push_opt(line: &mut String, value: Option<&str>) {
+    match value {
+        Some(value) => json::push_str(line, value),
+        None => line.push_str("null"),
+    }
+}
+
+fn push_object(line: &mut String, pairs: &[(String, String)]) {
+    line.push('{');
+    for (i, (name, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        json::push_str(line, name);
+        line.push(':');
+        json::push_str(line, value);
+    }
+    line.push('}');
+}
+
+impl AuditEvent {
+    /// The event as a single-line JSON object; `timestamp` is in Unix milliseconds.
+    pub fn to_json(&self) -> String {
+        let millis = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut line = String::with_capacity(256);
+        let _ = write!(line, r#"{{"timestamp":{},"principal":"#, millis);
+        push_opt(&mut line, self.principal.as_deref());
+        line.push_str(r#","action":"#);
+        json::push_str(&mut line, &self.action);
+        let _ = write!(
+            line,
+            r#","outcome":"{}","status":{},"client_ip":"#,
+            self.outcome.as_str(),
+            self.status.as_u16()
+        );
+        push_opt(&mut line, self.client_ip.as_deref());
+        line.push_str(r#","request_id":"#);
+        push_opt(&mut line, self.request_id.as_deref());
+        line.push_str(r#","headers":"#);
+        push_object(&mut line, &self.headers);
+        line.push_str(r#","details":"#);
+        push_object(&mut line, &self.details);
+        line.push('}');
+        line
+    }
+}
+
+/// Destination of audit events. Events are recorded off the response path, on a spawned
+/// task, so a slow sink does not delay responses.
+pub trait AuditSink: Send + Sync {
+    fn record<'a>(&'a self, event: &'a AuditEvent) -> BoxFuture<'a, ()>;
+}
+
+/// Writes one JSON object per line to stdout.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutJson;
+
+impl AuditSink for StdoutJson {
+    fn record<'a>(&'a self, event: &'a AuditEvent) -> BoxFuture<'a, ()> {
+        let line = event.to_json();
+        Box::pin(async move {
+            let _ = writeln!(io::stdout().lock(), "{}", line);
+        })
+    }
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+}
+
+/// Writes one JSON object per line to a file. Once the file would grow past `max_bytes` it
+/// is renamed to `<path>.1` (shifting older files up to `<path>.<keep>`) and a new one is
+/// started. Writes are blocking but short.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    current: Mutex<Option<OpenFile>>,
+}
+
+impl RotatingFile {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        RotatingFile {
+            path: path.into(),
+            max_bytes,
+            keep,
+            current: Mutex::new(None),
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenFile { file, size })
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let len = line.len() as u64 + 1;
+        if let Some(open) = current.as_ref() {
+            if open.size > 0 && open.size + len > self.max_bytes {
+                *current = None;
+                self.rotate()?;
+            }
+        }
+        if current.is_none() {
+            *current = Some(self.open()?);
+        }
+
+        let open = current.as_mut().unwrap();
+        writeln!(open.file, "{}", line)?;
+        open.size += len;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RotatingFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatingFile")
+            .field("path", &self.path)
+            .field("max_bytes", &self.max_bytes)
+            .field("keep", &self.keep)
+            .finish()
+    }
+}
+
+impl AuditSink for RotatingFile {
+    fn record<'a>(&'a self, event: &'a AuditEvent) -> BoxFuture<'a, ()> {
+        let line = event.to_json();
+        Box::pin(async move {
+            if let Err(err) = self.write_line(&line) {
+                log::error!("failed to write audit event to {}: {}", self.path.display(), err);
+            }
+        })
+    }
+}
+
+/// Marks requests that passed the exclusion rules.
+#[derive(Clone, Copy)]
+struct Audited;
+
+type PrincipalFn = dyn Fn(&HttpRequest) -> Option<String> + Send + Sync;
+
+/// Records an `AuditEvent` for every state-changing request, and for reads that were
+/// denied. Events are built in `finalize`, after the route ran, so principals that are
+/// only known once authenticated (e.g. `BasicUser`) are included.
+#[derive(Clone)]
+pub struct Audit {
+    sink: Arc<dyn AuditSink>,
+    excluded: SkipSet,
+    include_reads: bool,
+    record_headers: Vec<HeaderName>,
+    redact_headers: Vec<HeaderName>,
+    redact_fields: Vec<String>,
+    principal: Option<Arc<PrincipalFn>>,
+}
+
+impl Audit {
+    pub fn new<S: AuditSink + 'static>(sink: S) -> Self {
+        Audit {
+            sink: Arc::new(sink),
+            excluded: SkipSet::new(),
+            include_reads: false,
+            record_headers: vec![],
+            redact_headers: ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+                .into_iter()
+                .map(HeaderName::from_static)
+                .collect(),
+            redact_fields: vec![],
+            principal: None,
+        }
+    }
+
+    pub fn exclude(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.excluded.push(rule.into());
+        self
+    }
+
+    /// Also record successful `GET`, `HEAD` and `OPTIONS` requests.
+    pub fn include_reads(mut self, include: bool) -> Self {
+        self.include_reads = include;
+        self
+    }
+
+    /// Request headers copied into each event.
+    pub fn record_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.record_headers = headers;
+        self
+    }
+
+    /// Headers whose values are masked; replaces the default list of credential headers.
+    pub fn redact_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.redact_headers = headers;
+        self
+    }
+
+    /// `AuditDetails` names whose values are masked, compared case-insensitively.
+    pub fn redact_fields<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.redact_fields = fields.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Resolves the principal, e.g. the subject of JWT claims; `AuditPrincipal` and the
+    /// identities of this crate's `auth` handlers are used otherwise.
+    pub fn with_principal<F>(mut self, principal: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.principal = Some(Arc::new(principal));
+        self
+    }
+
+    fn principal(&self, req: &HttpRequest) -> Option<String> {
+        if let Some(principal) = self.principal.as_ref().and_then(|f| f(req)) {
+            return Some(principal);
+        }
+
+        let ext = req.extensions();
+        if let Some(AuditPrincipal(principal)) = ext.get::<AuditPrincipal>() {
+            return Some(principal.clone());
+        }
+        #[cfg(feature = "auth-apikey")]
+        {
+            if let Some(identity) = ext.get::<crate::auth::ApiKeyIdentity>() {
+                return Some(identity.0.clone());
+            }
+        }
+        #[cfg(feature = "auth-basic")]
+        {
+            if let Some(user) = ext.get::<crate::auth::BasicUser>() {
+                return Some(user.0.clone());
+            }
+        }
+        None
+    }
+
+    fn client_ip(req: &HttpRequest) -> Option<String> {
+        crate::client_addr(req).map(|ip| ip.to_string())
+    }
+
+    fn request_id(req: &HttpRequest) -> Option<String> {
+        #[cfg(feature = "request-id")]
+        {
+            if let Some(id) = req.extensions().get::<crate::request_id::RequestId>() {
+                return Some(id.0.clone());
+            }
+        }
+
+        req.headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    }
+
+    fn event(&self, req: &HttpRequest, status: StatusCode) -> AuditEvent {
+        let action = format!(
+            "{} {}",
+            req.method(),
+            req.match_pattern().unwrap_or_else(|| req.path().to_string())
+        );
+        let headers = self
+            .record_headers
+            .iter()
+            .filter_map(|name| {
+                let value = req.headers().get(name)?;
+                let value = if self.redact_headers.contains(name) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                Some((name.to_string(), value))
+            })
+            .collect();
+        let details = req
+            .extensions()
+            .get::<AuditDetails>()
+            .map(|AuditDetails(details)| {
+                details
+                    .iter()
+                    .map(|(name, value)| {
+                        let redact = self.redact_fields.iter().any(|f| f.eq_ignore_ascii_case(name));
+                        (name.clone(), if redact { REDACTED.to_string() } else { value.clone() })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        AuditEvent {
+            timestamp: SystemTime::now(),
+            principal: self.principal(req),
+            action,
+            outcome: AuditOutcome::from_status(status),
+            status,
+            client_ip: Audit::client_ip(req),
+            request_id: Audit::request_id(req),
+            headers,
+            details,
+        }
+    }
+}
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Audit")
+            .field("excluded", &self.excluded)
+            .field("include_reads", &self.include_reads)
+            .field("record_headers", &self.record_headers)
+            .field("redact_headers", &self.redact_headers)
+            .field("redact_fields", &self.redact_fields)
+            .finish()
+    }
+}
+
+impl<B> Handler<B> for Audit {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.excluded.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        req.extensions_mut().insert(Audited);
+        Either::Right(req)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let req = resp.request();
+        if req.extensions().get::<Audited>().is_none() {
+            return resp;
+        }
+
+        let read = [Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method());
+        let outcome = AuditOutcome::from_status(resp.status());
+        if read && !self.include_reads && outcome != AuditOutcome::Denied {
+            return resp;
+        }
+
+        let event = self.event(req, resp.status());
+        let sink = self.sink.clone();
+        rt::spawn(async move { sink.record(&event).await });
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use actix_web::{
+        http::header::{self, HeaderName},
+        rt, test, web, App, HttpMessage, HttpRequest, HttpResponse,
+    };
+    use futures_core::future::BoxFuture;
+
+    use super::{Audit, AuditDetails, AuditEvent, AuditOutcome, AuditPrincipal, AuditSink};
+    use crate::Factory;
+
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<AuditEvent>>>);
+
+    impl AuditSink for Collect {
+        fn record<'a>(&'a self, event: &'a AuditEvent) -> BoxFuture<'a, ()> {
+            self.0.lock().unwrap().push(event.clone());
+            Box::pin(async {})
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_audit() {
+        let sink = Collect::default();
+        let audit = Audit::new(sink.clone())
+            .record_headers(vec![header::AUTHORIZATION, HeaderName::from_static("x-tenant")])
+            .redact_fields(["password"]);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(audit))
+                .route(
+                    "/users/{id}",
+                    web::post().to(|req: HttpRequest| async move {
+                        req.extensions_mut().insert(AuditPrincipal("alice".to_string()));
+                        req.extensions_mut().insert(AuditDetails(vec![
+                            ("id".to_string(), "7".to_string()),
+                            ("password".to_string(), "hunter2".to_string()),
+                        ]));
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+                .route("/admin", web::get().to(HttpResponse::Forbidden)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users/7")
+            .insert_header((header::AUTHORIZATION, "Bearer t"))
+            .insert_header(("x-tenant", "acme"))
+            .to_request();
+        test::call_service(&app, req).await;
+        test::call_service(&app, test::TestRequest::get().uri("/users/7").to_request()).await;
+        test::call_service(&app, test::TestRequest::get().uri("/admin").to_request()).await;
+        rt::time::sleep(Duration::from_millis(10)).await;
+
+        let events = sink.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, "POST /users/{id}");
+        assert_eq!(events[0].principal.as_deref(), Some("alice"));
+        assert_eq!(events[0].headers[0].1, "[REDACTED]");
+        assert_eq!(events[0].headers[1].1, "acme");
+        assert_eq!(events[0].details[1].1, "[REDACTED]");
+        assert_eq!(events[1].outcome, AuditOutcome::Denied);
+    }
+}
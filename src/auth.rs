@@ -0,0 +1,13 @@
+#[cfg(feature = "auth-apikey")]
+mod apikey;
+#[cfg(feature = "auth-basic")]
+mod basic;
+#[cfg(feature = "auth-jwt")]
+mod jwt;
+
+#[cfg(feature = "auth-apikey")]
+pub use apikey::{ApiKeyAuth, ApiKeyIdentity, ApiKeySource, KeyValidator, StaticKeys, StoreKeys};
+#[cfg(feature = "auth-basic")]
+pub use basic::{BasicAuth, BasicUser, CredentialVerifier, StaticCredentials};
+#[cfg(feature = "auth-jwt")]
+pub use jwt::{Claims, JwtAuth};
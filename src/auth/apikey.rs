@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::HeaderName,
+    web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    CachedPolicies, FromBoxBody, Handler, Identity, MwContext, MwError, SkipRule, SkipSet, Store, StoreError, Verdict,
+};
+
+/// Identity the presented API key resolved to, available to route handlers behind `ApiKeyAuth`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiKeyIdentity(pub String);
+
+impl FromRequest for ApiKeyIdentity {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<ApiKeyIdentity>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("API key").into()),
+        )
+    }
+}
+
+/// Resolves an API key to the identity it belongs to; see `StoreKeys` for keys kept in a
+/// `Store`.
+pub trait KeyValidator: Send + Sync {
+    fn validate(&self, key: &str) -> Option<String>;
+}
+
+impl<F> KeyValidator for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+{
+    fn validate(&self, key: &str) -> Option<String> {
+        self(key)
+    }
+}
+
+/// A fixed set of keys, compared in constant time.
+#[derive(Clone, Default)]
+pub struct StaticKeys {
+    keys: Vec<(String, String)>,
+}
+
+impl StaticKeys {
+    pub fn new() -> Self {
+        StaticKeys::default()
+    }
+
+    pub fn key(mut self, key: &str, identity: &str) -> Self {
+        self.keys.push((key.to_string(), identity.to_string()));
+        self
+    }
+}
+
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.keys.iter().map(|(_, identity)| identity)).finish()
+    }
+}
+
+impl KeyValidator for StaticKeys {
+    fn validate(&self, key: &str) -> Option<String> {
+        // compare against every key so timing does not reveal which one matched
+        let mut found = None;
+        for (candidate, identity) in &self.keys {
+            if bool::from(candidate.as_bytes().ct_eq(key.as_bytes())) {
+                found = Some(identity);
+            }
+        }
+        found.cloned()
+    }
+}
+
+/// API keys kept in a `Store`, e.g. one shared with the service that issues them: the value
+/// at `<prefix><key>` is the identity of `key`. Looked up in `Handler::verify`.
+#[derive(Clone)]
+pub struct StoreKeys {
+    store: Arc<dyn Store>,
+    prefix: String,
+}
+
+impl StoreKeys {
+    /// Keys under the `apikey:` prefix.
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        StoreKeys {
+            store,
+            prefix: "apikey:".to_string(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    async fn validate(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let identity = self.store.get(&format!("{}{}", self.prefix, key)).await?;
+        Ok(identity.and_then(|identity| String::from_utf8(identity).ok()))
+    }
+}
+
+impl fmt::Debug for StoreKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreKeys").field("prefix", &self.prefix).finish()
+    }
+}
+
+#[derive(Clone)]
+enum Validator {
+    Local(Arc<dyn KeyValidator>),
+    Store(StoreKeys),
+}
+
+/// A presented key `verify` looks up in the `StoreKeys`.
+struct PendingKey(String);
+
+/// Where the API key is read from; sources are tried in order.
+#[derive(Clone, Debug)]
+pub enum ApiKeySource {
+    Header(HeaderName),
+    Query(String),
+}
+
+impl ApiKeySource {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            ApiKeySource::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            ApiKeySource::Query(param) => {
+                let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?;
+                query.into_inner().remove(param)
+            }
+        }
+    }
+}
+
+/// Requires a valid API key: `401` when none is presented, `403` when it is unknown.
+///
+/// Routes can also require a scope (`require_scope`), checked against the identity's
+/// `Policy` from `with_policies`: `403` when the scope is not granted, and `503` while
+/// the identity's first policy lookup is still running, as the check fails closed.
+#[derive(Clone)]
+pub struct ApiKeyAuth {
+    validator: Validator,
+    sources: Vec<ApiKeySource>,
+    skip_rules: SkipSet,
+    policies: Option<CachedPolicies>,
+    scopes: Vec<(SkipRule, String)>,
+}
+
+impl ApiKeyAuth {
+    /// Reads the key from the `x-api-key` header.
+    pub fn new<V: KeyValidator + 'static>(validator: V) -> Self {
+        ApiKeyAuth::with_validator(Validator::Local(Arc::new(validator)))
+    }
+
+    /// Looks keys up in `keys`; a failing store answers `503`.
+    pub fn from_store(keys: StoreKeys) -> Self {
+        ApiKeyAuth::with_validator(Validator::Store(keys))
+    }
+
+    fn with_validator(validator: Validator) -> Self {
+        ApiKeyAuth {
+            validator,
+            sources: vec![ApiKeySource::Header(HeaderName::from_static("x-api-key"))],
+            skip_rules: SkipSet::new(),
+            policies: None,
+            scopes: vec![],
+        }
+    }
+
+    pub fn with_sources(mut self, sources: Vec<ApiKeySource>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    pub fn with_skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+
+    pub fn with_policies(mut self, policies: CachedPolicies) -> Self {
+        self.policies = Some(policies);
+        self
+    }
+
+    /// Requests matching `rule` need `scope`; panics without `with_policies`, so call it
+    /// first.
+    pub fn require_scope(mut self, rule: impl Into<SkipRule>, scope: &str) -> Self {
+        assert!(self.policies.is_some(), "ApiKeyAuth::require_scope needs with_policies");
+        self.scopes.push((rule.into(), scope.to_string()));
+        self
+    }
+
+    fn check_scopes(&self, req: &ServiceRequest, identity: &str) -> Result<(), MwError> {
+        let mut required = self.scopes.iter().filter(|(rule, _)| rule.matches(req)).peekable();
+        let policies = match (required.peek(), &self.policies) {
+            (Some(_), Some(policies)) => policies,
+            _ => return Ok(()),
+        };
+        let policy = policies
+            .get(&Identity(identity.to_string()))
+            .ok_or(MwError::Unavailable("policy_unavailable"))?;
+        if required.all(|(_, scope)| policy.allows(scope)) {
+            Ok(())
+        } else {
+            Err(MwError::Forbidden("insufficient_scope"))
+        }
+    }
+
+    /// Lets the caller of a known key in as its identity, unless a required scope is missing.
+    fn accept(&self, req: &ServiceRequest, identity: Option<String>) -> Result<(), MwError> {
+        let identity = identity.ok_or(MwError::Forbidden("invalid_api_key"))?;
+        self.check_scopes(req, &identity)?;
+        MwContext::of(req).set_principal(identity.clone());
+        req.extensions_mut().insert(ApiKeyIdentity(identity));
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ApiKeyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyAuth")
+            .field("sources", &self.sources)
+            .field("skip_rules", &self.skip_rules)
+            .field("policies", &self.policies)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for ApiKeyAuth {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.skip_rules.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let key = match self.sources.iter().find_map(|source| source.extract(&req)) {
+            Some(key) => key,
+            None => return Either::Left(MwError::Unauthorized("missing_api_key").reject(req)),
+        };
+        let identity = match &self.validator {
+            Validator::Local(validator) => validator.validate(&key),
+            Validator::Store(_) => {
+                req.extensions_mut().insert(PendingKey(key));
+                return Either::Right(req);
+            }
+        };
+        match self.accept(&req, identity) {
+            Ok(()) => Either::Right(req),
+            Err(err) => Either::Left(err.reject(req)),
+        }
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let PendingKey(key) = req.extensions_mut().remove::<PendingKey>()?;
+        let keys = match &self.validator {
+            Validator::Store(keys) => keys.clone(),
+            Validator::Local(_) => return None,
+        };
+        let auth = self.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            let identity = match keys.validate(&key).await {
+                Ok(identity) => identity,
+                Err(err) => return Err(MwError::Store(err).respond(req)),
+            };
+            let req = ServiceRequest::from_request(req);
+            auth.accept(&req, identity).map_err(|err| err.reject(req))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::HeaderName, StatusCode},
+        test, web, App,
+    };
+
+    use super::{ApiKeyAuth, ApiKeyIdentity, ApiKeySource, StaticKeys};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_api_key() {
+        let auth = ApiKeyAuth::new(StaticKeys::new().key("k-123", "billing")).with_sources(vec![
+            ApiKeySource::Header(HeaderName::from_static("x-api-key")),
+            ApiKeySource::Query("api_key".to_string()),
+        ]);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(auth))
+                .route("/", web::get().to(|identity: ApiKeyIdentity| async move { identity.0 })),
+        )
+        .await;
+
+        let req = test::TestRequest::get().insert_header(("x-api-key", "k-123")).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "billing");
+        let req = test::TestRequest::get().uri("/?api_key=k-123").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "billing");
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get().insert_header(("x-api-key", "k-124")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_store_keys() {
+        use std::sync::Arc;
+
+        use super::StoreKeys;
+        use crate::{InMemoryStore, MwContext, Store};
+
+        let store = Arc::new(InMemoryStore::new());
+        store.set("apikey:k-123", b"billing".to_vec(), None).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(ApiKeyAuth::from_store(StoreKeys::new(store))))
+                .route("/", web::get().to(|context: MwContext| async move { context.principal().unwrap() })),
+        )
+        .await;
+
+        let req = test::TestRequest::get().insert_header(("x-api-key", "k-123")).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "billing");
+        let req = test::TestRequest::get().insert_header(("x-api-key", "k-124")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
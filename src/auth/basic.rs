@@ -0,0 +1,213 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_core::future::BoxFuture;
+use futures_util::future::Either;
+use subtle::ConstantTimeEq;
+
+use crate::{FromBoxBody, Handler, MwContext, MwError, SkipRule, SkipSet, Verdict};
+
+/// Checks a username and password, e.g. against a user database.
+pub trait CredentialVerifier: Send + Sync {
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> BoxFuture<'a, bool>;
+}
+
+/// A fixed set of users, compared in constant time.
+#[derive(Clone, Default)]
+pub struct StaticCredentials {
+    users: Vec<(String, String)>,
+}
+
+impl StaticCredentials {
+    pub fn new() -> Self {
+        StaticCredentials::default()
+    }
+
+    pub fn user(mut self, username: &str, password: &str) -> Self {
+        self.users.push((username.to_string(), password.to_string()));
+        self
+    }
+}
+
+impl fmt::Debug for StaticCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.users.iter().map(|(username, _)| username)).finish()
+    }
+}
+
+impl CredentialVerifier for StaticCredentials {
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> BoxFuture<'a, bool> {
+        let mut valid = false;
+        for (u, p) in &self.users {
+            let matches = u.as_bytes().ct_eq(username.as_bytes()) & p.as_bytes().ct_eq(password.as_bytes());
+            valid |= bool::from(matches);
+        }
+        Box::pin(async move { valid })
+    }
+}
+
+/// The authenticated username, available to route handlers behind `BasicAuth`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicUser(pub String);
+
+impl FromRequest for BasicUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<BasicUser>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("Basic auth").into()),
+        )
+    }
+}
+
+/// HTTP Basic authentication. `process` rejects requests without well-formed credentials;
+/// the `CredentialVerifier` runs in `verify`, before the inner service is called.
+#[derive(Clone)]
+pub struct BasicAuth {
+    verifier: Arc<dyn CredentialVerifier>,
+    challenge: HeaderValue,
+    skip_rules: SkipSet,
+}
+
+impl BasicAuth {
+    pub fn new<V: CredentialVerifier + 'static>(verifier: V) -> Self {
+        BasicAuth {
+            verifier: Arc::new(verifier),
+            challenge: HeaderValue::from_static(r#"Basic realm="Restricted", charset="UTF-8""#),
+            skip_rules: SkipSet::new(),
+        }
+    }
+
+    /// Fails if `realm` cannot be sent in a header, e.g. because it contains a newline.
+    pub fn with_realm(mut self, realm: &str) -> Result<Self, MwError> {
+        let challenge = format!(r#"Basic realm="{}", charset="UTF-8""#, realm.replace('"', "\\\""));
+        self.challenge = HeaderValue::from_str(&challenge)
+            .map_err(|_| MwError::Config(format!("invalid Basic auth realm {:?}", realm)))?;
+        Ok(self)
+    }
+
+    pub fn with_skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("challenge", &self.challenge)
+            .field("skip_rules", &self.skip_rules)
+            .finish()
+    }
+}
+
+fn credentials(req: &ServiceRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for BasicAuth {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.skip_rules.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if credentials(&req).is_some() {
+            return Either::Right(req);
+        }
+        let mut resp = MwError::Unauthorized("missing_credentials").reject(req);
+        resp.headers_mut().insert(header::WWW_AUTHENTICATE, self.challenge.clone());
+        Either::Left(resp)
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let (username, password) = credentials(req)?;
+        let verifier = self.verifier.clone();
+        let challenge = self.challenge.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            if verifier.verify(&username, &password).await {
+                MwContext::of(&req).set_principal(username.clone());
+                req.extensions_mut().insert(BasicUser(username));
+                return Ok(());
+            }
+
+            let mut resp: ServiceResponse<B> = MwError::Unauthorized("invalid_credentials").respond(req);
+            resp.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+            Err(resp)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, web, App, HttpResponse,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::{BasicAuth, BasicUser, StaticCredentials};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_basic_auth() {
+        let auth = BasicAuth::new(StaticCredentials::new().user("alice", "secret"))
+            .with_realm("Admin")
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(auth))
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/me", web::get().to(|user: BasicUser| async move { user.0 })),
+        )
+        .await;
+        let get = |uri: &str, credentials: Option<&str>| {
+            let req = test::TestRequest::get().uri(uri);
+            match credentials {
+                Some(credentials) => req.insert_header((
+                    header::AUTHORIZATION,
+                    format!("Basic {}", STANDARD.encode(credentials)),
+                )),
+                None => req,
+            }
+            .to_request()
+        };
+
+        let resp = test::call_service(&app, get("/", None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        // the route does not extract `BasicUser`, so only the middleware can reject this
+        let resp = test::call_service(&app, get("/", Some("alice:wrong"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        let resp = test::call_service(&app, get("/", Some("alice:secret"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::call_and_read_body(&app, get("/me", Some("alice:secret"))).await;
+        assert_eq!(body, "alice");
+
+        assert!(BasicAuth::new(StaticCredentials::new()).with_realm("a\nb").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    future::{ready, Ready},
+    marker::PhantomData,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use futures_util::future::Either;
+use jsonwebtoken::{decode, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{FromBoxBody, Handler, MwContext, MwError, Secret, SecretError, SecretSource, SkipRule, SkipSet};
+
+/// Decoded claims of the bearer token, available to route handlers behind `JwtAuth<T>`.
+#[derive(Clone, Debug)]
+pub struct Claims<T>(pub T);
+
+impl<T: Clone + 'static> FromRequest for Claims<T> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Claims<T>>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("JWT").into()),
+        )
+    }
+}
+
+/// HMAC keys stay a `Secret`; `jsonwebtoken` only gets a copy while it verifies a token.
+#[derive(Clone)]
+enum JwtKey {
+    Hmac(Secret),
+    Public(DecodingKey),
+}
+
+/// Validates `Authorization: Bearer` tokens and stores their claims as `Claims<T>`, and the
+/// `sub` claim as the `MwContext` principal. `exp` is always checked; `nbf`, `aud` and
+/// `iss` when configured.
+pub struct JwtAuth<T> {
+    key: JwtKey,
+    validation: Validation,
+    skip_rules: SkipSet,
+    realm: String,
+    _claims: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for JwtAuth<T> {
+    fn clone(&self) -> Self {
+        JwtAuth {
+            key: self.key.clone(),
+            validation: self.validation.clone(),
+            skip_rules: self.skip_rules.clone(),
+            realm: self.realm.clone(),
+            _claims: PhantomData,
+        }
+    }
+}
+
+impl<T> JwtAuth<T> {
+    fn new(key: JwtKey, alg: Algorithm) -> Self {
+        let mut validation = Validation::new(alg);
+        validation.validate_nbf = true;
+        JwtAuth {
+            key,
+            validation,
+            skip_rules: SkipSet::new(),
+            realm: "api".to_string(),
+            _claims: PhantomData,
+        }
+    }
+
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtAuth::new(JwtKey::Hmac(Secret::new(secret.to_vec())), Algorithm::HS256)
+    }
+
+    /// Loads the HMAC key from the environment, a file or a callback.
+    pub fn hs256_from(source: &SecretSource) -> Result<Self, SecretError> {
+        Ok(JwtAuth::new(JwtKey::Hmac(source.resolve()?), Algorithm::HS256))
+    }
+
+    /// `pem` is the PEM-encoded RSA public key.
+    pub fn rs256(pem: &[u8]) -> Result<Self, jsonwebtoken::errors::Error> {
+        Ok(JwtAuth::new(JwtKey::Public(DecodingKey::from_rsa_pem(pem)?), Algorithm::RS256))
+    }
+
+    pub fn with_audience(mut self, audience: &[&str]) -> Self {
+        self.validation.set_audience(audience);
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &[&str]) -> Self {
+        self.validation.set_issuer(issuer);
+        self
+    }
+
+    /// Clock skew tolerated for `exp` and `nbf`, in seconds.
+    pub fn with_leeway(mut self, seconds: u64) -> Self {
+        self.validation.leeway = seconds;
+        self
+    }
+
+    /// Realm reported in `WWW-Authenticate`.
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_string();
+        self
+    }
+
+    pub fn with_skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+
+    /// RFC 6750 challenge; `error` is omitted when no token was sent at all.
+    fn unauthorized(&self, error: Option<&str>) -> HttpResponse {
+        let challenge = match error {
+            Some(error) => format!(
+                r#"Bearer realm="{}", error="invalid_token", error_description="{}""#,
+                self.realm, error
+            ),
+            None => format!(r#"Bearer realm="{}""#, self.realm),
+        };
+        let code = if error.is_some() { "invalid_token" } else { "missing_token" };
+        let mut resp = MwError::Unauthorized(code).error_response();
+        if let Ok(value) = HeaderValue::from_str(&challenge) {
+            resp.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        resp
+    }
+}
+
+#[derive(Deserialize)]
+struct Subject {
+    sub: Option<String>,
+}
+
+/// The `sub` claim of a token `process` already verified; whatever `T` is, it may not
+/// keep the claim.
+fn subject(token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    decode::<Subject>(token, &DecodingKey::from_secret(&[]), &validation)
+        .ok()?
+        .claims
+        .sub
+}
+
+fn bearer(req: &ServiceRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+impl<T, B> Handler<B> for JwtAuth<T>
+where
+    T: DeserializeOwned + Clone + 'static,
+    B: FromBoxBody,
+{
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.skip_rules.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let hmac;
+        let key = match &self.key {
+            JwtKey::Hmac(secret) => {
+                hmac = DecodingKey::from_secret(secret.expose());
+                &hmac
+            }
+            JwtKey::Public(key) => key,
+        };
+        let result = match bearer(&req) {
+            Some(token) => decode::<T>(token, key, &self.validation).map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => "token expired",
+                ErrorKind::ImmatureSignature => "token not yet valid",
+                ErrorKind::InvalidAudience => "invalid audience",
+                ErrorKind::InvalidIssuer => "invalid issuer",
+                _ => "invalid token",
+            }),
+            None => {
+                let resp = self.unauthorized(None);
+                return Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)));
+            }
+        };
+
+        match result {
+            Ok(data) => {
+                if let Some(sub) = bearer(&req).and_then(subject) {
+                    MwContext::of(&req).set_principal(sub);
+                }
+                req.extensions_mut().insert(Claims(data.claims));
+                Either::Right(req)
+            }
+            Err(error) => {
+                let resp = self.unauthorized(Some(error));
+                Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::header,
+        test::{self, TestRequest},
+        web, App, HttpResponse,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::{Deserialize, Serialize};
+
+    use super::{Claims, JwtAuth};
+    use crate::Factory;
+
+    #[derive(Clone, Deserialize, Serialize)]
+    struct User {
+        sub: String,
+        exp: u64,
+    }
+
+    fn token(exp: u64) -> String {
+        let user = User {
+            sub: "alice".to_string(),
+            exp,
+        };
+        let token = encode(&Header::default(), &user, &EncodingKey::from_secret(b"secret")).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[actix_web::test]
+    async fn test_jwt() {
+        let app = test::init_service(
+            App::new().wrap(Factory::new(JwtAuth::<User>::hs256(b"secret"))).route(
+                "/",
+                web::get().to(|claims: Claims<User>| async move { HttpResponse::Ok().body(claims.0.sub) }),
+            ),
+        )
+        .await;
+
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, token(4_000_000_000)));
+        assert_eq!(test::call_and_read_body(&app, req.to_request()).await, "alice");
+
+        let resp = test::call_service(&app, TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers().get(header::WWW_AUTHENTICATE).unwrap(), r#"Bearer realm="api""#);
+
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, token(1)));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 401);
+        let challenge = resp.headers().get(header::WWW_AUTHENTICATE).unwrap().to_str().unwrap();
+        assert!(challenge.contains("token expired"));
+    }
+
+    #[actix_web::test]
+    async fn test_principal() {
+        use crate::MwContext;
+
+        let app = test::init_service(
+            App::new().wrap(Factory::new(JwtAuth::<User>::hs256(b"secret"))).route(
+                "/",
+                web::get().to(|context: MwContext| async move { context.principal().unwrap_or_default() }),
+            ),
+        )
+        .await;
+
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, token(4_000_000_000)));
+        assert_eq!(test::call_and_read_body(&app, req.to_request()).await, "alice");
+    }
+}
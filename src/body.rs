@@ -0,0 +1,228 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(any(feature = "body-limit", feature = "mirror"))]
+use actix_web::{
+    dev::ServiceRequest,
+    http::{header, Method, Version},
+};
+use actix_web::{
+    body::{BodySize, BoxBody, EitherBody, MessageBody},
+    web::{Bytes, BytesMut},
+};
+use pin_project_lite::pin_project;
+
+/// Response bodies a handler can build its own short-circuit responses in.
+///
+/// Handlers that reject requests (e.g. `CSRF`) are generic over `B: FromBoxBody`, so they fit
+/// stacks whose body is `BoxBody` or an `EitherBody` whose right side is `BoxBody`.
+pub trait FromBoxBody: MessageBody {
+    fn from_box_body(body: BoxBody) -> Self;
+}
+
+impl FromBoxBody for BoxBody {
+    fn from_box_body(body: BoxBody) -> Self {
+        body
+    }
+}
+
+impl<L: MessageBody + 'static> FromBoxBody for EitherBody<L, BoxBody> {
+    fn from_box_body(body: BoxBody) -> Self {
+        EitherBody::right(body)
+    }
+}
+
+/// The bytes of a body that is available at once and at most `limit` long, with the body
+/// rebuilt around them. Streaming and larger bodies come back untouched with `None`.
+#[cfg(any(feature = "etag", feature = "idempotency"))]
+pub(crate) fn buffered<B: FromBoxBody>(body: B, limit: usize) -> (Option<Bytes>, B) {
+    match body.size() {
+        BodySize::Sized(size) if size <= limit as u64 => {}
+        _ => return (None, body),
+    }
+
+    match body.try_into_bytes() {
+        Ok(bytes) => (Some(bytes.clone()), B::from_box_body(BoxBody::new(bytes))),
+        Err(body) => (None, body),
+    }
+}
+
+/// Whether a request comes with a body. HTTP/2 and HTTP/3 requests need neither
+/// `Content-Length` nor `Transfer-Encoding`, so without those the method decides there.
+#[cfg(any(feature = "body-limit", feature = "mirror"))]
+pub(crate) fn has_request_body(req: &ServiceRequest) -> bool {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    if let Some(length) = length {
+        return length > 0;
+    }
+    if req.headers().contains_key(header::TRANSFER_ENCODING) {
+        return true;
+    }
+    req.version() >= Version::HTTP_2
+        && !matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::DELETE | Method::TRACE | Method::CONNECT
+        )
+}
+
+/// Sees a response body chunk by chunk as it is sent, e.g. to count bytes or feed a hash.
+pub trait BodyObserver: 'static {
+    fn chunk(&mut self, chunk: &Bytes);
+
+    /// Called once, when the body ends (`complete`) or is dropped early, e.g. because the
+    /// client went away or the body failed.
+    fn end(&mut self, complete: bool) {
+        let _ = complete;
+    }
+}
+
+/// Calls `end(false)` if the body is dropped before it ends.
+struct Ending<O: BodyObserver>(Option<O>);
+
+impl<O: BodyObserver> Ending<O> {
+    fn finish(&mut self, complete: bool) {
+        if let Some(mut observer) = self.0.take() {
+            observer.end(complete);
+        }
+    }
+}
+
+impl<O: BodyObserver> Drop for Ending<O> {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+pin_project! {
+    /// A body passed through unchanged, chunk by chunk, while `O` observes it.
+    pub struct Observed<B, O: BodyObserver> {
+        #[pin]
+        body: B,
+        observer: Ending<O>,
+    }
+}
+
+impl<B: MessageBody, O: BodyObserver> MessageBody for Observed<B, O> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let item = this.body.poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(observer) = &mut this.observer.0 {
+                    observer.chunk(chunk);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.observer.finish(false),
+            Poll::Ready(None) => this.observer.finish(true),
+            Poll::Pending => {}
+        }
+        item
+    }
+}
+
+/// Wraps `body` so `observer` sees it as it streams; streaming and SSE responses are not
+/// buffered or delayed.
+pub fn observe<B, O>(body: B, observer: O) -> B
+where
+    B: FromBoxBody + 'static,
+    O: BodyObserver,
+{
+    if body.size().is_eof() {
+        let mut ending = Ending(Some(observer));
+        ending.finish(true);
+        return body;
+    }
+    B::from_box_body(BoxBody::new(Observed {
+        body,
+        observer: Ending(Some(observer)),
+    }))
+}
+
+/// Copies chunks aside until the body ends or passes `limit`.
+struct Collect<F> {
+    limit: usize,
+    copy: Option<BytesMut>,
+    done: Option<F>,
+}
+
+impl<F: FnOnce(Option<Bytes>) + 'static> BodyObserver for Collect<F> {
+    fn chunk(&mut self, chunk: &Bytes) {
+        if let Some(copy) = &mut self.copy {
+            if copy.len() + chunk.len() > self.limit {
+                self.copy = None;
+            } else {
+                copy.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    fn end(&mut self, complete: bool) {
+        if let Some(done) = self.done.take() {
+            done(self.copy.take().filter(|_| complete).map(BytesMut::freeze));
+        }
+    }
+}
+
+/// Streams `body` through unchanged and calls `done` once it has been sent: with the whole
+/// body if it was at most `limit` bytes, and `None` if it was larger or not sent to the end.
+/// Unlike buffering, a long or endless body (e.g. SSE) costs at most `limit` bytes and
+/// never stalls, so it suits logging or auditing but not headers that must precede it.
+pub fn collect_up_to<B, F>(body: B, limit: usize, done: F) -> B
+where
+    B: FromBoxBody + 'static,
+    F: FnOnce(Option<Bytes>) + 'static,
+{
+    let copy = match body.size() {
+        BodySize::Sized(size) if size > limit as u64 => None,
+        _ => Some(BytesMut::new()),
+    };
+    observe(
+        body,
+        Collect {
+            limit,
+            copy,
+            done: Some(done),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use actix_web::{
+        body::{self, BodyStream, BoxBody},
+        web::Bytes,
+    };
+    use futures_util::stream;
+
+    use super::collect_up_to;
+
+    #[actix_web::test]
+    async fn test_collect_up_to() {
+        let seen = Rc::new(RefCell::new(vec![]));
+
+        let chunks = stream::iter(["ab", "cd"].map(|s| Ok::<_, actix_web::Error>(Bytes::from(s))));
+        let log = seen.clone();
+        let body = collect_up_to(BoxBody::new(BodyStream::new(chunks)), 8, move |b| log.borrow_mut().push(b));
+        assert_eq!(body::to_bytes(body).await.unwrap(), "abcd");
+
+        let log = seen.clone();
+        let body = collect_up_to(BoxBody::new("too long"), 4, move |b| log.borrow_mut().push(b));
+        assert_eq!(body::to_bytes(body).await.unwrap(), "too long");
+
+        assert_eq!(*seen.borrow(), vec![Some(Bytes::from_static(b"abcd")), None]);
+    }
+}
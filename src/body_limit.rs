@@ -0,0 +1,191 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header,
+    web::Bytes,
+    HttpMessage,
+};
+use futures_core::Stream;
+use futures_util::future::Either;
+
+use crate::{body::has_request_body, FromBoxBody, Handler, MwError, SkipRule};
+
+/// The request payload, failing with `PayloadError::Overflow` once more than `remaining`
+/// bytes arrive. Extractors turn that into `413 Payload Too Large`.
+struct Limited {
+    inner: Payload,
+    remaining: usize,
+}
+
+impl Stream for Limited {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if chunk.len() > self.remaining {
+                    self.remaining = 0;
+                    return Poll::Ready(Some(Err(PayloadError::Overflow)));
+                }
+                self.remaining -= chunk.len();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            other => other,
+        }
+    }
+}
+
+/// `application/json; charset=utf-8` -> `application/json`.
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// `type/*` matches every subtype.
+fn type_allowed(allowed: &[String], content_type: &str) -> bool {
+    allowed.iter().any(|a| match a.strip_suffix("/*") {
+        Some(main) => content_type.split_once('/').is_some_and(|(t, _)| t == main),
+        None => a == content_type,
+    })
+}
+
+/// Rejects request bodies over a size limit with `413 Payload Too Large`, and bodies of
+/// unexpected media types with `415 Unsupported Media Type`.
+///
+/// A `Content-Length` over the limit is rejected at once. Bodies without one (chunked) or
+/// with a wrong one are cut off: the payload fails once the limit is passed, which body
+/// extractors answer with `413`.
+#[derive(Clone, Debug)]
+pub struct BodyLimit {
+    default: Option<usize>,
+    limits: Vec<(SkipRule, Option<usize>)>,
+    content_types: Vec<(SkipRule, Vec<String>)>,
+}
+
+impl BodyLimit {
+    pub fn new(default: usize) -> Self {
+        BodyLimit {
+            default: Some(default),
+            limits: vec![],
+            content_types: vec![],
+        }
+    }
+
+    /// Requests matching `rule` get `limit` instead of the default; the first matching
+    /// override wins.
+    pub fn with_limit(mut self, rule: impl Into<SkipRule>, limit: usize) -> Self {
+        self.limits.push((rule.into(), Some(limit)));
+        self
+    }
+
+    /// Requests matching `rule` have no size limit, e.g. upload routes with their own.
+    pub fn without_limit(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.limits.push((rule.into(), None));
+        self
+    }
+
+    /// Bodies of requests matching `rule` must have one of `types`, e.g.
+    /// `["application/json"]` under `/api` or `["image/*"]`; the first matching rule wins.
+    pub fn allow_content_types(mut self, rule: impl Into<SkipRule>, types: &[&str]) -> Self {
+        let types = types.iter().map(|t| t.to_ascii_lowercase()).collect();
+        self.content_types.push((rule.into(), types));
+        self
+    }
+
+    fn limit(&self, req: &ServiceRequest) -> Option<usize> {
+        match self.limits.iter().find(|(rule, _)| rule.matches(req)) {
+            Some((_, limit)) => *limit,
+            None => self.default,
+        }
+    }
+
+    fn content_length(req: &ServiceRequest) -> Option<u64> {
+        req.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+    }
+
+    fn check(&self, req: &mut ServiceRequest) -> Result<(), MwError> {
+        if let Some(limit) = self.limit(req) {
+            if BodyLimit::content_length(req).is_some_and(|length| length > limit as u64) {
+                return Err(MwError::PayloadTooLarge);
+            }
+
+            let inner = req.take_payload();
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(Limited { inner, remaining: limit }),
+            });
+        }
+
+        let allowed = self.content_types.iter().find(|(rule, _)| rule.matches(req));
+        if let Some((_, allowed)) = allowed {
+            if has_request_body(req) {
+                let content_type = req
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(essence);
+                if !content_type.is_some_and(|ct| type_allowed(allowed, &ct)) {
+                    return Err(MwError::UnsupportedMediaType);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for BodyLimit {
+    fn process(&self, mut req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        match self.check(&mut req) {
+            Ok(()) => Either::Right(req),
+            Err(err) => Either::Left(err.reject(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, Version},
+        test::{self, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::BodyLimit;
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_body_limit() {
+        let limit = BodyLimit::new(8).allow_content_types("/api", &["application/json"]);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(limit))
+                .default_service(web::to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) })),
+        )
+        .await;
+
+        let req = TestRequest::post().uri("/upload").set_payload("0123456789").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 413);
+
+        let req = TestRequest::post()
+            .uri("/api")
+            .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+            .set_payload("{}")
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "{}");
+
+        let req = TestRequest::post().uri("/api").set_payload("a=1").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 415);
+
+        // HTTP/2 bodies need no Content-Length
+        let req = TestRequest::post().uri("/api").version(Version::HTTP_2).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 415);
+        let req = TestRequest::get().uri("/api").version(Version::HTTP_2).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
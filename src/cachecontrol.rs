@@ -0,0 +1,213 @@
+use std::time::{Duration, SystemTime};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderName, HeaderValue, HttpDate, TryIntoHeaderValue},
+    HttpMessage,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, SkipRule};
+
+/// The caching headers for one group of paths.
+#[derive(Clone, Debug, Default)]
+pub struct CachePolicy {
+    directives: Vec<String>,
+    max_age: Option<Duration>,
+    expires: bool,
+    vary: Vec<HeaderName>,
+}
+
+impl CachePolicy {
+    /// `no-store`: never cached, e.g. API responses with personal data.
+    pub fn no_store() -> Self {
+        CachePolicy::default().directive("no-store")
+    }
+
+    /// `no-cache`: cached but revalidated on every use.
+    pub fn no_cache() -> Self {
+        CachePolicy::default().directive("no-cache")
+    }
+
+    /// `public, max-age=<max_age>`.
+    pub fn public(max_age: Duration) -> Self {
+        CachePolicy::default().directive("public").max_age(max_age)
+    }
+
+    /// `private, max-age=<max_age>`: only the browser may cache.
+    pub fn private(max_age: Duration) -> Self {
+        CachePolicy::default().directive("private").max_age(max_age)
+    }
+
+    /// Any other directive, e.g. `stale-while-revalidate=60`.
+    pub fn directive(mut self, directive: impl Into<String>) -> Self {
+        self.directives.push(directive.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// For fingerprinted assets that never change.
+    pub fn immutable(self) -> Self {
+        self.directive("immutable")
+    }
+
+    pub fn must_revalidate(self) -> Self {
+        self.directive("must-revalidate")
+    }
+
+    /// Also sends `Expires` (now + `max-age`, or the past without one) for HTTP/1.0 caches.
+    pub fn expires(mut self) -> Self {
+        self.expires = true;
+        self
+    }
+
+    /// Headers the response varies by, e.g. `Accept-Encoding`; appended to `Vary`.
+    pub fn vary(mut self, headers: Vec<HeaderName>) -> Self {
+        self.vary = headers;
+        self
+    }
+
+    fn cache_control(&self) -> String {
+        let mut value = self.directives.join(", ");
+        if let Some(max_age) = self.max_age {
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            value.push_str(&format!("max-age={}", max_age.as_secs()));
+        }
+        value
+    }
+}
+
+/// Index of the rule chosen for a request.
+#[derive(Clone, Copy)]
+struct Rule(usize);
+
+/// Sets `Cache-Control`, `Expires` and `Vary` on successful responses from per-path rules,
+/// e.g. `/static` -> `public, max-age=31536000, immutable` and `/api` -> `no-store`.
+/// Headers already set by route handlers are kept unless `force` is enabled.
+#[derive(Clone, Debug, Default)]
+pub struct CacheControl {
+    rules: Vec<(SkipRule, CachePolicy)>,
+    force: bool,
+}
+
+impl CacheControl {
+    pub fn new() -> Self {
+        CacheControl::default()
+    }
+
+    /// The first matching rule wins; requests matching none are left alone.
+    pub fn rule(mut self, rule: impl Into<SkipRule>, policy: CachePolicy) -> Self {
+        self.rules.push((rule.into(), policy));
+        self
+    }
+
+    /// Overwrite `Cache-Control` and `Expires` set by route handlers.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+impl<B> Handler<B> for CacheControl {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if let Some(i) = self.rules.iter().position(|(rule, _)| rule.matches(&req)) {
+            req.extensions_mut().insert(Rule(i));
+        }
+        Either::Right(req)
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let chosen = resp.request().extensions().get::<Rule>().copied();
+        let policy = match chosen {
+            Some(Rule(i)) if resp.status().is_success() => &self.rules[i].1,
+            _ => return resp,
+        };
+
+        let headers = resp.headers_mut();
+        let cache_control = policy.cache_control();
+        if !cache_control.is_empty() && (self.force || !headers.contains_key(header::CACHE_CONTROL)) {
+            if let Ok(value) = HeaderValue::from_str(&cache_control) {
+                headers.insert(header::CACHE_CONTROL, value);
+            }
+        }
+
+        if policy.expires && (self.force || !headers.contains_key(header::EXPIRES)) {
+            let at = match policy.max_age {
+                Some(max_age) => SystemTime::now() + max_age,
+                None => SystemTime::UNIX_EPOCH,
+            };
+            if let Ok(value) = HttpDate::from(at).try_into_value() {
+                headers.insert(header::EXPIRES, value);
+            }
+        }
+
+        for name in &policy.vary {
+            let present = headers
+                .get_all(header::VARY)
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(name.as_str()));
+            if !present {
+                if let Ok(value) = HeaderValue::from_str(name.as_str()) {
+                    headers.append(header::VARY, value);
+                }
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{http::header, test, web, App, HttpResponse};
+
+    use super::{CacheControl, CachePolicy};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_cache_control() {
+        let cache = CacheControl::new()
+            .rule(
+                "/static",
+                CachePolicy::public(Duration::from_secs(3600))
+                    .immutable()
+                    .vary(vec![header::ACCEPT_ENCODING]),
+            )
+            .rule("/api", CachePolicy::no_store().expires());
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(cache))
+                .route("/static/app.js", web::get().to(HttpResponse::Ok))
+                .route("/static/missing.js", web::get().to(HttpResponse::NotFound))
+                .route(
+                    "/api/me",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::CACHE_CONTROL, "private"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/static/app.js").to_request()).await;
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "public, immutable, max-age=3600");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/static/missing.js").to_request()).await;
+        assert!(!resp.headers().contains_key(header::CACHE_CONTROL));
+
+        // set by the route, so kept
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/me").to_request()).await;
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "private");
+        assert!(resp.headers().contains_key(header::EXPIRES));
+    }
+}
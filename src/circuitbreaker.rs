@@ -0,0 +1,368 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    HttpMessage,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, FromBoxBody, Handler, MwError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    /// Requests fail fast until the cool-down ends.
+    Open,
+    /// A limited number of trial requests decide whether to close again.
+    HalfOpen,
+}
+
+pub type TransitionFn = dyn Fn(&str, CircuitState, CircuitState) + Send + Sync;
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    /// Outcomes of the latest calls while closed, `true` for failures.
+    window: VecDeque<bool>,
+    consecutive_failures: usize,
+    opened_at: Instant,
+    trials: usize,
+    trial_successes: usize,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            window: VecDeque::new(),
+            consecutive_failures: 0,
+            opened_at: Instant::now(),
+            trials: 0,
+            trial_successes: 0,
+        }
+    }
+}
+
+struct Breakers {
+    settings: Settings,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    on_transition: Option<Arc<TransitionFn>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    consecutive_failures: usize,
+    failure_rate: Option<(f64, usize)>,
+    cool_down: Duration,
+    half_open_trials: usize,
+    max_circuits: usize,
+}
+
+impl Breakers {
+    fn transition(&self, bucket: &str, circuit: &mut Circuit, to: CircuitState) {
+        let from = circuit.state;
+        circuit.state = to;
+        match to {
+            CircuitState::Open => circuit.opened_at = Instant::now(),
+            CircuitState::HalfOpen => {
+                circuit.trials = 0;
+                circuit.trial_successes = 0;
+            }
+            CircuitState::Closed => {
+                circuit.window.clear();
+                circuit.consecutive_failures = 0;
+            }
+        }
+        if let Some(hook) = &self.on_transition {
+            hook(bucket, from, to);
+        }
+    }
+
+    /// Whether a request to `bucket` may go through now.
+    fn admit(&self, bucket: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        if !circuits.contains_key(bucket) && circuits.len() >= self.settings.max_circuits {
+            // a closed circuit without recent failures is no different from a new one
+            circuits.retain(|_, c| {
+                c.state != CircuitState::Closed || c.consecutive_failures > 0 || c.window.contains(&true)
+            });
+            if circuits.len() >= self.settings.max_circuits {
+                return true;
+            }
+        }
+        let circuit = circuits.entry(bucket.to_string()).or_insert_with(Circuit::new);
+
+        if circuit.state == CircuitState::Open && circuit.opened_at.elapsed() >= self.settings.cool_down {
+            self.transition(bucket, circuit, CircuitState::HalfOpen);
+        }
+
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if circuit.trials < self.settings.half_open_trials => {
+                circuit.trials += 1;
+                true
+            }
+            CircuitState::HalfOpen => false,
+        }
+    }
+
+    fn record(&self, bucket: &str, failed: bool) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(bucket) {
+            Some(circuit) => circuit,
+            None => return,
+        };
+
+        match circuit.state {
+            CircuitState::Closed => {
+                circuit.consecutive_failures = if failed { circuit.consecutive_failures + 1 } else { 0 };
+                if let Some((_, size)) = self.settings.failure_rate {
+                    circuit.window.push_back(failed);
+                    if circuit.window.len() > size {
+                        circuit.window.pop_front();
+                    }
+                }
+
+                if circuit.consecutive_failures >= self.settings.consecutive_failures || self.rate_exceeded(circuit) {
+                    self.transition(bucket, circuit, CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if failed => self.transition(bucket, circuit, CircuitState::Open),
+            CircuitState::HalfOpen => {
+                circuit.trial_successes += 1;
+                if circuit.trial_successes >= self.settings.half_open_trials {
+                    self.transition(bucket, circuit, CircuitState::Closed);
+                }
+            }
+            // a request admitted before the circuit opened
+            CircuitState::Open => {}
+        }
+    }
+
+    fn rate_exceeded(&self, circuit: &Circuit) -> bool {
+        match self.settings.failure_rate {
+            Some((rate, size)) if circuit.window.len() >= size => {
+                let failures = circuit.window.iter().filter(|f| **f).count();
+                failures as f64 / size as f64 >= rate
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A request admitted through a circuit. Dropped without an outcome, e.g. because the
+/// inner service failed and `finalize` never saw a response, it counts as a failure.
+struct Attempt {
+    breakers: Arc<Breakers>,
+    bucket: String,
+    failed: Option<bool>,
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.breakers.record(&self.bucket, self.failed.unwrap_or(true));
+    }
+}
+
+/// Opens a circuit per bucket after repeated failures of the inner service: errors and
+/// `5xx` responses. While open, requests fail fast with `503`; after the cool-down a few
+/// trial requests decide whether to close it again.
+///
+/// Buckets default to the route pattern (`HttpRequest::match_pattern`, e.g. `/users/{id}`),
+/// or `unmatched`, so that clients cannot create circuits at will. At most
+/// `with_max_circuits` are kept; once full, circuits without recent failures are dropped,
+/// and requests to new buckets pass unchecked while none can be.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    breakers: Arc<Breakers>,
+    bucket: Arc<dyn Fn(&ServiceRequest) -> String + Send + Sync>,
+}
+
+impl CircuitBreaker {
+    /// Opens after `consecutive_failures` failures in a row, for `cool_down`.
+    pub fn new(consecutive_failures: usize, cool_down: Duration) -> Self {
+        CircuitBreaker {
+            breakers: Arc::new(Breakers {
+                settings: Settings {
+                    consecutive_failures: consecutive_failures.max(1),
+                    failure_rate: None,
+                    cool_down,
+                    half_open_trials: 1,
+                    max_circuits: 1024,
+                },
+                circuits: Mutex::new(HashMap::new()),
+                on_transition: None,
+            }),
+            bucket: Arc::new(|req| req.match_pattern().unwrap_or_else(|| "unmatched".to_string())),
+        }
+    }
+
+    fn settings(mut self, f: impl FnOnce(&mut Settings)) -> Self {
+        let breakers = Arc::get_mut(&mut self.breakers).expect("configure CircuitBreaker before cloning it");
+        f(&mut breakers.settings);
+        self
+    }
+
+    /// Also opens when at least `rate` of the last `window` calls failed.
+    pub fn with_failure_rate(self, rate: f64, window: usize) -> Self {
+        self.settings(|s| s.failure_rate = Some((rate, window.max(1))))
+    }
+
+    /// Trial requests let through while half-open; all must succeed to close the circuit.
+    pub fn with_half_open_trials(self, trials: usize) -> Self {
+        self.settings(|s| s.half_open_trials = trials.max(1))
+    }
+
+    /// Defaults to 1024.
+    pub fn with_max_circuits(self, max: usize) -> Self {
+        self.settings(|s| s.max_circuits = max)
+    }
+
+    /// Groups requests into circuits, e.g. by upstream host. Keep the number of buckets
+    /// bounded; see `with_max_circuits`.
+    pub fn with_bucket<F>(mut self, bucket: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> String + Send + Sync + 'static,
+    {
+        self.bucket = Arc::new(bucket);
+        self
+    }
+
+    /// Called with the bucket and the old and new state on every transition, e.g. to alert
+    /// when a circuit opens.
+    pub fn on_transition<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, CircuitState, CircuitState) + Send + Sync + 'static,
+    {
+        let breakers = Arc::get_mut(&mut self.breakers).expect("configure CircuitBreaker before cloning it");
+        breakers.on_transition = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn state(&self, bucket: &str) -> CircuitState {
+        self.breakers
+            .circuits
+            .lock()
+            .unwrap()
+            .get(bucket)
+            .map_or(CircuitState::Closed, |c| c.state)
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("settings", &self.breakers.settings)
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for CircuitBreaker {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let bucket = (self.bucket)(&req);
+        if !self.breakers.admit(&bucket) {
+            return Either::Left(MwError::Unavailable("circuit_open").reject(req));
+        }
+
+        req.extensions_mut().insert(Attempt {
+            breakers: self.breakers.clone(),
+            bucket,
+            failed: None,
+        });
+        Either::Right(req)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let attempt = resp.request().extensions_mut().remove::<Attempt>();
+        if let Some(mut attempt) = attempt {
+            attempt.failed = Some(resp.status().is_server_error());
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{CircuitBreaker, CircuitState};
+
+    #[test]
+    fn test_transitions() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        let breaker = CircuitBreaker::new(2, Duration::ZERO)
+            .on_transition(move |bucket, _, to| log.lock().unwrap().push((bucket.to_string(), to)));
+        let breakers = &breaker.breakers;
+
+        assert!(breakers.admit("/a"));
+        breakers.record("/a", true);
+        assert!(breakers.admit("/a"));
+        breakers.record("/a", true);
+        assert_eq!(breaker.state("/a"), CircuitState::Open);
+        assert_eq!(breaker.state("/b"), CircuitState::Closed);
+
+        // the cool-down has passed: one trial, then fail fast until it reports back
+        assert!(breakers.admit("/a"));
+        assert!(!breakers.admit("/a"));
+        breakers.record("/a", false);
+        assert_eq!(breaker.state("/a"), CircuitState::Closed);
+
+        let seen = seen.lock().unwrap();
+        let states = seen.iter().map(|(_, to)| *to).collect::<Vec<_>>();
+        assert_eq!(states, [CircuitState::Open, CircuitState::HalfOpen, CircuitState::Closed]);
+    }
+
+    #[test]
+    fn test_max_circuits() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60)).with_max_circuits(2);
+        let breakers = &breaker.breakers;
+
+        assert!(breakers.admit("/a"));
+        breakers.record("/a", true);
+        assert!(breakers.admit("/b"));
+        breakers.record("/b", false);
+
+        // `/b` is idle and makes room; `/a` is open and stays
+        assert!(breakers.admit("/c"));
+        breakers.record("/c", true);
+        assert_eq!(breakers.circuits.lock().unwrap().len(), 2);
+        assert_eq!(breaker.state("/a"), CircuitState::Open);
+
+        // full of open circuits: new buckets pass untracked
+        assert!(breakers.admit("/d"));
+        assert_eq!(breakers.circuits.lock().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_route_bucket() {
+        use actix_web::{test, web, App, HttpResponse};
+
+        use crate::Factory;
+
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(breaker.clone()))
+                .route("/users/{id}", web::get().to(HttpResponse::InternalServerError)),
+        )
+        .await;
+
+        for uri in ["/users/1", "/users/2"] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), 500);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/users/3").to_request()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(breaker.state("/users/{id}"), CircuitState::Open);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    future::{ready, Ready},
+    net::{IpAddr, SocketAddr},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderName},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+use ipnet::IpNet;
+
+use crate::{Handler, MwContext, MwError};
+
+/// Address of the client after skipping trusted proxies, cached in the request extensions
+/// by `ClientIpResolver` (or `IpFilter`) for later handlers and route handlers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequest for ClientIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<ClientIp>()
+                .copied()
+                .ok_or_else(|| MwError::not_installed("client IP").into()),
+        )
+    }
+}
+
+/// Where the client address is read from once the peer is a trusted proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpStrategy {
+    /// The rightmost untrusted hop of `X-Forwarded-For`.
+    XForwardedFor,
+    /// The rightmost untrusted `for=` hop of `Forwarded` (RFC 7239).
+    Forwarded,
+    /// `CF-Connecting-IP`; trust Cloudflare's published ranges for it.
+    CfConnectingIp,
+    /// A single-address header set by the proxy, e.g. `X-Real-IP`.
+    Header(HeaderName),
+}
+
+/// Parses `192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]:80` and quoted forms.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+/// Hops recorded by proxies, nearest proxy last; `None` when the header is absent.
+fn chain(req: &ServiceRequest, strategy: &IpStrategy) -> Option<Vec<IpAddr>> {
+    let headers = req.headers();
+    let hops = match strategy {
+        IpStrategy::XForwardedFor => {
+            let values = headers
+                .get_all(header::X_FORWARDED_FOR)
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>();
+            if values.is_empty() {
+                return None;
+            }
+            values.iter().flat_map(|v| v.split(',')).filter_map(parse_node).collect()
+        }
+        IpStrategy::Forwarded => {
+            let forwarded = headers.get(header::FORWARDED)?.to_str().ok()?;
+            forwarded
+                .split(',')
+                .filter_map(|element| {
+                    element.split(';').find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim().eq_ignore_ascii_case("for").then(|| parse_node(value)).flatten()
+                    })
+                })
+                .collect()
+        }
+        IpStrategy::CfConnectingIp => vec![parse_node(headers.get("cf-connecting-ip")?.to_str().ok()?)?],
+        IpStrategy::Header(name) => vec![parse_node(headers.get(name)?.to_str().ok()?)?],
+    };
+    Some(hops)
+}
+
+/// Finds the real client address behind proxies, once per request: the peer address,
+/// unless the peer is a trusted proxy; then the first strategy whose header is present
+/// is followed from the right, up to `trust_depth` hops, while each hop is itself a
+/// trusted proxy. Headers are never read when the peer is untrusted, so clients cannot
+/// spoof them.
+///
+/// Only configure the header your proxies actually set. With several strategies, a header
+/// the proxy passes through untouched can be filled in by the client and, if it comes
+/// first, wins.
+///
+/// As a handler it stores `ClientIp` in the request extensions. Other middlewares
+/// (rate limits, audit, honeypot) use the cached value when it is there.
+#[derive(Clone, Debug)]
+pub struct ClientIpResolver {
+    strategies: Vec<IpStrategy>,
+    trusted_proxies: Vec<IpNet>,
+    trust_depth: usize,
+}
+
+impl Default for ClientIpResolver {
+    /// `X-Forwarded-For` only; no trusted proxies, so the peer address.
+    fn default() -> Self {
+        ClientIpResolver {
+            strategies: vec![IpStrategy::XForwardedFor],
+            trusted_proxies: vec![],
+            trust_depth: usize::MAX,
+        }
+    }
+}
+
+impl ClientIpResolver {
+    pub fn new() -> Self {
+        ClientIpResolver::default()
+    }
+
+    /// Replaces the strategies; they are tried in order.
+    pub fn strategies(mut self, strategies: Vec<IpStrategy>) -> Self {
+        self.strategies = strategies;
+        self
+    }
+
+    pub fn trusted_proxy(mut self, net: IpNet) -> Self {
+        self.trusted_proxies.push(net);
+        self
+    }
+
+    /// At most `depth` hops are followed; unlimited by default.
+    pub fn trust_depth(mut self, depth: usize) -> Self {
+        self.trust_depth = depth;
+        self
+    }
+
+    fn trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolves without looking at or filling the cache.
+    pub fn resolve(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let mut client = req.peer_addr()?.ip();
+        if !self.trusted(&client) {
+            return Some(client);
+        }
+
+        let hops = match self.strategies.iter().find_map(|s| chain(req, s)) {
+            Some(hops) => hops,
+            None => return Some(client),
+        };
+        for hop in hops.into_iter().rev().take(self.trust_depth) {
+            client = hop;
+            if !self.trusted(&client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    /// The cached `ClientIp`, resolving and caching it on first use.
+    pub fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        if let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+            return Some(*ip);
+        }
+        let ip = self.resolve(req)?;
+        req.extensions_mut().insert(ClientIp(ip));
+        MwContext::of(req).set_client_ip(ip);
+        Some(ip)
+    }
+}
+
+impl<B> Handler<B> for ClientIpResolver {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        self.client_ip(&req);
+        Either::Right(req)
+    }
+}
+
+/// The cached `ClientIp` if a resolver ran earlier in the chain, else the peer address.
+pub fn cached_or_peer(req: &HttpRequest) -> Option<IpAddr> {
+    if let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+        return Some(*ip);
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{ClientIpResolver, IpStrategy};
+
+    #[test]
+    fn test_strategies() {
+        let resolver = ClientIpResolver::new().trusted_proxy("10.0.0.0/8".parse().unwrap());
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4, 203.0.113.9, 10.0.0.2"))
+            .to_srv_request();
+        assert_eq!(resolver.resolve(&req), Some("203.0.113.9".parse().unwrap()));
+
+        // only the configured header counts
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("forwarded", r#"for="[2001:db8::1]:80", for=10.0.0.3"#))
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_srv_request();
+        assert_eq!(resolver.resolve(&req), Some("1.2.3.4".parse().unwrap()));
+        let forwarded = resolver.clone().strategies(vec![IpStrategy::Forwarded]);
+        assert_eq!(forwarded.resolve(&req), Some("2001:db8::1".parse().unwrap()));
+
+        let cloudflare = resolver.strategies(vec![IpStrategy::CfConnectingIp]);
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("cf-connecting-ip", "198.51.100.7"))
+            .to_srv_request();
+        assert_eq!(cloudflare.resolve(&req), Some("198.51.100.7".parse().unwrap()));
+    }
+}
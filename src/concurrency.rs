@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    rt, HttpMessage,
+};
+use futures_util::future::Either;
+
+#[cfg(feature = "stats")]
+use crate::{stats::Gauge, MiddlewareStats};
+use crate::{CallInfo, FromBoxBody, Handler, MwError, Verdict};
+
+type KeyFn = Arc<dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct Usage {
+    global: usize,
+    keyed: HashMap<String, usize>,
+}
+
+struct Limits {
+    global: Option<usize>,
+    per_key: Option<(usize, KeyFn)>,
+    status: StatusCode,
+    queue: Option<Duration>,
+    usage: Mutex<Usage>,
+    queued: AtomicUsize,
+    /// Requests queued for a slot, woken whenever one is given back.
+    waiters: Mutex<Vec<Waker>>,
+    #[cfg(feature = "stats")]
+    stats: Option<MiddlewareStats>,
+}
+
+impl Limits {
+    /// Takes the global and the per-key slot together, or neither.
+    fn try_acquire(self: &Arc<Self>, key: Option<&str>) -> Option<Permit> {
+        let mut usage = self.usage.lock().unwrap();
+        if self.global.is_some_and(|limit| usage.global >= limit) {
+            return None;
+        }
+
+        let key = match (&self.per_key, key) {
+            (Some((limit, _)), Some(key)) => {
+                let used = usage.keyed.entry(key.to_string()).or_insert(0);
+                if *used >= *limit {
+                    return None;
+                }
+                *used += 1;
+                Some(key.to_string())
+            }
+            _ => None,
+        };
+        usage.global += 1;
+
+        #[cfg(feature = "stats")]
+        if let Some(stats) = &self.stats {
+            stats.raise(Gauge::InFlight);
+        }
+        Some(Permit {
+            limits: self.clone(),
+            key,
+        })
+    }
+
+    fn release(&self, key: Option<&str>) {
+        {
+            let mut usage = self.usage.lock().unwrap();
+            usage.global -= 1;
+            if let Some(key) = key {
+                if let Some(used) = usage.keyed.get_mut(key) {
+                    *used -= 1;
+                    if *used == 0 {
+                        usage.keyed.remove(key);
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "stats")]
+        if let Some(stats) = &self.stats {
+            stats.lower(Gauge::InFlight);
+        }
+        for waker in self.waiters.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Held in the request extensions while the request is in flight. Dropped in `finalize`,
+/// or with the request when the inner service fails.
+struct Permit {
+    limits: Arc<Limits>,
+    key: Option<String>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limits.release(self.key.as_deref());
+    }
+}
+
+/// Set by `process` on a request over the limit that may queue; the key it counts under.
+struct Waiting(Option<String>);
+
+/// Resolves to a permit once a slot is free.
+struct Acquire {
+    limits: Arc<Limits>,
+    key: Option<String>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        // registered first, so a slot given back right after the attempt still wakes us
+        self.limits.waiters.lock().unwrap().push(cx.waker().clone());
+        match self.limits.try_acquire(self.key.as_deref()) {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Counts a request as queued for as long as it lives.
+struct Queued(Arc<Limits>);
+
+impl Queued {
+    fn new(limits: &Arc<Limits>) -> Self {
+        limits.queued.fetch_add(1, Ordering::AcqRel);
+        #[cfg(feature = "stats")]
+        if let Some(stats) = &limits.stats {
+            stats.raise(Gauge::Queued);
+        }
+        Queued(limits.clone())
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::AcqRel);
+        #[cfg(feature = "stats")]
+        if let Some(stats) = &self.0.stats {
+            stats.lower(Gauge::Queued);
+        }
+    }
+}
+
+/// Caps the number of requests in flight, globally and per key. Requests over a limit are
+/// shed at once with `503` (or the configured status), or with `queue` wait in `verify`
+/// for a slot first.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    limits: Arc<Limits>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new() -> Self {
+        ConcurrencyLimit {
+            limits: Arc::new(Limits {
+                global: None,
+                per_key: None,
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                queue: None,
+                usage: Mutex::default(),
+                queued: AtomicUsize::new(0),
+                waiters: Mutex::default(),
+                #[cfg(feature = "stats")]
+                stats: None,
+            }),
+        }
+    }
+
+    fn limits(mut self, f: impl FnOnce(&mut Limits)) -> Self {
+        f(Arc::get_mut(&mut self.limits).expect("configure ConcurrencyLimit before cloning it"));
+        self
+    }
+
+    pub fn global(self, limit: usize) -> Self {
+        self.limits(|l| l.global = Some(limit))
+    }
+
+    /// At most `limit` requests per key; requests without a key only count globally.
+    pub fn per_key<F>(self, limit: usize, key: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.limits(|l| l.per_key = Some((limit, Arc::new(key))))
+    }
+
+    /// e.g. `429 Too Many Requests` when the per-key limit is the one that matters.
+    pub fn reject_with(self, status: StatusCode) -> Self {
+        self.limits(|l| l.status = status)
+    }
+
+    /// Requests over a limit wait up to `timeout` for a slot before they are rejected.
+    pub fn queue(self, timeout: Duration) -> Self {
+        self.limits(|l| l.queue = Some(timeout))
+    }
+
+    /// Keeps the `in_flight` and `queued` gauges of `stats`, e.g. those of the factory:
+    /// `Factory::new(limit.with_stats(stats.clone())).with_stats(stats)`.
+    #[cfg(feature = "stats")]
+    pub fn with_stats(self, stats: MiddlewareStats) -> Self {
+        self.limits(|l| l.stats = Some(stats))
+    }
+
+    /// Requests currently holding a slot.
+    pub fn in_flight(&self) -> usize {
+        self.limits.usage.lock().unwrap().global
+    }
+
+    /// Requests waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.limits.queued.load(Ordering::Acquire)
+    }
+
+    /// Keys with at least one request in flight.
+    pub fn active_keys(&self) -> usize {
+        self.limits.usage.lock().unwrap().keyed.len()
+    }
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        ConcurrencyLimit::new()
+    }
+}
+
+impl fmt::Debug for ConcurrencyLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrencyLimit")
+            .field("global", &self.limits.global)
+            .field("per_key", &self.limits.per_key.as_ref().map(|(limit, _)| limit))
+            .field("status", &self.limits.status)
+            .field("queue", &self.limits.queue)
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for ConcurrencyLimit {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let key = self.limits.per_key.as_ref().and_then(|(_, key)| key(&req));
+        match self.limits.try_acquire(key.as_deref()) {
+            Some(permit) => {
+                req.extensions_mut().insert(permit);
+                Either::Right(req)
+            }
+            None if self.limits.queue.is_some() => {
+                req.extensions_mut().insert(Waiting(key));
+                Either::Right(req)
+            }
+            None => Either::Left(MwError::Rejected(self.limits.status, "concurrency_limited").reject(req)),
+        }
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let Waiting(key) = req.extensions_mut().remove::<Waiting>()?;
+        let limits = self.limits.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            let _queued = Queued::new(&limits);
+            let timeout = limits.queue.unwrap_or_default();
+            let acquire = Acquire {
+                limits: limits.clone(),
+                key,
+            };
+            match rt::time::timeout(timeout, acquire).await {
+                Ok(permit) => {
+                    req.extensions_mut().insert(permit);
+                    Ok(())
+                }
+                Err(_) => Err(MwError::Rejected(limits.status, "concurrency_limited").respond(req)),
+            }
+        }))
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        resp.request().extensions_mut().remove::<Permit>();
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{rt, test::TestRequest, web, App, HttpResponse};
+    use futures_util::future::join;
+
+    use super::ConcurrencyLimit;
+    use crate::Factory;
+
+    #[test]
+    fn test_permits() {
+        let limit = ConcurrencyLimit::new()
+            .global(2)
+            .per_key(1, |req| req.headers().get("x-user").map(|v| v.to_str().unwrap().to_string()));
+
+        let a = limit.limits.try_acquire(Some("a"));
+        assert!(a.is_some());
+        assert!(limit.limits.try_acquire(Some("a")).is_none());
+        assert_eq!(limit.in_flight(), 1);
+
+        let b = limit.limits.try_acquire(None);
+        assert!(b.is_some());
+        assert!(limit.limits.try_acquire(None).is_none());
+
+        drop(a);
+        drop(b);
+        assert_eq!(limit.in_flight(), 0);
+        assert_eq!(limit.active_keys(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_queue() {
+        use actix_web::test;
+
+        let slow = || async {
+            rt::time::sleep(Duration::from_millis(20)).await;
+            HttpResponse::Ok().finish()
+        };
+
+        let limit = ConcurrencyLimit::new().global(1).queue(Duration::from_millis(500));
+        let app = test::init_service(App::new().wrap(Factory::new(limit)).route("/", web::get().to(slow))).await;
+        let (a, b) = join(
+            test::call_service(&app, TestRequest::get().to_request()),
+            test::call_service(&app, TestRequest::get().to_request()),
+        )
+        .await;
+        assert_eq!(a.status(), 200);
+        assert_eq!(b.status(), 200);
+
+        let limit = ConcurrencyLimit::new().global(1).queue(Duration::from_millis(5));
+        let app = test::init_service(App::new().wrap(Factory::new(limit)).route("/", web::get().to(slow))).await;
+        let (a, b) = join(
+            test::call_service(&app, TestRequest::get().to_request()),
+            test::call_service(&app, TestRequest::get().to_request()),
+        )
+        .await;
+        assert_eq!(a.status(), 200);
+        assert_eq!(b.status(), 503);
+    }
+}
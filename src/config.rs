@@ -0,0 +1,434 @@
+use std::{fmt, path::Path};
+
+use serde::Deserialize;
+
+use crate::{Chain, FromBoxBody, Handler, MwError};
+
+/// A config file that could not be read, parsed or turned into handlers.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// e.g. an unparsable CIDR or header name; the message names the middleware.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read middleware config: {}", err),
+            ConfigError::Parse(err) => write!(f, "failed to parse middleware config: {}", err),
+            ConfigError::Invalid(message) => write!(f, "invalid middleware config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(err) => Some(err),
+            ConfigError::Parse(err) => Some(err),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<ConfigError> for MwError {
+    fn from(err: ConfigError) -> Self {
+        MwError::Config(err.to_string())
+    }
+}
+
+fn invalid(middleware: &str, err: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid(format!("{}: {}", middleware, err))
+}
+
+#[cfg(any(feature = "ratelimit", feature = "csrf"))]
+fn enforcement(report_only: bool) -> crate::EnforcementMode {
+    if report_only {
+        crate::EnforcementMode::ReportOnly
+    } else {
+        crate::EnforcementMode::Enforce
+    }
+}
+
+#[cfg(feature = "request-id")]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIdConfig {
+    pub header: Option<String>,
+    pub trust_incoming: Option<bool>,
+}
+
+#[cfg(feature = "security-headers")]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    /// Start from `SecurityHeadersBuilder::recommended`.
+    pub recommended: bool,
+    pub hsts_max_age_secs: Option<u64>,
+    pub hsts_include_subdomains: bool,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    /// Path prefixes that get no headers.
+    pub skip: Vec<String>,
+}
+
+#[cfg(feature = "cors")]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Exact origins; `*` allows any origin, but not together with `credentials`.
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age_secs: Option<u32>,
+}
+
+#[cfg(feature = "ipfilter")]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterConfig {
+    /// Addresses or CIDR ranges.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    pub trust_depth: Option<usize>,
+    pub status: Option<u16>,
+}
+
+#[cfg(feature = "ratelimit")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub capacity: u32,
+    pub period_secs: u64,
+    /// `peer-ip` (default), `client-ip`, `principal` or `header:<name>`.
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub report_only: bool,
+}
+
+#[cfg(feature = "body-limit")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitConfig {
+    pub max_bytes: usize,
+}
+
+#[cfg(feature = "timeout")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub timeout_ms: u64,
+    /// Path prefixes without a deadline.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+/// A secret in the config file: inline, or `{ env = "VAR" }` / `{ file = "/path" }` to keep
+/// it out of the file.
+#[cfg(feature = "csrf")]
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SecretConfig {
+    Inline(String),
+    Env { env: String },
+    File { file: std::path::PathBuf },
+}
+
+#[cfg(feature = "csrf")]
+impl SecretConfig {
+    pub fn source(&self) -> crate::SecretSource {
+        match self {
+            SecretConfig::Inline(value) => crate::SecretSource::Inline(value.clone().into_bytes()),
+            SecretConfig::Env { env } => crate::SecretSource::Env(env.clone()),
+            SecretConfig::File { file } => crate::SecretSource::File(file.clone()),
+        }
+    }
+}
+
+#[cfg(feature = "csrf")]
+impl fmt::Debug for SecretConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source().fmt(f)
+    }
+}
+
+#[cfg(feature = "csrf")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsrfConfig {
+    pub header: String,
+    /// The HMAC key of the tokens.
+    pub salt: SecretConfig,
+    pub token_ttl_secs: i64,
+    #[serde(default)]
+    pub skip: Vec<String>,
+    #[serde(default)]
+    pub report_only: bool,
+}
+
+/// One entry of the stack, tagged by `type`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MiddlewareConfig {
+    #[cfg(feature = "request-id")]
+    RequestId(RequestIdConfig),
+    #[cfg(feature = "security-headers")]
+    SecurityHeaders(SecurityHeadersConfig),
+    #[cfg(feature = "cors")]
+    Cors(CorsConfig),
+    #[cfg(feature = "ipfilter")]
+    IpFilter(IpFilterConfig),
+    #[cfg(feature = "ratelimit")]
+    RateLimit(RateLimitConfig),
+    #[cfg(feature = "body-limit")]
+    BodyLimit(BodyLimitConfig),
+    #[cfg(feature = "timeout")]
+    Timeout(TimeoutConfig),
+    #[cfg(feature = "csrf")]
+    Csrf(CsrfConfig),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StackConfig {
+    #[serde(default)]
+    middleware: Vec<MiddlewareConfig>,
+}
+
+/// Builds a `Chain` from a TOML file listing middlewares in the order they run, so the
+/// stack can change without recompiling:
+///
+/// ```toml
+/// [[middleware]]
+/// type = "ip-filter"
+/// deny = ["203.0.113.0/24"]
+///
+/// [[middleware]]
+/// type = "rate-limit"
+/// capacity = 100
+/// period_secs = 60
+/// ```
+///
+/// Only middlewares whose features are enabled are known; other `type`s fail to parse.
+#[derive(Clone, Debug, Default)]
+pub struct StackBuilder {
+    config: StackConfig,
+}
+
+impl StackBuilder {
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        let config = toml::from_str(toml).map_err(ConfigError::Parse)?;
+        Ok(StackBuilder { config })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let toml = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        StackBuilder::from_toml(&toml)
+    }
+
+    /// Reads the file named by the environment variable `var`, e.g. `MIDDLEWARE_CONFIG`.
+    pub fn from_env(var: &str) -> Result<Self, ConfigError> {
+        let path = std::env::var_os(var).ok_or_else(|| ConfigError::Invalid(format!("{} is not set", var)))?;
+        StackBuilder::from_file(path)
+    }
+
+    pub fn middleware(&self) -> &[MiddlewareConfig] {
+        &self.config.middleware
+    }
+
+    /// Builds every configured middleware; call once per worker.
+    pub fn build<B: FromBoxBody + 'static>(&self) -> Result<Chain<B>, ConfigError> {
+        self.config.middleware.iter().cloned().map(build_one).collect()
+    }
+}
+
+// with no middleware features enabled `MiddlewareConfig` has no variants
+#[allow(unreachable_code)]
+fn build_one<B: FromBoxBody + 'static>(config: MiddlewareConfig) -> Result<Box<dyn Handler<B>>, ConfigError> {
+    Ok(match config {
+        #[cfg(feature = "request-id")]
+        MiddlewareConfig::RequestId(c) => {
+            use actix_web::http::header::HeaderName;
+
+            let mut h = crate::request_id::SetRequestId::new();
+            if let Some(header) = &c.header {
+                h = h.with_header_name(HeaderName::try_from(header.as_str()).map_err(|e| invalid("request-id", e))?);
+            }
+            if let Some(trust) = c.trust_incoming {
+                h = h.trust_incoming(trust);
+            }
+            Box::new(h)
+        }
+        #[cfg(feature = "security-headers")]
+        MiddlewareConfig::SecurityHeaders(c) => {
+            use crate::security_headers::SecurityHeadersBuilder;
+
+            let mut h = if c.recommended {
+                SecurityHeadersBuilder::recommended()
+            } else {
+                SecurityHeadersBuilder::default()
+            };
+            if let Some(max_age) = c.hsts_max_age_secs {
+                h = h.hsts(max_age, c.hsts_include_subdomains, false);
+            }
+            if let Some(policy) = &c.referrer_policy {
+                h = h.referrer_policy(policy);
+            }
+            if let Some(policy) = &c.permissions_policy {
+                h = h.permissions_policy(policy);
+            }
+            for path in &c.skip {
+                h = h.skip(path.as_str());
+            }
+            Box::new(h.build().map_err(|e| invalid("security-headers", e))?)
+        }
+        #[cfg(feature = "cors")]
+        MiddlewareConfig::Cors(c) => {
+            use crate::cors::{AllowedOrigin, Cors};
+            use actix_web::http::{header::HeaderName, Method};
+
+            let header_names = |names: &[String]| {
+                names
+                    .iter()
+                    .map(|n| HeaderName::try_from(n.as_str()).map_err(|e| invalid("cors", e)))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            if c.credentials && c.origins.iter().any(|origin| origin == "*") {
+                return Err(invalid("cors", "credentials cannot be allowed for any origin (`*`)"));
+            }
+            let mut h = Cors::new().allow_credentials(c.credentials);
+            for origin in &c.origins {
+                h = if origin == "*" {
+                    h.allow_any_origin()
+                } else {
+                    h.allow_origin(AllowedOrigin::Exact(origin.clone()))
+                };
+            }
+            if !c.methods.is_empty() {
+                let methods = c
+                    .methods
+                    .iter()
+                    .map(|m| Method::from_bytes(m.as_bytes()).map_err(|e| invalid("cors", e)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                h = h.allow_methods(methods);
+            }
+            h = h.allow_headers(header_names(&c.headers)?).expose_headers(header_names(&c.expose_headers)?);
+            if let Some(max_age) = c.max_age_secs {
+                h = h.max_age(max_age);
+            }
+            Box::new(h)
+        }
+        #[cfg(feature = "ipfilter")]
+        MiddlewareConfig::IpFilter(c) => {
+            use actix_web::http::StatusCode;
+            use ipnet::IpNet;
+
+            let net = |s: &String| -> Result<IpNet, ConfigError> {
+                s.parse::<IpNet>()
+                    .or_else(|_| s.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .map_err(|e| invalid("ip-filter", format!("{}: {}", s, e)))
+            };
+            let mut h = crate::ipfilter::IpFilter::new();
+            for s in &c.allow {
+                h = h.allow(net(s)?);
+            }
+            for s in &c.deny {
+                h = h.deny(net(s)?);
+            }
+            for s in &c.trusted_proxies {
+                h = h.trusted_proxy(net(s)?);
+            }
+            if let Some(depth) = c.trust_depth {
+                h = h.trust_depth(depth);
+            }
+            if let Some(status) = c.status {
+                h = h.reject_with(StatusCode::from_u16(status).map_err(|e| invalid("ip-filter", e))?);
+            }
+            Box::new(h)
+        }
+        #[cfg(feature = "ratelimit")]
+        MiddlewareConfig::RateLimit(c) => {
+            use crate::ratelimit::{KeySource, RateLimit};
+            use actix_web::http::header::HeaderName;
+
+            if c.capacity == 0 || c.period_secs == 0 {
+                return Err(invalid("rate-limit", "capacity and period_secs must be positive"));
+            }
+            let key = match c.key.as_deref() {
+                None | Some("peer-ip") => KeySource::PeerIp,
+                Some("client-ip") => KeySource::ClientIp,
+                Some("principal") => KeySource::Principal,
+                Some(other) => match other.strip_prefix("header:") {
+                    Some(name) => {
+                        KeySource::Header(HeaderName::try_from(name).map_err(|e| invalid("rate-limit", e))?)
+                    }
+                    None => return Err(invalid("rate-limit", format!("unknown key {:?}", other))),
+                },
+            };
+            Box::new(
+                RateLimit::new(c.capacity, std::time::Duration::from_secs(c.period_secs))
+                    .with_key(key)
+                    .with_enforcement(enforcement(c.report_only)),
+            )
+        }
+        #[cfg(feature = "body-limit")]
+        MiddlewareConfig::BodyLimit(c) => Box::new(crate::body_limit::BodyLimit::new(c.max_bytes)),
+        #[cfg(feature = "timeout")]
+        MiddlewareConfig::Timeout(c) => {
+            let mut h = crate::timeout::Timeout::new(std::time::Duration::from_millis(c.timeout_ms));
+            for path in &c.exempt {
+                h = h.without_timeout(path.as_str());
+            }
+            Box::new(h)
+        }
+        #[cfg(feature = "csrf")]
+        MiddlewareConfig::Csrf(c) => {
+            let key = crate::csrf::CsrfKey::from_source(&c.salt.source()).map_err(|e| invalid("csrf", e))?;
+            // the salt itself only signs legacy tokens, which the config cannot enable
+            Box::new(
+                crate::csrf::CSRF::new(&c.header, c.skip.clone(), "", chrono::Duration::seconds(c.token_ttl_secs))
+                    .with_key(key)
+                    .with_enforcement(enforcement(c.report_only)),
+            )
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigError, StackBuilder};
+
+    #[test]
+    fn test_unknown_type() {
+        assert!(StackBuilder::from_toml("").unwrap().middleware().is_empty());
+        let err = StackBuilder::from_toml("[[middleware]]\ntype = \"no-such-middleware\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "csrf")]
+    fn test_csrf_salt_source() {
+        let config = |salt: &str| {
+            format!(
+                "[[middleware]]\ntype = \"csrf\"\nheader = \"x-csrf-token\"\nsalt = {}\ntoken_ttl_secs = 60\n",
+                salt
+            )
+        };
+
+        std::env::set_var("ACTIX_MW_TEST_CSRF_SALT", "s3cret");
+        let stack = StackBuilder::from_toml(&config(r#"{ env = "ACTIX_MW_TEST_CSRF_SALT" }"#)).unwrap();
+        assert!(stack.build::<actix_web::body::BoxBody>().is_ok());
+        assert!(!format!("{:?}", stack.middleware()).contains("s3cret"));
+
+        let stack = StackBuilder::from_toml(&config(r#"{ env = "ACTIX_MW_TEST_UNSET_SALT" }"#)).unwrap();
+        assert!(matches!(stack.build::<actix_web::body::BoxBody>(), Err(ConfigError::Invalid(_))));
+        let stack = StackBuilder::from_toml(&config(r#""inline""#)).unwrap();
+        assert!(stack.build::<actix_web::body::BoxBody>().is_ok());
+    }
+}
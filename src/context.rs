@@ -0,0 +1,140 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    future::{ready, Ready},
+    net::IpAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{dev::Payload, Error, FromRequest, HttpMessage, HttpRequest};
+
+#[derive(Default)]
+struct State {
+    request_id: Option<String>,
+    client_ip: Option<IpAddr>,
+    principal: Option<String>,
+    timed_out: bool,
+    /// Values kept for the rest of the request, see `MwContext::pin`.
+    pinned: HashMap<usize, Box<dyn Any>>,
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("request_id", &self.request_id)
+            .field("client_ip", &self.client_ip)
+            .field("principal", &self.principal)
+            .field("timed_out", &self.timed_out)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the middlewares learned about a request: its id, the client address and the
+/// authenticated principal, and when the outermost middleware received it.
+///
+/// `Middleware` puts one in the request extensions before running its handler, shared by
+/// every middleware of the stack, and `SetRequestId`, `ClientIpResolver` and the auth
+/// handlers fill it in. Clones share the values, so what one handler sets in `process` is
+/// seen by all of them in `post` and `finalize` (`MwContext::of(resp.request())`) and in
+/// `on_error` (`CallInfo::context`), short-circuited requests included.
+#[derive(Clone, Debug)]
+pub struct MwContext {
+    started_at: Instant,
+    state: Rc<RefCell<State>>,
+}
+
+impl MwContext {
+    /// The context of `req`, created on first use.
+    pub fn of<R: HttpMessage>(req: &R) -> MwContext {
+        if let Some(context) = req.extensions().get::<MwContext>() {
+            return context.clone();
+        }
+        let context = MwContext {
+            started_at: Instant::now(),
+            state: Rc::default(),
+        };
+        req.extensions_mut().insert(context.clone());
+        context
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn request_id(&self) -> Option<String> {
+        self.state.borrow().request_id.clone()
+    }
+
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.state.borrow().client_ip
+    }
+
+    /// e.g. the Basic auth username or the API key identity.
+    pub fn principal(&self) -> Option<String> {
+        self.state.borrow().principal.clone()
+    }
+
+    /// The inner service missed a `Handler::timeout` deadline; the call ended in an error.
+    pub fn timed_out(&self) -> bool {
+        self.state.borrow().timed_out
+    }
+
+    pub fn set_request_id(&self, id: impl Into<String>) {
+        self.state.borrow_mut().request_id = Some(id.into());
+    }
+
+    pub fn set_client_ip(&self, ip: IpAddr) {
+        self.state.borrow_mut().client_ip = Some(ip);
+    }
+
+    pub fn set_principal(&self, principal: impl Into<String>) {
+        self.state.borrow_mut().principal = Some(principal.into());
+    }
+
+    pub(crate) fn set_timed_out(&self) {
+        self.state.borrow_mut().timed_out = true;
+    }
+
+    /// The value `load` gave for `slot` the first time this request asked, e.g. the handler
+    /// a `ConfigHandle` held when the request started.
+    pub(crate) fn pin<T: Clone + 'static>(&self, slot: usize, load: impl FnOnce() -> T) -> T {
+        let mut state = self.state.borrow_mut();
+        let pinned = state.pinned.entry(slot).or_insert_with(|| Box::new(load()));
+        match pinned.downcast_ref::<T>() {
+            Some(value) => value.clone(),
+            None => unreachable!("slot {} pinned with another type", slot),
+        }
+    }
+}
+
+impl FromRequest for MwContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(MwContext::of(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::MwContext;
+
+    #[test]
+    fn test_shared() {
+        let req = TestRequest::default().to_srv_request();
+        MwContext::of(&req).set_principal("alice");
+        let resp = req.into_response(actix_web::HttpResponse::Forbidden().finish());
+        assert_eq!(MwContext::of(resp.request()).principal().as_deref(), Some("alice"));
+        assert_eq!(MwContext::of(resp.request()).request_id(), None);
+    }
+}
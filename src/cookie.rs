@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+pub use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{cookie::time, dev::ServiceResponse, HttpRequest};
+
+/// Name prefixes browsers enforce extra rules for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CookiePrefix {
+    None,
+    /// `__Secure-`: must be `Secure`.
+    Secure,
+    /// `__Host-`: must be `Secure`, have `Path=/` and no `Domain`, so no subdomain can set
+    /// or read it.
+    Host,
+}
+
+impl CookiePrefix {
+    fn of(name: &str) -> Self {
+        if name.starts_with("__Host-") {
+            CookiePrefix::Host
+        } else if name.starts_with("__Secure-") {
+            CookiePrefix::Secure
+        } else {
+            CookiePrefix::None
+        }
+    }
+}
+
+/// How a cookie is written, with secure defaults: `Secure`, `HttpOnly`, `SameSite=Lax`,
+/// `Path=/` and no expiry. The rules of the `__Host-` and `__Secure-` prefixes are
+/// enforced: a setter that would break them panics, so a misconfiguration shows at
+/// startup rather than as a cookie browsers silently drop.
+#[derive(Clone, Debug)]
+pub struct CookieSpec {
+    name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    max_age: Option<Duration>,
+}
+
+impl CookieSpec {
+    pub fn new(name: impl Into<String>) -> Self {
+        CookieSpec {
+            name: name.into(),
+            path: "/".to_string(),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            max_age: None,
+        }
+    }
+
+    /// `__Host-{name}`.
+    pub fn host(name: &str) -> Self {
+        CookieSpec::new(format!("__Host-{}", name))
+    }
+
+    /// `__Secure-{name}`.
+    pub fn secure(name: &str) -> Self {
+        CookieSpec::new(format!("__Secure-{}", name))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn prefix(&self) -> CookiePrefix {
+        CookiePrefix::of(&self.name)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        assert!(
+            self.prefix() != CookiePrefix::Host || path == "/",
+            "cookie {} must have Path=/",
+            self.name
+        );
+        self.path = path;
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        assert!(self.prefix() != CookiePrefix::Host, "cookie {} must not have a Domain", self.name);
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Also send the cookie over plain HTTP, e.g. for local development.
+    pub fn insecure(mut self) -> Self {
+        assert!(self.prefix() == CookiePrefix::None, "cookie {} must be Secure", self.name);
+        self.secure = false;
+        self
+    }
+
+    /// `false` lets scripts read the cookie, e.g. for double-submit CSRF tokens.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// `SameSite=None` requires `Secure`.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Persistent for `max_age`; session cookies by default.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn build(&self, value: impl Into<String>) -> Cookie<'static> {
+        let mut cookie = Cookie::build(self.name.clone(), value.into())
+            .path(self.path.clone())
+            .secure(self.secure || self.same_site == SameSite::None)
+            .http_only(self.http_only)
+            .same_site(self.same_site)
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(time::Duration::seconds(max_age.as_secs() as i64));
+        }
+        cookie
+    }
+
+    /// A cookie that deletes this one, with the same path and domain.
+    pub fn removal(&self) -> Cookie<'static> {
+        let mut cookie = self.build("");
+        cookie.make_removal();
+        cookie
+    }
+
+    pub fn get(&self, req: &HttpRequest) -> Option<String> {
+        req.cookie(&self.name).map(|c| c.value().to_string())
+    }
+
+    /// Adds `Set-Cookie` to `resp`; a value that cannot be encoded is logged and dropped.
+    pub fn set<B>(&self, resp: &mut ServiceResponse<B>, value: impl Into<String>) {
+        if resp.response_mut().add_cookie(&self.build(value)).is_err() {
+            log::warn!("failed to set cookie {}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CookiePrefix, CookieSpec};
+
+    #[test]
+    fn test_defaults() {
+        let spec = CookieSpec::host("csrf").http_only(false);
+        assert_eq!(spec.prefix(), CookiePrefix::Host);
+        assert_eq!(spec.build("t").to_string(), "__Host-csrf=t; SameSite=Lax; Secure; Path=/");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_host_domain() {
+        CookieSpec::host("sid").domain("example.com");
+    }
+}
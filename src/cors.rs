@@ -0,0 +1,318 @@
+use std::{fmt, sync::Arc};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method,
+    },
+    HttpMessage, HttpResponse, ResponseError,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, FromBoxBody, Handler, MwError};
+
+/// An origin, or family of origins, allowed to make cross-origin requests.
+#[derive(Clone)]
+pub enum AllowedOrigin {
+    /// e.g. `https://app.example.com`; compared case-insensitively.
+    Exact(String),
+    /// Any origin whose host is a subdomain of the suffix, e.g. `example.com` or
+    /// `.example.com` allows `https://app.example.com` but not `https://evilexample.com`.
+    Suffix(String),
+    Callback(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl AllowedOrigin {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Suffix(suffix) => {
+                let host = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+                let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+                let suffix = suffix.trim_start_matches('.');
+                host.len() > suffix.len() + 1
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+                    && host.get(host.len() - suffix.len()..).is_some_and(|end| end.eq_ignore_ascii_case(suffix))
+            }
+            AllowedOrigin::Callback(f) => f(origin),
+        }
+    }
+}
+
+impl fmt::Debug for AllowedOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllowedOrigin::Exact(origin) => f.debug_tuple("Exact").field(origin).finish(),
+            AllowedOrigin::Suffix(suffix) => f.debug_tuple("Suffix").field(suffix).finish(),
+            AllowedOrigin::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// The allowed origin of a non-preflight request, echoed in `finalize`.
+#[derive(Clone)]
+struct CorsOrigin(HeaderValue);
+
+/// CORS within the `Handler` model. Preflights from allowed origins are answered from
+/// `process` with `204`; other requests are forwarded and get the CORS headers added.
+/// Requests from other origins get no CORS headers, which makes the browser block them.
+/// Unless every origin gets `*`, all responses carry `Vary: Origin`, so caches do not hand
+/// one origin's answer to another.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Default for Cors {
+    /// No origins allowed; `GET`, `HEAD` and `POST`.
+    fn default() -> Self {
+        Cors {
+            origins: vec![],
+            any_origin: false,
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: vec![],
+            expose_headers: vec![],
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    pub fn allow_origin(mut self, origin: AllowedOrigin) -> Self {
+        self.origins.push(origin);
+        self
+    }
+
+    /// Answers origins not allowed otherwise with `*`.
+    ///
+    /// **Credentials are never allowed for these origins**, whatever `allow_credentials`
+    /// says: browsers do not send cookies to `*`, and echoing every origin instead would let
+    /// any site make authenticated requests. Allow the trusted origins with `allow_origin`.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: Vec<Method>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.expose_headers = headers;
+        self
+    }
+
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight, in seconds.
+    pub fn max_age(mut self, seconds: u32) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin`, if it is allowed.
+    fn allow_origin_value(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let listed = origin.to_str().is_ok_and(|text| self.origins.iter().any(|o| o.allows(text)));
+        if listed {
+            return Some(origin.clone());
+        }
+        self.any_origin.then(|| HeaderValue::from_static("*"))
+    }
+
+    /// Only for listed origins, which are echoed; never with `*`.
+    fn credentials_for(&self, allow_origin: &HeaderValue) -> bool {
+        self.credentials && allow_origin != "*"
+    }
+
+    /// Whether responses depend on the `Origin` header.
+    fn varies(&self) -> bool {
+        !(self.any_origin && self.origins.is_empty())
+    }
+
+    fn join<T: AsRef<str>>(items: &[T]) -> Option<HeaderValue> {
+        let joined = items.iter().map(|i| i.as_ref()).collect::<Vec<_>>().join(", ");
+        HeaderValue::from_str(&joined).ok()
+    }
+
+    fn preflight(&self, req: &ServiceRequest, allow_origin: HeaderValue) -> HttpResponse {
+        let requested_method = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok());
+        if !requested_method.is_some_and(|m| self.methods.contains(&m)) {
+            return MwError::Forbidden("cors_method_not_allowed").error_response();
+        }
+
+        let credentials = self.credentials_for(&allow_origin);
+        let mut resp = HttpResponse::NoContent().finish();
+        let headers = resp.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if let Some(methods) = Self::join(&self.methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Some(allowed) = Self::join(&self.headers).filter(|_| !self.headers.is_empty()) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        if credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        resp
+    }
+}
+
+fn vary_origin(headers: &mut HeaderMap) {
+    let present = headers
+        .get_all(header::VARY)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("origin"));
+    if !present {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for Cors {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let allow_origin = match req.headers().get(header::ORIGIN) {
+            Some(origin) => self.allow_origin_value(origin),
+            None => return Either::Right(req),
+        };
+
+        let is_preflight =
+            req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        match (allow_origin, is_preflight) {
+            (Some(allow_origin), true) => {
+                let resp = self.preflight(&req, allow_origin);
+                Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)))
+            }
+            (None, true) => Either::Left(MwError::Forbidden("cors_origin_not_allowed").reject(req)),
+            (Some(allow_origin), false) => {
+                req.extensions_mut().insert(CorsOrigin(allow_origin));
+                Either::Right(req)
+            }
+            (None, false) => Either::Right(req),
+        }
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let origin = resp.request().extensions().get::<CorsOrigin>().cloned();
+        if self.varies() {
+            // also without an allowed `Origin`: a cached response must not be reused for one
+            vary_origin(resp.headers_mut());
+        }
+        if let Some(CorsOrigin(origin)) = origin {
+            let credentials = self.credentials_for(&origin);
+            let headers = resp.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            if credentials {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+            if let Some(exposed) = Self::join(&self.expose_headers).filter(|_| !self.expose_headers.is_empty()) {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        http::{header, Method},
+        test, web, App, HttpResponse,
+    };
+
+    use super::{AllowedOrigin, Cors};
+    use crate::Factory;
+
+    async fn call(cors: Cors, req: test::TestRequest) -> ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(cors))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        app.call(req.to_request()).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_cors() {
+        let cors = || {
+            Cors::new()
+                .allow_origin(AllowedOrigin::Exact("https://app.example.com".to_string()))
+                .allow_credentials(true)
+        };
+
+        let req = test::TestRequest::get().insert_header((header::ORIGIN, "https://app.example.com"));
+        let resp = call(cors(), req).await;
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+
+        let req = test::TestRequest::get().insert_header((header::ORIGIN, "https://evil.example"));
+        let resp = call(cors(), req).await;
+        assert!(!resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, "https://evil.example"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"));
+        assert_eq!(call(cors(), req).await.status(), 403);
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"));
+        let resp = call(cors(), req).await;
+        assert_eq!(resp.status(), 204);
+        assert_eq!(resp.headers().get_all(header::VARY).count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_suffix() {
+        for suffix in ["example.com", ".example.com"] {
+            let allowed = AllowedOrigin::Suffix(suffix.to_string());
+            assert!(allowed.allows("https://app.example.com"));
+            assert!(allowed.allows("http://a.b.EXAMPLE.com:8080"));
+            assert!(!allowed.allows("https://evilexample.com"));
+            assert!(!allowed.allows("https://example.com"));
+            assert!(!allowed.allows("https://example.com.evil.example"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_any_origin() {
+        // credentials are not extended to arbitrary origins
+        let cors = Cors::new().allow_any_origin().allow_credentials(true);
+        let req = test::TestRequest::get().insert_header((header::ORIGIN, "https://evil.example"));
+        let resp = call(cors, req).await;
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(!resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(!resp.headers().contains_key(header::VARY));
+    }
+}
@@ -1,23 +1,466 @@
-use std::str::FromStr;
+use std::{collections::HashMap, ops::Range, str::FromStr, sync::Arc};
 use crate::*;
+use crate::cookie::CookieSpec;
+use crate::prefetch::prefetch;
+
+mod builder;
+mod key;
+mod replay;
+mod validator;
+
+pub use builder::{CsrfBuilder, CsrfConfigError};
+pub use key::{CsrfKey, HashAlgorithm, HostSecrets, SecretResolver};
+pub use replay::{MemoryReplayStore, ReplayStore};
+pub use validator::TokenValidator;
+#[cfg(feature = "csrf-session")]
+pub use validator::SessionValidator;
+use key::{Keyring, MAX_MAC_LEN};
 
 use actix_web::{
-    http::header::{HeaderName, HeaderValue},
-    body::BoxBody,
-    HttpResponse
+    cookie::{Cookie, SameSite},
+    http::{header::{self, CacheControl, CacheDirective, ContentType, HeaderName, HeaderValue}, Method, Uri},
+    dev::Payload,
+    mime,
+    web,
+    FromRequest,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    HttpResponseBuilder,
+    ResponseError,
+    Route
 };
 
+/// Double-submit cookie settings: the token is also sent as a cookie readable by scripts,
+/// and a request is only accepted when the submitted token equals the cookie value.
+#[derive(Clone, Debug)]
+pub struct CsrfCookie {
+    pub name: String,
+    pub same_site: SameSite,
+}
+
+impl Default for CsrfCookie {
+    fn default() -> Self {
+        CsrfCookie {
+            name: "__Host-csrf".to_string(),
+            same_site: SameSite::Strict,
+        }
+    }
+}
+
+impl CsrfCookie {
+    fn build(&self, token: String) -> Cookie<'static> {
+        CookieSpec::new(self.name.clone())
+            .http_only(false)
+            .same_site(self.same_site)
+            .build(token)
+    }
+}
+
+/// Where a submitted token is looked up; sources are tried in order.
+///
+/// `Form` fields are only looked up when no other source has a token, in `Handler::verify`:
+/// `application/x-www-form-urlencoded` and `multipart/form-data` bodies are buffered (up to
+/// `CSRF::with_form_limit`) and passed on unchanged to the route.
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+    Header(HeaderName),
+    Query(String),
+    Form(String),
+}
+
+impl TokenSource {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            TokenSource::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            TokenSource::Query(param) => {
+                let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?;
+                query.into_inner().remove(param)
+            }
+            TokenSource::Form(_) => req.extensions().get::<FormToken>().map(|token| token.0.clone()),
+        }
+    }
+}
+
+/// Set by `process` when the token can only be in the form body.
+struct FormPending;
+
+/// The token `verify` found in the form body.
+struct FormToken(String);
+
+/// Marks a request forwarded despite a failed check, so that `post` issues no token for it.
+struct Unverified;
+
+/// The value of `field` in a urlencoded or multipart form body.
+fn form_field(req: &HttpRequest, body: &[u8], field: &str) -> Option<String> {
+    let mime = req.mime_type().ok()??;
+    if mime == mime::APPLICATION_WWW_FORM_URLENCODED {
+        let form = web::Query::<HashMap<String, String>>::from_query(std::str::from_utf8(body).ok()?).ok()?;
+        return form.into_inner().remove(field);
+    }
+    if mime.type_() != mime::MULTIPART || mime.subtype() != mime::FORM_DATA {
+        return None;
+    }
+
+    let delimiter = format!("--{}", mime.get_param(mime::BOUNDARY)?);
+    let name = format!("; name=\"{}\"", field);
+    let mut rest = body;
+    while let Some(start) = find(rest, delimiter.as_bytes()) {
+        rest = &rest[start + delimiter.len()..];
+        let part = &rest[..find(rest, delimiter.as_bytes()).unwrap_or(rest.len())];
+        let end = match find(part, b"\r\n\r\n") {
+            Some(end) => end,
+            None => continue,
+        };
+        if find(&part[..end], name.as_bytes()).is_some() {
+            let value = &part[end + 4..];
+            let value = value.strip_suffix(b"\r\n").unwrap_or(value);
+            return String::from_utf8(value.to_vec()).ok();
+        }
+    }
+    None
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Identifies the caller a token is issued to. The value is mixed into the token MAC, so a
+/// token only verifies for the same caller. Requests without a value get unbound tokens.
+#[derive(Clone)]
+pub enum TokenBinding {
+    /// Value of a session cookie.
+    Cookie(String),
+    /// Value of a request header, e.g. one set by an upstream auth proxy.
+    Header(HeaderName),
+    /// Any identity the application can derive, e.g. a user id from request extensions.
+    Custom(Arc<dyn Fn(&HttpRequest) -> Option<String> + Send + Sync>),
+}
+
+impl TokenBinding {
+    fn resolve(&self, req: &HttpRequest) -> Option<String> {
+        match self {
+            TokenBinding::Cookie(name) => req.cookie(name).map(|c| c.value().to_string()),
+            TokenBinding::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            TokenBinding::Custom(f) => f(req),
+        }
+    }
+}
+
+impl std::fmt::Debug for TokenBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenBinding::Cookie(name) => f.debug_tuple("Cookie").field(name).finish(),
+            TokenBinding::Header(name) => f.debug_tuple("Header").field(name).finish(),
+            TokenBinding::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Text encoding of emitted tokens. Verification accepts both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TokenEncoding {
+    /// Compatible with tokens issued by earlier releases.
+    Hex,
+    /// Unpadded base64url, a third shorter than hex.
+    #[default]
+    Base64Url,
+}
+
+/// When `post` hands the client a new token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RotationPolicy {
+    /// Every successful response carries a new token.
+    #[default]
+    PerRequest,
+    /// Requests with unsafe methods get a new token; safe requests keep a still valid one.
+    PerUnsafeRequest,
+    /// A new token is only minted when the client has none or it is no longer valid.
+    OnExpiryOnly,
+}
+
+/// The token issued for the current request, for embedding in rendered forms.
+/// Available to route handlers behind the `CSRF` middleware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+impl FromRequest for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CsrfToken>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("CSRF").into()),
+        )
+    }
+}
+
+/// A `GET` route for SPAs to fetch a token at startup. Responds with
+/// `{"token": "...", "header": "x-csrf-token", "expires_in": 3600}`, `expires_in` in seconds:
+///
+/// `App::new().route("/csrf-token", csrf::token_route(&csrf))`
+pub fn token_route(csrf: &CSRF) -> Route {
+    let csrf = csrf.clone();
+    web::get().to(move |req: HttpRequest| {
+        let token = csrf.current_token(&req);
+        // tokens, header names and numbers need no JSON escaping
+        let body = format!(
+            r#"{{"token":"{}","header":"{}","expires_in":{}}}"#,
+            token,
+            csrf.header_name,
+            csrf.effective.num_seconds()
+        );
+        ready(
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+                .body(body),
+        )
+    })
+}
+
+/// Issues a CSRF token on responses from routes outside the `CSRF` middleware.
+pub trait CsrfResponseExt {
+    /// Sets the token header, and the cookie in double-submit mode.
+    fn with_csrf_token(&mut self, csrf: &CSRF, req: &HttpRequest) -> &mut Self;
+}
+
+impl CsrfResponseExt for HttpResponseBuilder {
+    fn with_csrf_token(&mut self, csrf: &CSRF, req: &HttpRequest) -> &mut Self {
+        let token = csrf.current_token(req);
+        if let Some(cookie) = &csrf.cookie {
+            self.cookie(cookie.build(token.clone()));
+        }
+        self.insert_header((csrf.header_name.clone(), token))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfRejectionReason {
+    MissingToken,
+    MalformedToken,
+    Expired,
+    /// The token is not authentic, is bound to another caller, or differs from the cookie.
+    Mismatch,
+    /// A one-time token was already used.
+    Replayed,
+    /// Origin checking is enabled but the request has neither `Origin` nor `Referer`.
+    MissingOrigin,
+    /// The `Origin` (or `Referer`) is not in the allowlist.
+    OriginMismatch,
+    /// The token uses an older layout and the version grace window has ended.
+    OutdatedVersion,
+}
+
+impl CsrfRejectionReason {
+    /// The code of the default `403` `MwError` response.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CsrfRejectionReason::MissingToken => "csrf_missing_token",
+            CsrfRejectionReason::MalformedToken => "csrf_malformed_token",
+            CsrfRejectionReason::Expired => "csrf_expired_token",
+            CsrfRejectionReason::Mismatch => "csrf_invalid_token",
+            CsrfRejectionReason::Replayed => "csrf_replayed_token",
+            CsrfRejectionReason::MissingOrigin => "csrf_missing_origin",
+            CsrfRejectionReason::OriginMismatch => "csrf_origin_mismatch",
+            CsrfRejectionReason::OutdatedVersion => "csrf_outdated_token",
+        }
+    }
+}
+
+pub type RejectionHandlerFn = dyn Fn(&ServiceRequest, CsrfRejectionReason) -> HttpResponse + Send + Sync;
+pub type OnRejectFn = dyn Fn(&ServiceRequest, CsrfRejectionReason) + Send + Sync;
+
+/// Ready-made `on_reject` hook emitting a `tracing` warning with client IP, method, path
+/// and reason.
+#[cfg(feature = "tracing")]
+pub fn trace_rejection(req: &ServiceRequest, reason: CsrfRejectionReason) {
+    let client_ip = crate::client_addr(req.request()).map(|ip| ip.to_string());
+    tracing::warn!(
+        client_ip = client_ip.as_deref().unwrap_or("-"),
+        method = %req.method(),
+        path = req.path(),
+        ?reason,
+        "CSRF check failed"
+    );
+}
+
+/// Shared callback or store that keeps `CSRF` cloneable and debuggable.
+struct Opaque<T: ?Sized>(Arc<T>);
+
+impl<T: ?Sized> Clone for Opaque<T> {
+    fn clone(&self) -> Self {
+        Opaque(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Opaque<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("..")
+    }
+}
+
+const DEFAULT_FORM_LIMIT: usize = 64 * 1024;
+
 #[derive(Clone, Debug)]
 pub struct CSRF {
-    skip_urls: Vec<String>,
+    skip_rules: SkipSet,
+    keys: Keyring,
     salt: String,
+    legacy_tokens: bool,
+    /// Tokens older than `TOKEN_VERSION` are accepted until then; `None` accepts them always.
+    old_versions_until: Option<chrono::DateTime<chrono::Utc>>,
     pub effective: chrono::Duration,
     pub header_name: HeaderName,
+    pub cookie: Option<CsrfCookie>,
+    pub safe_methods: Vec<Method>,
+    pub sources: Vec<TokenSource>,
+    pub binding: Option<TokenBinding>,
+    rejection_handler: Option<Opaque<RejectionHandlerFn>>,
+    on_reject: Option<Opaque<OnRejectFn>>,
+    replay: Option<Opaque<dyn ReplayStore>>,
+    resolver: Option<Opaque<dyn SecretResolver>>,
+    validator: Option<Opaque<dyn TokenValidator>>,
+    allowed_origins: Option<Vec<String>>,
+    masked: bool,
+    pub rotation: RotationPolicy,
+    pub algorithm: HashAlgorithm,
+    pub encoding: TokenEncoding,
+    truncate_mac: bool,
+    enforcement: EnforcementMode,
+    form_limit: usize,
 }
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
 use sha2::Digest;
+use subtle::ConstantTimeEq;
+
+fn normalize_origins(origins: Vec<String>) -> Vec<String> {
+    origins
+        .into_iter()
+        .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+        .collect()
+}
+
+/// `scheme://host[:port]` of a URL such as a `Referer` value.
+fn origin_of(url: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+fn default_safe_methods() -> Vec<Method> {
+    vec![Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE]
+}
+
+/// Tokens issued by earlier releases: 8-byte timestamp and HMAC-SHA256, checked against
+/// the primary key.
+const UNTAGGED_TOKEN_LEN: usize = 40;
+/// Longest base64url input that fits `MAX_RAW_LEN` once decoded.
+const MAX_BASE64_LEN: usize = (MAX_RAW_LEN * 4 + 2) / 3;
+/// Layout written by `sign_token`. Version 0 is the untagged 40-byte token, version 1
+/// the key- and algorithm-tagged token without a version byte.
+pub const TOKEN_VERSION: u8 = 2;
+/// Version byte, key id and algorithm id in front of every version 2 token.
+const HEADER_LEN: usize = 3;
+const NONCE_LEN: usize = 16;
+const MAX_TOKEN_LEN: usize = HEADER_LEN + 8 + NONCE_LEN + MAX_MAC_LEN;
+/// Longest accepted input: a masked token of the longest layout.
+const MAX_RAW_LEN: usize = MAX_TOKEN_LEN * 2;
+/// Set on the algorithm id when the MAC is cut to `TRUNCATED_MAC_LEN` bytes.
+const TRUNCATED_FLAG: u8 = 0x80;
+const TRUNCATED_MAC_LEN: usize = 16;
+
+/// A decoded token split into its parts:
+/// `version | key id | algorithm id | timestamp | nonce (one-time tokens only) | MAC`.
+struct RawToken<'a> {
+    version: u8,
+    key_id: Option<u8>,
+    alg: HashAlgorithm,
+    issued_at: &'a [u8],
+    nonce: &'a [u8],
+    mac: &'a [u8],
+}
+
+impl<'a> RawToken<'a> {
+    fn parse(raw: &'a [u8]) -> Option<Self> {
+        if raw.len() == UNTAGGED_TOKEN_LEN {
+            return Some(RawToken {
+                version: 0,
+                key_id: None,
+                alg: HashAlgorithm::HmacSha256,
+                issued_at: &raw[0..8],
+                nonce: &[],
+                mac: &raw[8..],
+            });
+        }
+
+        // version 2 layouts have odd lengths, version 1 layouts even ones
+        match raw.split_first() {
+            Some((&TOKEN_VERSION, tagged)) if raw.len() % 2 == 1 => Self::parse_tagged(TOKEN_VERSION, tagged),
+            _ if raw.len() % 2 == 0 => Self::parse_tagged(1, raw),
+            _ => None,
+        }
+    }
+
+    /// `raw` starts at the key id.
+    fn parse_tagged(version: u8, raw: &'a [u8]) -> Option<Self> {
+        if raw.len() < 2 + 8 {
+            return None;
+        }
+
+        let alg = HashAlgorithm::from_id(raw[1] & !TRUNCATED_FLAG)?;
+        let mac_len = if raw[1] & TRUNCATED_FLAG != 0 {
+            TRUNCATED_MAC_LEN
+        } else {
+            alg.mac_len()
+        };
+        let body_len = raw.len().checked_sub(2 + mac_len)?;
+        if body_len != 8 && body_len != 8 + NONCE_LEN {
+            return None;
+        }
+
+        let (body, mac) = raw[2..].split_at(body_len);
+        let (issued_at, nonce) = body.split_at(8);
+
+        Some(RawToken {
+            version,
+            key_id: Some(raw[0]),
+            alg,
+            issued_at,
+            nonce,
+            mac,
+        })
+    }
+
+    fn issued_at(&self) -> i64 {
+        let mut issued_at = [0u8; 8];
+        issued_at.copy_from_slice(self.issued_at);
+        i64::from_le_bytes(issued_at)
+    }
+}
+
+/// What `CSRF::inspect_token` can tell about a token without verifying it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub version: u8,
+    pub issued_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
 
 impl CSRF {
+    #[deprecated(note = "salted SHA-256 tokens; use `CSRF::generate_token` with a `CsrfKey`")]
     pub fn token(salt: &str) -> String {
         let now = chrono::Utc::now().timestamp_millis().to_le_bytes();
         let mut src = vec![0; 8+salt.len()];
@@ -31,101 +474,637 @@ impl CSRF {
         hex::encode(dst)
     }
 
+    pub fn builder() -> CsrfBuilder {
+        CsrfBuilder::default()
+    }
+
+    /// `salt` is used as the HMAC key; see `with_key` for a proper random key.
+    /// Panics on an invalid `header_name`; `CSRF::builder` reports it as an error instead.
     pub fn new(header_name: &str, skip_urls: Vec<String>, salt: &str, effective_duration: chrono::Duration) -> Self {
+        let header_name = HeaderName::from_str(&header_name).unwrap();
         CSRF {
-            header_name: HeaderName::from_str(&header_name).unwrap(),
-            skip_urls,
+            sources: vec![TokenSource::Header(header_name.clone())],
+            binding: None,
+            rejection_handler: None,
+            on_reject: None,
+            replay: None,
+            resolver: None,
+            validator: None,
+            allowed_origins: None,
+            masked: false,
+            rotation: RotationPolicy::default(),
+            algorithm: HashAlgorithm::default(),
+            encoding: TokenEncoding::Hex,
+            truncate_mac: false,
+            enforcement: EnforcementMode::Enforce,
+            form_limit: DEFAULT_FORM_LIMIT,
+            header_name,
+            skip_rules: skip_urls.into_iter().map(SkipRule::from).collect(),
+            keys: Keyring::new(CsrfKey::from_bytes(salt.as_bytes())),
             salt: salt.to_string(),
+            legacy_tokens: false,
+            old_versions_until: None,
             effective: effective_duration,
+            cookie: None,
+            safe_methods: default_safe_methods(),
         }
     }
 
+    /// Adds a rule for requests that bypass the middleware, e.g.
+    /// `SkipRule::path("/api/webhooks").method(Method::POST)`.
+    pub fn with_skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+
+    /// Sets the key new tokens are signed with.
+    pub fn with_key(mut self, key: CsrfKey) -> Self {
+        self.keys.set_primary(key);
+        self
+    }
+
+    /// Keys that are no longer used for signing but whose tokens are still accepted.
+    /// Keys reusing the id of an already configured key are ignored; `CsrfBuilder`
+    /// reports them as an error.
+    pub fn with_previous_keys(mut self, keys: Vec<CsrfKey>) -> Self {
+        for key in keys {
+            self.keys.add_previous(key);
+        }
+        self
+    }
+
+    /// Selects the signing key per request, e.g. per tenant host. Requests the resolver has
+    /// no key for use the keys configured with `with_key`.
+    pub fn with_secret_resolver<R: SecretResolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Opaque(Arc::new(resolver)));
+        self
+    }
+
+    /// Accepts tokens of older layouts for `grace` from now, e.g. the token ttl while a
+    /// deploy rolls out.
+    pub fn with_version_grace(mut self, grace: chrono::Duration) -> Self {
+        self.old_versions_until = Some(chrono::Utc::now() + grace);
+        self
+    }
+
+    /// Also accept tokens in the old `SHA256(timestamp || salt)` format while clients migrate.
+    #[deprecated(note = "salted SHA-256 tokens are weaker than HMAC tokens")]
+    pub fn accept_legacy_tokens(mut self, accept: bool) -> Self {
+        self.legacy_tokens = accept;
+        self
+    }
+
+    /// Methods that are forwarded without a token check; `post` still issues a fresh token.
+    pub fn with_safe_methods(mut self, methods: Vec<Method>) -> Self {
+        self.safe_methods = methods;
+        self
+    }
+
+    /// Replaces the default of reading the token from `header_name`.
+    pub fn with_token_sources(mut self, sources: Vec<TokenSource>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Largest form body read for a `TokenSource::Form` token; larger ones get a `413`.
+    /// Defaults to 64 KiB.
+    pub fn with_form_limit(mut self, limit: usize) -> Self {
+        self.form_limit = limit;
+        self
+    }
+
+    /// Whether the token of `req` may still be in its body.
+    fn reads_form(&self, req: &ServiceRequest) -> bool {
+        let form = match req.mime_type() {
+            Ok(Some(mime)) => {
+                mime == mime::APPLICATION_WWW_FORM_URLENCODED
+                    || (mime.type_() == mime::MULTIPART && mime.subtype() == mime::FORM_DATA)
+            }
+            _ => false,
+        };
+        form && self.sources.iter().any(|source| matches!(source, TokenSource::Form(_)))
+    }
+
+    /// Binds issued tokens to the caller identity resolved by `binding`.
+    pub fn with_binding(mut self, binding: TokenBinding) -> Self {
+        self.binding = Some(binding);
+        self
+    }
+
+    /// Called with every rejected request before the rejection response is built,
+    /// e.g. `trace_rejection` or a SIEM exporter.
+    pub fn with_on_reject<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ServiceRequest, CsrfRejectionReason) + Send + Sync + 'static,
+    {
+        self.on_reject = Some(Opaque(Arc::new(hook)));
+        self
+    }
+
+    /// With `EnforcementMode::ReportOnly`, failed checks call the `with_on_reject` hook and
+    /// are logged, but the request is forwarded; no fresh token is issued for it.
+    pub fn with_enforcement(mut self, mode: EnforcementMode) -> Self {
+        self.enforcement = mode;
+        self
+    }
+
+    /// Issues single-use tokens; a token is rejected once `store` has seen its nonce.
+    pub fn with_replay_store<S: ReplayStore + 'static>(mut self, store: S) -> Self {
+        self.replay = Some(Opaque(Arc::new(store)));
+        self
+    }
+
+    /// Also checks every token that passed the signature check against `validator`, e.g. a
+    /// `SessionValidator`; a rejection answers `403` like any other failed check.
+    pub fn with_validator<V: TokenValidator + 'static>(mut self, validator: V) -> Self {
+        self.validator = Some(Opaque(Arc::new(validator)));
+        self
+    }
+
+    /// Checks `Origin` (falling back to `Referer`) against `origins`, e.g.
+    /// `https://example.com`, before looking at the token.
+    pub fn with_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = Some(normalize_origins(origins));
+        self
+    }
+
+    fn check_origin(&self, req: &ServiceRequest) -> Result<(), CsrfRejectionReason> {
+        let allowed = match &self.allowed_origins {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+
+        let headers = req.headers();
+        let origin = match headers.get(header::ORIGIN) {
+            Some(origin) => origin.to_str().ok().map(str::to_string),
+            None => match headers.get(header::REFERER) {
+                Some(referer) => referer.to_str().ok().and_then(origin_of),
+                None => return Err(CsrfRejectionReason::MissingOrigin),
+            },
+        };
+
+        match origin {
+            Some(origin) if allowed.contains(&origin.to_ascii_lowercase()) => Ok(()),
+            _ => Err(CsrfRejectionReason::OriginMismatch),
+        }
+    }
+
+    /// Emits every token XOR-masked with a fresh random pad so the bytes differ on each
+    /// response (BREACH mitigation). Unmasked tokens are still accepted.
+    pub fn with_masked_tokens(mut self, masked: bool) -> Self {
+        self.masked = masked;
+        self
+    }
+
+    /// Algorithm for new tokens; tokens signed with any compiled-in algorithm are accepted.
+    pub fn with_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: TokenEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Cuts the MAC of new tokens to 16 bytes for shorter headers and cookies.
+    pub fn with_truncated_mac(mut self, truncate: bool) -> Self {
+        self.truncate_mac = truncate;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: RotationPolicy) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Builds the response for rejected requests instead of the default `403 Forbidden`.
+    pub fn with_rejection_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&ServiceRequest, CsrfRejectionReason) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.rejection_handler = Some(Opaque(Arc::new(handler)));
+        self
+    }
+
+    fn binding_for(&self, req: &HttpRequest) -> Option<String> {
+        self.binding.as_ref().and_then(|b| b.resolve(req))
+    }
+
+    fn extract_token(&self, req: &ServiceRequest) -> Option<String> {
+        self.sources.iter().find_map(|source| source.extract(req))
+    }
+
+    /// Enables double-submit cookie mode.
+    pub fn with_cookie(mut self, cookie: CsrfCookie) -> Self {
+        self.cookie = Some(cookie);
+        self
+    }
+
+    fn cookie_matches(&self, req: &ServiceRequest, token: &str) -> bool {
+        match &self.cookie {
+            Some(cookie) => match req.cookie(&cookie.name) {
+                Some(c) => c.value().as_bytes().ct_eq(token.as_bytes()).into(),
+                None => false,
+            },
+            None => true,
+        }
+    }
+
+    /// Signs with the `with_key` key; `generate_token_for` also consults the secret resolver.
     pub fn generate_token(&self) -> String {
-        CSRF::token(&self.salt)
+        self.sign_token(self.keys.primary(), None)
+    }
+
+    /// A token bound to the caller of `req` when a `TokenBinding` is configured.
+    pub fn generate_token_for(&self, req: &HttpRequest) -> String {
+        let key = self.tenant_key(req).unwrap_or(self.keys.primary());
+        self.sign_token(key, self.binding_for(req).as_deref())
+    }
+
+    fn tenant_key(&self, req: &HttpRequest) -> Option<&CsrfKey> {
+        self.resolver.as_ref().and_then(|r| r.0.secret_for(req))
+    }
+
+    fn sign_token(&self, key: &CsrfKey, binding: Option<&str>) -> String {
+        let now = chrono::Utc::now().timestamp_millis().to_le_bytes();
+
+        let mut nonce_buf = [0u8; NONCE_LEN];
+        let nonce: &[u8] = if self.replay.is_some() {
+            rand::thread_rng().fill_bytes(&mut nonce_buf);
+            &nonce_buf
+        } else {
+            &[]
+        };
+
+        let mut mac = key.sign(self.algorithm, &[&now, nonce, binding.unwrap_or_default().as_bytes()]);
+        let mut alg_id = self.algorithm.id();
+        if self.truncate_mac {
+            mac.truncate(TRUNCATED_MAC_LEN);
+            alg_id |= TRUNCATED_FLAG;
+        }
+
+        let mut dst = Vec::with_capacity(HEADER_LEN + 8 + nonce.len() + mac.len());
+        dst.push(TOKEN_VERSION);
+        dst.push(key.id());
+        dst.push(alg_id);
+        dst.extend_from_slice(&now);
+        dst.extend_from_slice(nonce);
+        dst.extend_from_slice(&mac);
+        self.encode(dst)
+    }
+
+    fn encode(&self, raw: Vec<u8>) -> String {
+        let raw = if self.masked {
+            let mut masked = vec![0; raw.len() * 2];
+            let (pad, body) = masked.split_at_mut(raw.len());
+            rand::thread_rng().fill_bytes(pad);
+            for ((b, p), r) in body.iter_mut().zip(pad.iter()).zip(raw.iter()) {
+                *b = p ^ r;
+            }
+            masked
+        } else {
+            raw
+        };
+
+        match self.encoding {
+            TokenEncoding::Hex => hex::encode(raw),
+            TokenEncoding::Base64Url => URL_SAFE_NO_PAD.encode(raw),
+        }
     }
 
     pub fn verify_token(&self, test_token: &str) -> bool {
-        let test_token = hex::decode(test_token);
+        self.check_token(test_token, None, true).is_ok()
+    }
+
+    /// Verifies a token issued by `generate_token_for` to the same caller.
+    pub fn verify_token_for(&self, req: &HttpRequest, test_token: &str) -> bool {
+        self.check_token(test_token, Some(req), true).is_ok()
+    }
+
+    /// Hex-decodes `token` into `buf` and removes the mask if there is one.
+    fn decode<'a>(&self, token: &str, buf: &'a mut [u8; MAX_RAW_LEN]) -> Option<&'a [u8]> {
+        // reject anything longer than the longest token before decoding
+        if token.len() > MAX_RAW_LEN * 2 {
+            return None;
+        }
+
+        // either encoding is accepted regardless of `self.encoding`, so deployments can switch
+        let mut range = None;
+        if token.len() % 2 == 0 {
+            let raw = &mut buf[..token.len() / 2];
+            if hex::decode_to_slice(token, &mut *raw).is_ok() {
+                range = self.unmask(raw);
+            }
+        }
+
+        if range.is_none() && token.len() <= MAX_BASE64_LEN {
+            let raw_len = URL_SAFE_NO_PAD.decode_slice(token, &mut buf[..]).ok()?;
+            range = self.unmask(&mut buf[..raw_len]);
+        }
+
+        range.map(|range| &buf[range])
+    }
+
+    /// Removes the mask if there is one; returns where the token lies within `raw`.
+    fn unmask(&self, raw: &mut [u8]) -> Option<Range<usize>> {
+        // masked lengths never coincide with a valid unmasked layout
+        if RawToken::parse(raw).is_some() {
+            return Some(0..raw.len());
+        }
+        if !self.masked || raw.len() % 2 != 0 {
+            return None;
+        }
+
+        let half = raw.len() / 2;
+        let (pad, body) = raw.split_at_mut(half);
+        for (b, p) in body.iter_mut().zip(pad.iter()) {
+            *b ^= p;
+        }
+        RawToken::parse(body)?;
+        Some(half..raw.len())
+    }
+
+    /// Decodes `token` for debugging. The signature is not checked; use `verify_token` for that.
+    pub fn inspect_token(&self, token: &str) -> Option<TokenInfo> {
+        let mut buf = [0u8; MAX_RAW_LEN];
+        let token = self.decode(token, &mut buf).and_then(RawToken::parse)?;
+        let issued_at = chrono::TimeZone::timestamp_millis_opt(&chrono::Utc, token.issued_at()).single()?;
+        Some(TokenInfo {
+            version: token.version,
+            issued_at,
+            expires_at: issued_at + self.effective,
+        })
+    }
+
+    /// With `consume` false a one-time token is checked without being used up.
+    fn check_token(
+        &self,
+        test_token: &str,
+        req: Option<&HttpRequest>,
+        consume: bool,
+    ) -> Result<(), CsrfRejectionReason> {
+        let mut test_token_buf = [0u8; MAX_RAW_LEN];
+        let test_token = self
+            .decode(test_token, &mut test_token_buf)
+            .and_then(RawToken::parse)
+            .ok_or(CsrfRejectionReason::MalformedToken)?;
+
+        let binding = req.and_then(|req| self.binding_for(req));
+        let tenant_key = req.and_then(|req| self.tenant_key(req));
+        if !self.authentic(&test_token, binding.unwrap_or_default().as_bytes(), tenant_key) {
+            return Err(CsrfRejectionReason::Mismatch);
+        }
 
-        if let Ok(test_token) = test_token {
-            if test_token.len() != 40 {
-                return false;
+        if test_token.version < TOKEN_VERSION {
+            if let Some(until) = self.old_versions_until {
+                if chrono::Utc::now() > until {
+                    return Err(CsrfRejectionReason::OutdatedVersion);
+                }
             }
-            
-            let generate_time = test_token[0..8].try_into();
-            if generate_time.is_err() {
-                return false;
+        }
+
+        let generate_time = test_token.issued_at();
+        let now = chrono::Utc::now().timestamp_millis();
+        if now < generate_time {
+            return Err(CsrfRejectionReason::MalformedToken);
+        }
+
+        let delta = chrono::Duration::milliseconds(now - generate_time);
+        if delta > self.effective {
+            return Err(CsrfRejectionReason::Expired);
+        }
+
+        if let Some(replay) = &self.replay {
+            if test_token.nonce.is_empty() || (consume && !replay.0.consume(test_token.nonce)) {
+                return Err(CsrfRejectionReason::Replayed);
             }
+        }
+
+        Ok(())
+    }
 
-            let generate_time = i64::from_le_bytes(generate_time.unwrap());
+    /// A tenant key replaces the keyring entirely, so tokens never cross tenants.
+    fn authentic(&self, test_token: &RawToken, binding: &[u8], tenant_key: Option<&CsrfKey>) -> bool {
+        if let Some(key) = tenant_key {
+            return test_token.key_id.map_or(true, |id| id == key.id())
+                && key.verify(test_token.alg, &[test_token.issued_at, test_token.nonce, binding], test_token.mac);
+        }
 
-            let now = chrono::Utc::now().timestamp_millis();            
-            if now < generate_time {
-                return false
+        let key = match test_token.key_id {
+            Some(id) => self.keys.get(id),
+            None => Some(self.keys.primary()),
+        };
+        if let Some(key) = key {
+            if key.verify(test_token.alg, &[test_token.issued_at, test_token.nonce, binding], test_token.mac) {
+                return true;
             }
+        }
 
-            let delta = chrono::Duration::milliseconds(now - generate_time);
-            if delta > self.effective {
-                return false;
+        if test_token.key_id.is_some() || !self.legacy_tokens || !binding.is_empty() {
+            return false;
+        }
+
+        let hash = sha2::Sha256::new()
+            .chain_update(test_token.issued_at)
+            .chain_update(self.salt.as_bytes())
+            .finalize();
+        hash.as_slice().ct_eq(test_token.mac).into()
+    }
+
+    fn verify_request(&self, req: &ServiceRequest) -> Result<(), CsrfRejectionReason> {
+        self.check_origin(req)?;
+        let token = self.extract_token(req).ok_or(CsrfRejectionReason::MissingToken)?;
+        if !self.cookie_matches(req, &token) {
+            return Err(CsrfRejectionReason::Mismatch);
+        }
+        self.check_token(&token, Some(req.request()), true)
+    }
+
+    /// Decides which token the response carries: a new one, or the valid token the client
+    /// already has when the rotation policy allows keeping it.
+    fn issue_token(&self, req: &ServiceRequest, verified: bool) {
+        let keep = match self.rotation {
+            RotationPolicy::PerRequest => None,
+            RotationPolicy::PerUnsafeRequest if !self.safe_methods.contains(req.method()) => None,
+            RotationPolicy::PerUnsafeRequest | RotationPolicy::OnExpiryOnly => {
+                self.reusable_token(req, verified)
             }
+        };
 
-            let mut hash = vec![0; 8 + self.salt.len()];
-            hash[0..8].copy_from_slice(&test_token[0..8]);
-            hash[8..].copy_from_slice(self.salt.as_bytes());
+        let token = keep.unwrap_or_else(|| self.generate_token_for(req.request()));
+        req.extensions_mut().insert(CsrfToken(token));
+    }
 
-            let hash = sha2::Sha256::digest(&hash);
-            return &hash[..] == &test_token[8..];
+    /// The token the middleware issued for `req`, or a new one outside the middleware.
+    fn current_token(&self, req: &HttpRequest) -> String {
+        match req.extensions().get::<CsrfToken>() {
+            Some(CsrfToken(token)) => token.clone(),
+            None => self.generate_token_for(req),
         }
-        
-        return false;
     }
 
-    
+    fn reusable_token(&self, req: &ServiceRequest, verified: bool) -> Option<String> {
+        // a verified one-time token has just been used up
+        if self.replay.is_some() && verified {
+            return None;
+        }
+
+        let token = self.extract_token(req)?;
+        if !verified {
+            self.check_token(&token, Some(req.request()), false).ok()?;
+        }
+
+        if !self.masked {
+            return Some(token);
+        }
+
+        // re-mask so the emitted bytes still change on every response
+        let mut buf = [0u8; MAX_RAW_LEN];
+        let raw = self.decode(&token, &mut buf)?;
+        Some(self.encode(raw.to_vec()))
+    }
+
+    /// The `with_validator` check of a token the signature check accepted.
+    async fn validate(&self, req: &HttpRequest) -> Result<(), CsrfRejectionReason> {
+        let validator = match &self.validator {
+            Some(validator) if !req.extensions().contains::<Unverified>() => validator,
+            _ => return Ok(()),
+        };
+        let token = self
+            .extract_token(&ServiceRequest::from_request(req.clone()))
+            .ok_or(CsrfRejectionReason::MissingToken)?;
+        validator.0.validate(req, &token).await
+    }
+
+    fn report(&self, req: &ServiceRequest, reason: CsrfRejectionReason) {
+        if let Some(hook) = &self.on_reject {
+            (hook.0)(req, reason);
+        }
+    }
+
+    /// Issues the next token once `req` passed its check, else answers (or only reports) it.
+    fn conclude<B: FromBoxBody>(
+        &self,
+        req: ServiceRequest,
+        checked: Result<(), CsrfRejectionReason>,
+    ) -> Either<ServiceResponse<B>, ServiceRequest> {
+        match checked {
+            Ok(()) => {
+                self.issue_token(&req, true);
+                Either::Right(req)
+            }
+            Err(reason) if !self.enforcement.is_enforced() => {
+                self.report(&req, reason);
+                log::warn!("CSRF check would reject {} {}: {}", req.method(), req.path(), reason.code());
+                req.extensions_mut().insert(Unverified);
+                Either::Right(req)
+            }
+            Err(reason) => Either::Left(self.reject(req, reason).map_body(|_, body| B::from_box_body(body))),
+        }
+    }
+
+    fn reject(&self, req: ServiceRequest, reason: CsrfRejectionReason) -> ServiceResponse {
+        self.report(&req, reason);
+
+        let resp = match &self.rejection_handler {
+            Some(handler) => (handler.0)(&req, reason),
+            None => MwError::Forbidden(reason.code()).error_response(),
+        };
+        req.into_response(resp)
+    }
+}
+
+fn internal_error<B: FromBoxBody>(resp: ServiceResponse<B>) -> ServiceResponse<B> {
+    let err = MwError::Config("CSRF token cannot be sent in a header or cookie".to_string());
+    resp.into_response(err.error_response())
+        .map_body(|_, body| B::from_box_body(body))
 }
 
-impl Handler<BoxBody> for CSRF {
+impl<B: FromBoxBody + 'static> Handler<B> for CSRF {
     fn skip(&self, req: &ServiceRequest) -> bool {
-        let test_path = req.path();
-        for url in &self.skip_urls {
-            if match_uri(test_path, url) {
-                return true;
-            }
+        if self.skip_rules.matches(req) {
+            self.issue_token(req, false);
+            return true;
         }
         false
     }
 
-    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse, ServiceRequest> {
-        match req.headers().get(&self.header_name) {
-            Some(token) => {
-                match token.to_str() {
-                    Ok(token) => {
-                        if self.verify_token(token) {
-                            Either::Right(req)
-                        } else {
-                            Either::Left(req.into_response(HttpResponse::Forbidden().body("Forbidden")))
-                        }
-                        
-                    },
-                    Err(_) => {
-                        Either::Left(req.into_response(HttpResponse::Forbidden().body("Forbidden")))
-                    }
-                }
-            },
-            None => {
-                Either::Left(req.into_response(HttpResponse::Forbidden().body("Forbidden")))
-            },
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if self.safe_methods.contains(req.method()) {
+            self.issue_token(&req, false);
+            return Either::Right(req);
+        }
+
+        match self.verify_request(&req) {
+            // looked up in the body by `verify`
+            Err(CsrfRejectionReason::MissingToken) if self.reads_form(&req) => {
+                req.extensions_mut().insert(FormPending);
+                Either::Right(req)
+            }
+            checked => self.conclude(req, checked),
         }
     }
 
-    fn post(&self, mut resp: ServiceResponse) -> ServiceResponse {
-        if resp.status().is_success() {
-            let token = self.generate_token();
-            let token = HeaderValue::from_str(&token);
-            if token.is_err() {
-                return resp.into_response(HttpResponse::InternalServerError().body("InternalServerError"))
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let form = req.extensions_mut().remove::<FormPending>().is_some();
+        let validate = self.validator.is_some()
+            && !self.safe_methods.contains(req.method())
+            && !req.extensions().contains::<Unverified>();
+        if !form && !validate {
+            return None;
+        }
+
+        let body = form.then(|| prefetch(req, self.form_limit));
+        let csrf = self.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            if let Some(body) = body {
+                let body = match body.await {
+                    Ok(body) => body,
+                    Err(err) => return Err(err.respond(req)),
+                };
+                let token = csrf.sources.iter().find_map(|source| match source {
+                    TokenSource::Form(field) => form_field(&req, &body, field),
+                    _ => None,
+                });
+                if let Some(token) = token {
+                    req.extensions_mut().insert(FormToken(token));
+                }
+
+                let checked = csrf.verify_request(&ServiceRequest::from_request(req.clone()));
+                if let Either::Left(resp) = csrf.conclude(ServiceRequest::from_request(req.clone()), checked) {
+                    return Err(resp);
+                }
+            }
+
+            match csrf.validate(&req).await {
+                Ok(()) => Ok(()),
+                Err(reason) => match csrf.conclude(ServiceRequest::from_request(req), Err(reason)) {
+                    Either::Left(resp) => Err(resp),
+                    Either::Right(_) => Ok(()),
+                },
+            }
+        }))
+    }
+
+    fn post(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        // a request that would have been rejected must not walk away with a valid token
+        let unverified = info.reported.is_some() || resp.request().extensions().contains::<Unverified>();
+        if resp.status().is_success() && !unverified {
+            let token = self.current_token(resp.request());
+            let value = HeaderValue::from_str(&token);
+            if value.is_err() {
+                return internal_error(resp)
             }
 
-            resp.headers_mut().insert(self.header_name.clone(), token.unwrap());
+            resp.headers_mut().insert(self.header_name.clone(), value.unwrap());
+
+            if let Some(cookie) = &self.cookie {
+                if resp.response_mut().add_cookie(&cookie.build(token)).is_err() {
+                    return internal_error(resp)
+                }
+            }
         }
 
         resp
@@ -137,8 +1116,26 @@ impl Handler<BoxBody> for CSRF {
 #[cfg(feature = "csrf")]
 mod tests {
     
+    use actix_web::{dev::ServiceResponse, test::TestRequest};
     use sha2::Digest;
 
+    /// Runs `req` through `csrf` in front of a route answering `200` to any method.
+    async fn call(csrf: &super::CSRF, req: TestRequest) -> ServiceResponse {
+        use actix_web::{dev::Service, test, web, App, HttpResponse};
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::Factory::new(csrf.clone()))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        app.call(req.to_request()).await.unwrap()
+    }
+
+    fn header(resp: &ServiceResponse) -> Option<&str> {
+        resp.headers().get("x-csrf-token").and_then(|v| v.to_str().ok())
+    }
+
     #[test]
     #[cfg(feature = "csrf")]
     fn test_hash() {
@@ -202,4 +1199,535 @@ mod tests {
         println!("{}", csrf.verify_token(&token));
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_builder() {
+        let err = super::CSRF::builder()
+            .header_name("bad header")
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap_err();
+        assert_eq!(err, super::CsrfConfigError::InvalidHeaderName("bad header".to_string()));
+
+        let err = super::CSRF::builder().build().unwrap_err();
+        assert_eq!(err, super::CsrfConfigError::MissingSecret);
+
+        let csrf = super::CSRF::builder()
+            .header_name("x-token")
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+        assert!(csrf.verify_token(&token));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = super::CsrfKey::generate().with_id(1);
+        let new = super::CsrfKey::generate().with_id(2);
+
+        let before = super::CSRF::builder().secret(old.clone()).build().unwrap();
+        let token = before.generate_token();
+
+        let after = super::CSRF::builder()
+            .secret(new.clone())
+            .previous_secret(old.clone())
+            .build()
+            .unwrap();
+        assert!(after.verify_token(&token));
+
+        let dropped = super::CSRF::builder().secret(new).build().unwrap();
+        assert!(!dropped.verify_token(&token));
+
+        let err = super::CSRF::builder()
+            .secret(old.clone())
+            .previous_secret(old)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, super::CsrfConfigError::DuplicateKeyId(1));
+    }
+
+    #[test]
+    fn test_one_time_token() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .one_time_tokens(super::MemoryReplayStore::new(std::time::Duration::from_secs(3600)))
+            .build()
+            .unwrap();
+
+        let token = csrf.generate_token();
+        assert!(csrf.verify_token(&token));
+        assert!(!csrf.verify_token(&token));
+    }
+
+    #[test]
+    fn test_masked_token() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .masked_tokens(true)
+            .build()
+            .unwrap();
+
+        let first = csrf.generate_token();
+        let second = csrf.generate_token();
+        assert_ne!(first, second);
+        assert!(csrf.verify_token(&first));
+        assert!(csrf.verify_token(&second));
+    }
+
+    #[test]
+    fn test_host_secrets() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .secret_resolver(
+                super::HostSecrets::new()
+                    .host("a.example.com", super::CsrfKey::generate())
+                    .host("b.example.com", super::CsrfKey::generate()),
+            )
+            .build()
+            .unwrap();
+
+        let a = TestRequest::default().insert_header(("host", "a.example.com")).to_http_request();
+        let b = TestRequest::default().insert_header(("host", "b.example.com")).to_http_request();
+
+        let token = csrf.generate_token_for(&a);
+        assert!(!csrf.verify_token_for(&b, &token));
+        assert!(!csrf.verify_token(&token));
+        assert!(csrf.verify_token_for(&a, &token));
+    }
+
+    #[actix_web::test]
+    async fn test_token_route() {
+        use actix_web::{test, App};
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+        let app = test::init_service(App::new().route("/csrf-token", super::token_route(&csrf))).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/csrf-token").to_request()).await;
+        assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(r#""header":"x-csrf-token""#));
+        assert!(body.ends_with(r#""expires_in":3600}"#));
+
+        let token = body.split('"').nth(3).unwrap();
+        assert!(csrf.verify_token(token));
+    }
+
+    #[test]
+    fn test_either_body() {
+        use actix_web::body::{BoxBody, EitherBody};
+        use futures_util::future::Either;
+
+        use crate::Handler;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+
+        let req = TestRequest::post().to_srv_request();
+        match Handler::<EitherBody<String, BoxBody>>::process(&csrf, req) {
+            Either::Left(resp) => assert_eq!(resp.status(), 403),
+            Either::Right(_) => panic!("request without a token was forwarded"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_form_token() {
+        use actix_web::{http::header, test, web, App, HttpResponse};
+
+        use crate::Factory;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .token_sources(vec![super::TokenSource::Form("_csrf".to_string())])
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(csrf))
+                .route("/", web::post().to(|form: web::Form<std::collections::HashMap<String, String>>| async move {
+                    HttpResponse::Ok().body(form.0["comment"].clone())
+                })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .insert_header(header::ContentType::form_url_encoded())
+            .set_payload(format!("comment=hi&_csrf={}", token))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "hi");
+
+        let req = test::TestRequest::post()
+            .insert_header(header::ContentType::form_url_encoded())
+            .set_payload("comment=hi")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        let req = test::TestRequest::default()
+            .insert_header(("content-type", "multipart/form-data; boundary=b"))
+            .to_http_request();
+        let body = format!("--b\r\nContent-Disposition: form-data; name=\"_csrf\"\r\n\r\n{}\r\n--b--\r\n", token);
+        assert_eq!(super::form_field(&req, body.as_bytes(), "_csrf").as_deref(), Some(token.as_str()));
+        assert_eq!(super::form_field(&req, body.as_bytes(), "csrf"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_report_only() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use actix_web::{test, web, App, HttpResponse};
+
+        use crate::{EnforcementMode, Factory};
+
+        let reported = Arc::new(AtomicUsize::new(0));
+        let counter = reported.clone();
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .on_reject(move |_, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .enforcement(EnforcementMode::ReportOnly)
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(csrf))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::post().to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert!(!resp.headers().contains_key("x-csrf-token"));
+        assert_eq!(reported.load(Ordering::SeqCst), 1);
+
+        let req = test::TestRequest::post().insert_header(("x-csrf-token", token)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().contains_key("x-csrf-token"));
+        assert_eq!(reported.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_validator() {
+        use actix_web::{test, web, App, HttpRequest, HttpResponse};
+        use futures_core::future::LocalBoxFuture;
+
+        use crate::Factory;
+
+        struct Expect(String);
+
+        impl super::TokenValidator for Expect {
+            fn validate<'a>(
+                &'a self,
+                _: &'a HttpRequest,
+                token: &'a str,
+            ) -> LocalBoxFuture<'a, Result<(), super::CsrfRejectionReason>> {
+                Box::pin(async move {
+                    if token == self.0 {
+                        Ok(())
+                    } else {
+                        Err(super::CsrfRejectionReason::Mismatch)
+                    }
+                })
+            }
+        }
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+        let expected = csrf.generate_token();
+        let other = csrf.generate_token();
+        assert!(csrf.verify_token(&other));
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(csrf.with_validator(Expect(expected.clone()))))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::post().insert_header(("x-csrf-token", expected)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::post().insert_header(("x-csrf-token", other)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+    }
+
+    #[test]
+    fn test_inspect_token() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+
+        let info = csrf.inspect_token(&csrf.generate_token()).unwrap();
+        assert_eq!(info.version, super::TOKEN_VERSION);
+        assert_eq!(info.expires_at - info.issued_at, chrono::Duration::hours(1));
+        assert!(csrf.inspect_token("not a token").is_none());
+    }
+
+    #[test]
+    fn test_token_encoding() {
+        let key = super::CsrfKey::generate();
+        let short = super::CSRF::builder()
+            .secret(key.clone())
+            .truncate_mac(true)
+            .build()
+            .unwrap();
+        let long = super::CSRF::builder()
+            .secret(key)
+            .encoding(super::TokenEncoding::Hex)
+            .build()
+            .unwrap();
+
+        let token = short.generate_token();
+        assert!(token.len() < long.generate_token().len());
+        assert!(short.verify_token(&token));
+        assert!(long.verify_token(&token));
+        assert!(short.verify_token(&long.generate_token()));
+    }
+
+    #[actix_web::test]
+    async fn test_double_submit() {
+        use actix_web::cookie::Cookie;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .cookie(super::CsrfCookie::default())
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+
+        let resp = call(&csrf, TestRequest::get()).await;
+        let cookie = resp.response().cookies().find(|c| c.name() == "__Host-csrf").unwrap();
+        assert_eq!(Some(cookie.value()), header(&resp));
+        assert_ne!(cookie.http_only(), Some(true));
+
+        let post = || TestRequest::post().insert_header(("x-csrf-token", token.clone()));
+        assert_eq!(call(&csrf, post()).await.status(), 403);
+        let req = post().cookie(Cookie::new("__Host-csrf", csrf.generate_token()));
+        assert_eq!(call(&csrf, req).await.status(), 403);
+        let req = post().cookie(Cookie::new("__Host-csrf", token.clone()));
+        assert_eq!(call(&csrf, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_safe_methods() {
+        use actix_web::http::Method;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+
+        let resp = call(&csrf, TestRequest::get()).await;
+        assert_eq!(resp.status(), 200);
+        assert!(csrf.verify_token(header(&resp).unwrap()));
+        assert_eq!(call(&csrf, TestRequest::default().method(Method::OPTIONS)).await.status(), 200);
+        assert_eq!(call(&csrf, TestRequest::delete()).await.status(), 403);
+
+        let csrf = csrf.with_safe_methods(vec![Method::GET]);
+        assert_eq!(call(&csrf, TestRequest::get()).await.status(), 200);
+        assert_eq!(call(&csrf, TestRequest::default().method(Method::OPTIONS)).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_binding() {
+        use actix_web::http::header::HeaderName;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .binding(super::TokenBinding::Header(HeaderName::from_static("x-user")))
+            .build()
+            .unwrap();
+        let alice = TestRequest::default().insert_header(("x-user", "alice")).to_http_request();
+        let token = csrf.generate_token_for(&alice);
+
+        let post = || TestRequest::post().insert_header(("x-csrf-token", token.clone()));
+        assert_eq!(call(&csrf, post().insert_header(("x-user", "alice"))).await.status(), 200);
+        assert_eq!(call(&csrf, post().insert_header(("x-user", "bob"))).await.status(), 403);
+        assert_eq!(call(&csrf, post()).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_rejection_reason() {
+        use actix_web::{http::StatusCode, test, HttpResponse};
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+        let resp = call(&csrf, TestRequest::post()).await;
+        assert_eq!(resp.status(), 403);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("csrf_missing_token"));
+
+        let csrf = csrf.with_rejection_handler(|_, reason| {
+            HttpResponse::build(StatusCode::from_u16(419).unwrap()).body(reason.code())
+        });
+        let resp = call(&csrf, TestRequest::post()).await;
+        assert_eq!(resp.status(), 419);
+        assert_eq!(test::read_body(resp).await, "csrf_missing_token");
+        let resp = call(&csrf, TestRequest::post().insert_header(("x-csrf-token", "not a token"))).await;
+        assert_eq!(test::read_body(resp).await, "csrf_malformed_token");
+        let other = super::CSRF::builder().secret(super::CsrfKey::generate()).build().unwrap();
+        let resp = call(&csrf, TestRequest::post().insert_header(("x-csrf-token", other.generate_token()))).await;
+        assert_eq!(test::read_body(resp).await, "csrf_invalid_token");
+    }
+
+    #[actix_web::test]
+    async fn test_token_extractor() {
+        use actix_web::{test, web, App};
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .build()
+            .unwrap();
+        let route = web::get().to(|token: super::CsrfToken| async move { token.0 });
+        let app = test::init_service(App::new().wrap(crate::Factory::new(csrf.clone())).route("/", route)).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        let issued = header(&resp).unwrap().to_string();
+        assert_eq!(test::read_body(resp).await, issued);
+        assert!(csrf.verify_token(&issued));
+
+        let route = web::get().to(|token: super::CsrfToken| async move { token.0 });
+        let app = test::init_service(App::new().route("/", route)).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 500);
+    }
+
+    #[actix_web::test]
+    async fn test_allowed_origins() {
+        use actix_web::test;
+
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .allowed_origins(vec!["https://example.com/".to_string()])
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+
+        let post = || TestRequest::post().insert_header(("x-csrf-token", token.clone()));
+        assert_eq!(call(&csrf, post().insert_header(("origin", "https://EXAMPLE.com"))).await.status(), 200);
+        assert_eq!(call(&csrf, post().insert_header(("referer", "https://example.com/form"))).await.status(), 200);
+        let resp = call(&csrf, post().insert_header(("origin", "https://evil.example"))).await;
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("csrf_origin_mismatch"));
+        let resp = call(&csrf, post()).await;
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("csrf_missing_origin"));
+    }
+
+    #[actix_web::test]
+    async fn test_rotation() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .rotation(super::RotationPolicy::OnExpiryOnly)
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+        // tokens minted within the same millisecond are identical
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let get = || TestRequest::get().insert_header(("x-csrf-token", token.clone()));
+        let post = || TestRequest::post().insert_header(("x-csrf-token", token.clone()));
+
+        assert_eq!(header(&call(&csrf, get()).await), Some(token.as_str()));
+        assert_eq!(header(&call(&csrf, post()).await), Some(token.as_str()));
+        let resp = call(&csrf, TestRequest::get().insert_header(("x-csrf-token", "not a token"))).await;
+        assert!(csrf.verify_token(header(&resp).unwrap()));
+
+        let csrf = csrf.with_rotation(super::RotationPolicy::PerUnsafeRequest);
+        assert_eq!(header(&call(&csrf, get()).await), Some(token.as_str()));
+        assert_ne!(header(&call(&csrf, post()).await), Some(token.as_str()));
+
+        let csrf = csrf.with_rotation(super::RotationPolicy::PerRequest);
+        assert_ne!(header(&call(&csrf, get()).await), Some(token.as_str()));
+    }
+
+    #[actix_web::test]
+    async fn test_algorithm_id() {
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .encoding(super::TokenEncoding::Hex)
+            .build()
+            .unwrap();
+        let mut raw = hex::decode(csrf.generate_token()).unwrap();
+        assert_eq!(raw[0], super::TOKEN_VERSION);
+        assert_eq!(raw[2], super::HashAlgorithm::HmacSha256.id());
+
+        // an unknown algorithm id makes the token unreadable rather than unauthentic
+        raw[2] = 0x7f;
+        let tampered = hex::encode(raw);
+        assert!(csrf.inspect_token(&tampered).is_none());
+        let resp = call(&csrf, TestRequest::post().insert_header(("x-csrf-token", tampered))).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_skip_rule() {
+        use actix_web::http::Method;
+
+        use crate::SkipRule;
+
+        let rule = SkipRule::path("/hooks")
+            .method(Method::POST)
+            .when(|req| req.headers().contains_key("x-hub-signature"));
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .skip_rule(rule)
+            .build()
+            .unwrap();
+
+        let signed = |req: TestRequest| req.insert_header(("x-hub-signature", "sha256=00"));
+        assert_eq!(call(&csrf, signed(TestRequest::post().uri("/hooks/github"))).await.status(), 200);
+        assert_eq!(call(&csrf, TestRequest::post().uri("/hooks/github")).await.status(), 403);
+        assert_eq!(call(&csrf, signed(TestRequest::put().uri("/hooks/github"))).await.status(), 403);
+        assert_eq!(call(&csrf, signed(TestRequest::post().uri("/hookshot"))).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_on_reject() {
+        use std::sync::{Arc, Mutex};
+
+        use super::CsrfRejectionReason;
+
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let seen = reasons.clone();
+        let csrf = super::CSRF::builder()
+            .secret(super::CsrfKey::generate())
+            .on_reject(move |req, reason| seen.lock().unwrap().push((req.path().to_string(), reason)))
+            .build()
+            .unwrap();
+        let token = csrf.generate_token();
+
+        assert_eq!(call(&csrf, TestRequest::post().uri("/a")).await.status(), 403);
+        let req = TestRequest::post().uri("/b").insert_header(("x-csrf-token", "not a token"));
+        assert_eq!(call(&csrf, req).await.status(), 403);
+        let req = TestRequest::post().uri("/c").insert_header(("x-csrf-token", token));
+        assert_eq!(call(&csrf, req).await.status(), 200);
+        assert_eq!(
+            *reasons.lock().unwrap(),
+            [
+                ("/a".to_string(), CsrfRejectionReason::MissingToken),
+                ("/b".to_string(), CsrfRejectionReason::MalformedToken),
+            ]
+        );
+
+        #[cfg(feature = "tracing")]
+        {
+            let csrf = csrf.with_on_reject(super::trace_rejection);
+            assert_eq!(call(&csrf, TestRequest::post()).await.status(), 403);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::{header::HeaderName, Method},
+    HttpResponse,
+};
+
+use crate::{EnforcementMode, MwError, SkipRule};
+
+use super::{
+    CsrfCookie, CsrfKey, CsrfRejectionReason, HashAlgorithm, Keyring, Opaque, ReplayStore, RotationPolicy,
+    SecretResolver, TokenBinding, TokenEncoding, TokenSource, TokenValidator, CSRF,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsrfConfigError {
+    InvalidHeaderName(String),
+    MissingSecret,
+    DuplicateKeyId(u8),
+    NonPositiveTtl,
+}
+
+impl fmt::Display for CsrfConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrfConfigError::InvalidHeaderName(name) => write!(f, "invalid CSRF header name: {name:?}"),
+            CsrfConfigError::MissingSecret => f.write_str("CSRF secret is not configured"),
+            CsrfConfigError::DuplicateKeyId(id) => write!(f, "CSRF key id {id} is used more than once"),
+            CsrfConfigError::NonPositiveTtl => f.write_str("CSRF token ttl must be positive"),
+        }
+    }
+}
+
+impl std::error::Error for CsrfConfigError {}
+
+impl From<CsrfConfigError> for MwError {
+    fn from(err: CsrfConfigError) -> Self {
+        MwError::Config(err.to_string())
+    }
+}
+
+pub struct CsrfBuilder {
+    header_name: String,
+    skip_rules: Vec<SkipRule>,
+    key: Option<CsrfKey>,
+    previous_keys: Vec<CsrfKey>,
+    ttl: chrono::Duration,
+    version_grace: Option<chrono::Duration>,
+    safe_methods: Option<Vec<Method>>,
+    sources: Option<Vec<TokenSource>>,
+    cookie: Option<CsrfCookie>,
+    binding: Option<TokenBinding>,
+    rejection_handler: Option<Opaque<super::RejectionHandlerFn>>,
+    on_reject: Option<Opaque<super::OnRejectFn>>,
+    replay: Option<Opaque<dyn ReplayStore>>,
+    resolver: Option<Opaque<dyn SecretResolver>>,
+    validator: Option<Opaque<dyn TokenValidator>>,
+    allowed_origins: Option<Vec<String>>,
+    masked: bool,
+    rotation: RotationPolicy,
+    algorithm: HashAlgorithm,
+    encoding: TokenEncoding,
+    truncate_mac: bool,
+    enforcement: EnforcementMode,
+    form_limit: usize,
+}
+
+impl Default for CsrfBuilder {
+    fn default() -> Self {
+        CsrfBuilder {
+            header_name: "x-csrf-token".to_string(),
+            skip_rules: vec![],
+            key: None,
+            previous_keys: vec![],
+            ttl: chrono::Duration::hours(1),
+            version_grace: None,
+            safe_methods: None,
+            sources: None,
+            cookie: None,
+            binding: None,
+            rejection_handler: None,
+            on_reject: None,
+            replay: None,
+            resolver: None,
+            validator: None,
+            allowed_origins: None,
+            masked: false,
+            rotation: RotationPolicy::default(),
+            algorithm: HashAlgorithm::default(),
+            encoding: TokenEncoding::default(),
+            truncate_mac: false,
+            enforcement: EnforcementMode::Enforce,
+            form_limit: super::DEFAULT_FORM_LIMIT,
+        }
+    }
+}
+
+impl CsrfBuilder {
+    /// Header the token is issued in and, unless `token_sources` is set, read from.
+    pub fn header_name(mut self, name: &str) -> Self {
+        self.header_name = name.to_string();
+        self
+    }
+
+    /// Adds a path prefix that bypasses the middleware entirely.
+    pub fn skip(mut self, url: impl Into<String>) -> Self {
+        self.skip_rules.push(SkipRule::path(url));
+        self
+    }
+
+    /// Adds a rule combining path, methods and request predicates.
+    pub fn skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+
+    /// The key new tokens are signed with.
+    pub fn secret(mut self, key: CsrfKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Picks the key per request, e.g. by tenant host. `secret` is still required and signs
+    /// requests the resolver has no key for.
+    pub fn secret_resolver<R: SecretResolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Opaque(Arc::new(resolver)));
+        self
+    }
+
+    /// A retired key whose tokens are still accepted until they expire.
+    pub fn previous_secret(mut self, key: CsrfKey) -> Self {
+        self.previous_keys.push(key);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long tokens of older layouts stay valid after `build`. Defaults to `ttl`,
+    /// long enough for every token issued before a deploy to expire.
+    pub fn version_grace(mut self, grace: chrono::Duration) -> Self {
+        self.version_grace = Some(grace);
+        self
+    }
+
+    pub fn safe_methods(mut self, methods: Vec<Method>) -> Self {
+        self.safe_methods = Some(methods);
+        self
+    }
+
+    pub fn token_sources(mut self, sources: Vec<TokenSource>) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// See `CSRF::with_form_limit`.
+    pub fn form_limit(mut self, limit: usize) -> Self {
+        self.form_limit = limit;
+        self
+    }
+
+    pub fn cookie(mut self, cookie: CsrfCookie) -> Self {
+        self.cookie = Some(cookie);
+        self
+    }
+
+    pub fn binding(mut self, binding: TokenBinding) -> Self {
+        self.binding = Some(binding);
+        self
+    }
+
+    pub fn rejection_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&ServiceRequest, CsrfRejectionReason) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.rejection_handler = Some(Opaque(Arc::new(handler)));
+        self
+    }
+
+    pub fn on_reject<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ServiceRequest, CsrfRejectionReason) + Send + Sync + 'static,
+    {
+        self.on_reject = Some(Opaque(Arc::new(hook)));
+        self
+    }
+
+    /// Issues single-use tokens checked against `store`.
+    pub fn one_time_tokens<S: ReplayStore + 'static>(mut self, store: S) -> Self {
+        self.replay = Some(Opaque(Arc::new(store)));
+        self
+    }
+
+    /// See `CSRF::with_validator`.
+    pub fn validator<V: TokenValidator + 'static>(mut self, validator: V) -> Self {
+        self.validator = Some(Opaque(Arc::new(validator)));
+        self
+    }
+
+    /// Requires `Origin`/`Referer` to match one of `origins` on unsafe requests.
+    pub fn allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = Some(super::normalize_origins(origins));
+        self
+    }
+
+    /// Masks emitted tokens with a per-response random pad.
+    pub fn masked_tokens(mut self, masked: bool) -> Self {
+        self.masked = masked;
+        self
+    }
+
+    pub fn rotation(mut self, rotation: RotationPolicy) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Defaults to base64url; use `TokenEncoding::Hex` to match tokens from `CSRF::new`.
+    pub fn encoding(mut self, encoding: TokenEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Cuts the MAC of new tokens to 16 bytes.
+    pub fn truncate_mac(mut self, truncate: bool) -> Self {
+        self.truncate_mac = truncate;
+        self
+    }
+
+    /// See `CSRF::with_enforcement`.
+    pub fn enforcement(mut self, mode: EnforcementMode) -> Self {
+        self.enforcement = mode;
+        self
+    }
+
+    pub fn build(self) -> Result<CSRF, CsrfConfigError> {
+        let header_name = HeaderName::from_str(&self.header_name)
+            .map_err(|_| CsrfConfigError::InvalidHeaderName(self.header_name.clone()))?;
+        let key = self.key.ok_or(CsrfConfigError::MissingSecret)?;
+        let mut keys = Keyring::new(key);
+        for key in self.previous_keys {
+            let id = key.id();
+            if !keys.add_previous(key) {
+                return Err(CsrfConfigError::DuplicateKeyId(id));
+            }
+        }
+        if self.ttl <= chrono::Duration::zero() {
+            return Err(CsrfConfigError::NonPositiveTtl);
+        }
+
+        let sources = self
+            .sources
+            .unwrap_or_else(|| vec![TokenSource::Header(header_name.clone())]);
+        let safe_methods = self.safe_methods.unwrap_or_else(super::default_safe_methods);
+
+        Ok(CSRF {
+            skip_rules: self.skip_rules.into_iter().collect(),
+            keys,
+            salt: String::new(),
+            legacy_tokens: false,
+            old_versions_until: Some(chrono::Utc::now() + self.version_grace.unwrap_or(self.ttl)),
+            effective: self.ttl,
+            header_name,
+            cookie: self.cookie,
+            safe_methods,
+            sources,
+            binding: self.binding,
+            rejection_handler: self.rejection_handler,
+            on_reject: self.on_reject,
+            replay: self.replay,
+            resolver: self.resolver,
+            validator: self.validator,
+            allowed_origins: self.allowed_origins,
+            masked: self.masked,
+            rotation: self.rotation,
+            algorithm: self.algorithm,
+            encoding: self.encoding,
+            truncate_mac: self.truncate_mac,
+            enforcement: self.enforcement,
+            form_limit: self.form_limit,
+        })
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashMap, fmt};
+
+use actix_web::{http::header, HttpRequest};
+
+use hmac::{digest::KeyInit, Hmac, Mac};
+use rand::RngCore;
+#[cfg(feature = "csrf-blake3")]
+use subtle::ConstantTimeEq;
+
+use crate::{Secret, SecretError, SecretSource};
+
+/// MAC used to sign tokens. The algorithm id is stored in every token, so instances
+/// configured with different algorithms accept each other's tokens during a migration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    HmacSha256,
+    #[cfg(feature = "csrf-sha512")]
+    HmacSha512,
+    /// BLAKE3 in keyed mode, with the key derived from the configured secret.
+    #[cfg(feature = "csrf-blake3")]
+    Blake3,
+}
+
+/// Longest MAC produced by any algorithm.
+pub(crate) const MAX_MAC_LEN: usize = 64;
+
+impl HashAlgorithm {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            HashAlgorithm::HmacSha256 => 1,
+            #[cfg(feature = "csrf-sha512")]
+            HashAlgorithm::HmacSha512 => 2,
+            #[cfg(feature = "csrf-blake3")]
+            HashAlgorithm::Blake3 => 3,
+        }
+    }
+
+    /// `None` for unknown ids and for algorithms not compiled in.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(HashAlgorithm::HmacSha256),
+            #[cfg(feature = "csrf-sha512")]
+            2 => Some(HashAlgorithm::HmacSha512),
+            #[cfg(feature = "csrf-blake3")]
+            3 => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn mac_len(self) -> usize {
+        match self {
+            HashAlgorithm::HmacSha256 => 32,
+            #[cfg(feature = "csrf-sha512")]
+            HashAlgorithm::HmacSha512 => 64,
+            #[cfg(feature = "csrf-blake3")]
+            HashAlgorithm::Blake3 => 32,
+        }
+    }
+}
+
+fn hmac<M: Mac + KeyInit>(key: &[u8], parts: &[&[u8]]) -> M {
+    let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+#[cfg(feature = "csrf-blake3")]
+fn blake3(key: &[u8], parts: &[&[u8]]) -> blake3::Hash {
+    let key = blake3::derive_key("actix-mw csrf token key", key);
+    let mut hasher = blake3::Hasher::new_keyed(&key);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Secret used to sign CSRF tokens.
+///
+/// Every token records the id of the key that signed it. When rotating, give the new key
+/// an id different from the keys it replaces.
+#[derive(Clone)]
+pub struct CsrfKey {
+    id: u8,
+    bytes: Secret,
+}
+
+impl CsrfKey {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        CsrfKey {
+            id: 0,
+            bytes: Secret::new(bytes.to_vec()),
+        }
+    }
+
+    /// Loads the key from the environment, a file or a callback, e.g.
+    /// `SecretSource::Env("CSRF_SECRET".into())`.
+    pub fn from_source(source: &SecretSource) -> Result<Self, SecretError> {
+        Ok(CsrfKey {
+            id: 0,
+            bytes: source.resolve()?,
+        })
+    }
+
+    /// A new random 32-byte key. Tokens signed with it do not survive a restart.
+    pub fn generate() -> Self {
+        let mut bytes = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        CsrfKey {
+            id: 0,
+            bytes: Secret::new(bytes),
+        }
+    }
+
+    pub fn with_id(mut self, id: u8) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub(crate) fn sign(&self, alg: HashAlgorithm, parts: &[&[u8]]) -> Vec<u8> {
+        match alg {
+            HashAlgorithm::HmacSha256 => hmac::<Hmac<sha2::Sha256>>(self.bytes.expose(), parts)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            #[cfg(feature = "csrf-sha512")]
+            HashAlgorithm::HmacSha512 => hmac::<Hmac<sha2::Sha512>>(self.bytes.expose(), parts)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            #[cfg(feature = "csrf-blake3")]
+            HashAlgorithm::Blake3 => blake3(self.bytes.expose(), parts).as_bytes().to_vec(),
+        }
+    }
+
+    /// `tag` may be a left-truncated MAC.
+    pub(crate) fn verify(&self, alg: HashAlgorithm, parts: &[&[u8]], tag: &[u8]) -> bool {
+        match alg {
+            HashAlgorithm::HmacSha256 => hmac::<Hmac<sha2::Sha256>>(self.bytes.expose(), parts)
+                .verify_truncated_left(tag)
+                .is_ok(),
+            #[cfg(feature = "csrf-sha512")]
+            HashAlgorithm::HmacSha512 => hmac::<Hmac<sha2::Sha512>>(self.bytes.expose(), parts)
+                .verify_truncated_left(tag)
+                .is_ok(),
+            #[cfg(feature = "csrf-blake3")]
+            HashAlgorithm::Blake3 => {
+                let hash = blake3(self.bytes.expose(), parts);
+                let hash = hash.as_bytes();
+                !tag.is_empty() && tag.len() <= hash.len() && hash[..tag.len()].ct_eq(tag).into()
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CsrfKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CsrfKey({}, ..)", self.id)
+    }
+}
+
+/// The primary signing key plus previous keys that are still accepted.
+#[derive(Clone, Debug)]
+pub(crate) struct Keyring {
+    primary: CsrfKey,
+    previous: HashMap<u8, CsrfKey>,
+}
+
+impl Keyring {
+    pub(crate) fn new(primary: CsrfKey) -> Self {
+        Keyring {
+            primary,
+            previous: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn primary(&self) -> &CsrfKey {
+        &self.primary
+    }
+
+    pub(crate) fn set_primary(&mut self, key: CsrfKey) {
+        self.previous.remove(&key.id);
+        self.primary = key;
+    }
+
+    /// Returns false when `key` reuses the id of a configured key.
+    pub(crate) fn add_previous(&mut self, key: CsrfKey) -> bool {
+        if key.id == self.primary.id || self.previous.contains_key(&key.id) {
+            return false;
+        }
+        self.previous.insert(key.id, key);
+        true
+    }
+
+    pub(crate) fn get(&self, id: u8) -> Option<&CsrfKey> {
+        if id == self.primary.id {
+            return Some(&self.primary);
+        }
+        self.previous.get(&id)
+    }
+}
+
+/// Chooses the key that signs and verifies tokens for a request, so tokens minted for
+/// one tenant are rejected by every other tenant.
+pub trait SecretResolver: Send + Sync {
+    /// `None` falls back to the keys configured on `CSRF`.
+    fn secret_for(&self, req: &HttpRequest) -> Option<&CsrfKey>;
+}
+
+/// The same key for every request.
+impl SecretResolver for CsrfKey {
+    fn secret_for(&self, _: &HttpRequest) -> Option<&CsrfKey> {
+        Some(self)
+    }
+}
+
+/// One key per virtual host, looked up by the request authority or `Host` header,
+/// port included.
+#[derive(Clone, Debug, Default)]
+pub struct HostSecrets {
+    keys: HashMap<String, CsrfKey>,
+}
+
+impl HostSecrets {
+    pub fn new() -> Self {
+        HostSecrets::default()
+    }
+
+    pub fn host(mut self, host: &str, key: CsrfKey) -> Self {
+        self.keys.insert(host.to_ascii_lowercase(), key);
+        self
+    }
+}
+
+impl SecretResolver for HostSecrets {
+    fn secret_for(&self, req: &HttpRequest) -> Option<&CsrfKey> {
+        let host = match req.uri().authority() {
+            Some(authority) => authority.as_str(),
+            None => req.headers().get(header::HOST)?.to_str().ok()?,
+        };
+        self.keys.get(&host.to_ascii_lowercase())
+    }
+}
@@ -0,0 +1,45 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Remembers the nonces of one-time tokens that have already been used.
+pub trait ReplayStore: Send + Sync {
+    /// Marks `token_id` as used. Returns false if it had been used before.
+    fn consume(&self, token_id: &[u8]) -> bool;
+}
+
+/// Process-local `ReplayStore`. Entries are dropped after `ttl`, which should be at least
+/// the token lifetime so an expired token can never be replayed.
+pub struct MemoryReplayStore {
+    ttl: Duration,
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl MemoryReplayStore {
+    pub fn new(ttl: Duration) -> Self {
+        MemoryReplayStore {
+            ttl,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ReplayStore for MemoryReplayStore {
+    fn consume(&self, token_id: &[u8]) -> bool {
+        let now = Instant::now();
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        seen.retain(|_, expires_at| *expires_at > now);
+        if seen.contains_key(token_id) {
+            return false;
+        }
+
+        seen.insert(token_id.to_vec(), now + self.ttl);
+        true
+    }
+}
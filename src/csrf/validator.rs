@@ -0,0 +1,61 @@
+use actix_web::HttpRequest;
+use futures_core::future::LocalBoxFuture;
+
+use super::CsrfRejectionReason;
+
+/// Server-side check of a submitted token, for the synchronizer-token pattern where the
+/// expected token lives in session storage rather than in the token itself. Set with
+/// `CSRF::with_validator`; it is only asked about tokens that passed the signature check.
+pub trait TokenValidator: Send + Sync {
+    fn validate<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), CsrfRejectionReason>>;
+}
+
+/// Keeps the expected token in the `actix-session` session under `key`.
+#[cfg(feature = "csrf-session")]
+pub struct SessionValidator {
+    key: String,
+}
+
+#[cfg(feature = "csrf-session")]
+impl SessionValidator {
+    pub fn new(key: &str) -> Self {
+        SessionValidator { key: key.to_string() }
+    }
+
+    /// Stores `token` as the one the next unsafe request must present.
+    pub fn store(&self, session: &actix_session::Session, token: &str) -> Result<(), actix_web::Error> {
+        session.insert(&self.key, token)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "csrf-session")]
+impl TokenValidator for SessionValidator {
+    fn validate<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        token: &'a str,
+    ) -> LocalBoxFuture<'a, Result<(), CsrfRejectionReason>> {
+        use actix_session::SessionExt;
+        use subtle::ConstantTimeEq;
+
+        Box::pin(async move {
+            let expected = req
+                .get_session()
+                .get::<String>(&self.key)
+                .ok()
+                .flatten()
+                .ok_or(CsrfRejectionReason::MissingToken)?;
+
+            if expected.as_bytes().ct_eq(token.as_bytes()).into() {
+                Ok(())
+            } else {
+                Err(CsrfRejectionReason::Mismatch)
+            }
+        })
+    }
+}
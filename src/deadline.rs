@@ -0,0 +1,64 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::{dev::ServiceResponse, rt::time::Sleep, Error};
+use pin_project_lite::pin_project;
+
+use crate::{Handler, MwContext};
+
+pin_project! {
+    /// The inner service call, raced against the deadline from `Handler::timeout`.
+    pub struct Deadline<F, T, B> {
+        #[pin]
+        fut: F,
+        #[pin]
+        sleep: Option<Sleep>,
+        expired: Option<(Rc<T>, MwContext)>,
+        _phantom: PhantomData<B>,
+    }
+}
+
+impl<F, T, B> Deadline<F, T, B> {
+    pub(crate) fn new(fut: F, timeout: Option<(Duration, Rc<T>, MwContext)>) -> Self {
+        let (sleep, expired) = match timeout {
+            Some((timeout, inner, context)) => (Some(actix_web::rt::time::sleep(timeout)), Some((inner, context))),
+            None => (None, None),
+        };
+        Deadline {
+            fut,
+            sleep,
+            expired,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<F, T, B> Future for Deadline<F, T, B>
+where
+    F: Future<Output = Result<ServiceResponse<B>, Error>>,
+    T: Handler<B>,
+{
+    type Output = Result<ServiceResponse<B>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(res) = this.fut.poll(cx) {
+            return Poll::Ready(res);
+        }
+
+        match this.sleep.as_pin_mut().map(|sleep| sleep.poll(cx)) {
+            Some(Poll::Ready(())) => {
+                let (inner, context) = this.expired.take().expect("Deadline polled after completion");
+                context.set_timed_out();
+                Poll::Ready(Err(inner.on_timeout(&context)))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
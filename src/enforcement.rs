@@ -0,0 +1,84 @@
+use std::{
+    cell::RefCell,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    web::Bytes,
+    HttpMessage,
+};
+use futures_core::Stream;
+
+/// Whether a rejection is answered or only reported, e.g. to roll out CSRF checks or an
+/// IP filter on an existing API and watch what they would block first.
+///
+/// On a `Factory`, `ReportOnly` forwards requests that `process` answered, whichever
+/// handler it is; `RateLimit` and `CSRF` also take a mode of their own, for use inside a
+/// `Chain` where only some handlers should be lenient.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnforcementMode {
+    #[default]
+    Enforce,
+    /// Log and count rejections, then forward the request as if it had been accepted.
+    ReportOnly,
+}
+
+impl EnforcementMode {
+    pub fn is_enforced(self) -> bool {
+        self == EnforcementMode::Enforce
+    }
+}
+
+/// Keeps the request payload reachable after `process` consumed the request, so that a
+/// report-only rejection can still be forwarded with its body. The request reads the
+/// payload through a shared handle, which also works when `process` wraps it.
+pub(crate) struct Retained(Rc<RefCell<Payload>>);
+
+impl Retained {
+    pub(crate) fn wrap(req: &mut ServiceRequest) -> Self {
+        let payload = Rc::new(RefCell::new(req.take_payload()));
+        req.set_payload(Payload::Stream {
+            payload: Box::pin(Shared(payload.clone())),
+        });
+        Retained(payload)
+    }
+
+    /// The request behind the rejection `res`, with its original payload.
+    pub(crate) fn restore<B>(self, res: ServiceResponse<B>) -> ServiceRequest {
+        let (req, _) = res.into_parts();
+        ServiceRequest::from_parts(req, self.0.replace(Payload::None))
+    }
+}
+
+struct Shared(Rc<RefCell<Payload>>);
+
+impl Stream for Shared {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut *self.0.borrow_mut()).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test::TestRequest, web::Bytes, HttpMessage, HttpResponse};
+    use futures_util::StreamExt;
+
+    use super::Retained;
+
+    #[actix_web::test]
+    async fn test_restore_payload() {
+        let mut req = TestRequest::post().set_payload("report me").to_srv_request();
+        let retained = Retained::wrap(&mut req);
+        let res = req.into_response(HttpResponse::Forbidden().finish());
+
+        let mut req = retained.restore(res);
+        let chunk = req.take_payload().next().await.unwrap().unwrap();
+        assert_eq!(chunk, Bytes::from_static(b"report me"));
+    }
+}
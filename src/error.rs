@@ -0,0 +1,167 @@
+use std::fmt;
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::ContentType, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+
+use crate::{json, FromBoxBody, SecretError, StoreError};
+
+/// A failure reported by one of the middlewares. As a `ResponseError` it renders as
+/// `{"error":{"code":"...","message":"..."}}`, where `code` is stable and meant for
+/// clients to match on. Server-side failures (configuration, secrets, stores) are logged
+/// with their details but answered with a generic message.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MwError {
+    /// A middleware is misconfigured or missing, e.g. an extractor without its middleware.
+    Config(String),
+    Secret(SecretError),
+    Store(StoreError),
+    /// Credentials, a token or a signature are missing or invalid; the code tells which.
+    Unauthorized(&'static str),
+    /// The request is understood but not allowed, e.g. a failed CSRF check.
+    Forbidden(&'static str),
+    /// A rejection whose status is configured, e.g. `IpFilter::with_status`.
+    Rejected(StatusCode, &'static str),
+    Conflict(&'static str),
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RateLimited,
+    /// e.g. an open circuit or exhausted concurrency limit.
+    Unavailable(&'static str),
+    Timeout,
+}
+
+impl MwError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MwError::Config(_) => "config_error",
+            MwError::Secret(_) => "secret_error",
+            MwError::Store(_) => "store_error",
+            MwError::Unauthorized(code)
+            | MwError::Forbidden(code)
+            | MwError::Rejected(_, code)
+            | MwError::Conflict(code)
+            | MwError::Unavailable(code) => code,
+            MwError::PreconditionFailed => "precondition_failed",
+            MwError::PayloadTooLarge => "payload_too_large",
+            MwError::UnsupportedMediaType => "unsupported_media_type",
+            MwError::RateLimited => "rate_limited",
+            MwError::Timeout => "timeout",
+        }
+    }
+
+    /// For extractors used without their middleware.
+    #[allow(dead_code)] // only with the features of those middlewares
+    pub(crate) fn not_installed(middleware: &str) -> Self {
+        MwError::Config(format!("{} middleware is not installed", middleware))
+    }
+
+    fn is_internal(&self) -> bool {
+        matches!(self, MwError::Config(_) | MwError::Secret(_) | MwError::Store(_))
+    }
+
+    /// The error response in place of the inner service, for `Handler::process`.
+    pub fn reject<B: FromBoxBody>(self, req: ServiceRequest) -> ServiceResponse<B> {
+        req.into_response(self.error_response())
+            .map_body(|_, body| B::from_box_body(body))
+    }
+
+    /// The same for a `Handler::verify` verdict, which only keeps the `HttpRequest`.
+    pub fn respond<B: FromBoxBody>(self, req: HttpRequest) -> ServiceResponse<B> {
+        ServiceResponse::new(req, self.error_response()).map_body(|_, body| B::from_box_body(body))
+    }
+}
+
+impl fmt::Display for MwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MwError::Config(message) => write!(f, "middleware misconfigured: {}", message),
+            MwError::Secret(err) => err.fmt(f),
+            MwError::Store(err) => err.fmt(f),
+            MwError::Unauthorized(_) => f.write_str("authentication failed"),
+            MwError::Forbidden(_) => f.write_str("request not allowed"),
+            MwError::Rejected(status, _) => f.write_str(status.canonical_reason().unwrap_or("request rejected")),
+            MwError::Conflict(_) => f.write_str("request conflicts with another request"),
+            MwError::PreconditionFailed => f.write_str("precondition failed"),
+            MwError::PayloadTooLarge => f.write_str("request body too large"),
+            MwError::UnsupportedMediaType => f.write_str("unsupported content type"),
+            MwError::RateLimited => f.write_str("too many requests"),
+            MwError::Unavailable(_) => f.write_str("service unavailable"),
+            MwError::Timeout => f.write_str("request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for MwError {}
+
+impl ResponseError for MwError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MwError::Config(_) | MwError::Secret(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MwError::Store(_) | MwError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            MwError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            MwError::Forbidden(_) => StatusCode::FORBIDDEN,
+            MwError::Rejected(status, _) => *status,
+            MwError::Conflict(_) => StatusCode::CONFLICT,
+            MwError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            MwError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            MwError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MwError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            MwError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = if self.is_internal() {
+            log::error!("{}", self);
+            "internal error".to_string()
+        } else {
+            self.to_string()
+        };
+
+        let mut body = String::with_capacity(64);
+        body.push_str(r#"{"error":{"code":"#);
+        json::push_str(&mut body, self.code());
+        body.push_str(r#","message":"#);
+        json::push_str(&mut body, &message);
+        body.push_str("}}");
+        HttpResponse::build(self.status_code())
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+impl From<SecretError> for MwError {
+    fn from(err: SecretError) -> Self {
+        MwError::Secret(err)
+    }
+}
+
+impl From<StoreError> for MwError {
+    fn from(err: StoreError) -> Self {
+        MwError::Store(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body, http::StatusCode, ResponseError};
+
+    use super::MwError;
+
+    #[actix_web::test]
+    async fn test_error_response() {
+        let resp = MwError::Rejected(StatusCode::NOT_FOUND, "ip_denied").error_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"error":{"code":"ip_denied","message":"Not Found"}}"#);
+
+        let resp = MwError::Config("secret key path".to_string()).error_response();
+        let body = body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"error":{"code":"config_error","message":"internal error"}}"#);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::{
+    body::BoxBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderValue},
+        Method, StatusCode,
+    },
+};
+use futures_util::future::Either;
+use sha2::{Digest, Sha256};
+
+use crate::{body::buffered, CallInfo, FromBoxBody, Handler, MwError};
+
+/// Paths remembered for `If-Match`; the map is cleared when it grows past this.
+const MAX_REMEMBERED: usize = 10_000;
+
+/// `W/"x"` and `"x"` compare equal under weak comparison.
+fn opaque(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+/// Whether the `If-None-Match` / `If-Match` list in `header` matches `etag`.
+fn list_matches(header: &str, etag: &str, strong: bool) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        if strong {
+            !tag.starts_with("W/") && !etag.starts_with("W/") && tag == etag
+        } else {
+            opaque(tag) == opaque(etag)
+        }
+    })
+}
+
+/// Adds an `ETag` to `200` responses of `GET` and `HEAD` requests whose bodies are
+/// available at once and below the size cap, and answers a matching `If-None-Match` with
+/// `304 Not Modified`. Responses that already carry an `ETag` keep it and are checked
+/// against it.
+///
+/// `If-Match` on unsafe methods is checked before the route runs, against the last ETag
+/// served for the path by this handler (within this process): a mismatch gets
+/// `412 Precondition Failed`. Paths without a remembered ETag are let through, and a
+/// successful unsafe request forgets the path's ETag.
+#[derive(Clone, Debug)]
+pub struct ETag {
+    weak: bool,
+    max_body: usize,
+    served: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ETag {
+    pub fn strong() -> Self {
+        ETag {
+            weak: false,
+            max_body: 1024 * 1024,
+            served: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// `W/"..."` tags, for responses that are equivalent but not byte-identical, e.g.
+    /// compressed differently.
+    pub fn weak() -> Self {
+        ETag {
+            weak: true,
+            ..ETag::strong()
+        }
+    }
+
+    /// Larger bodies get no `ETag`; defaults to 1 MiB.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    fn compute(&self, body: &[u8]) -> String {
+        let digest = Sha256::digest(body);
+        let tag = hex::encode(&digest[..16]);
+        if self.weak {
+            format!(r#"W/"{}""#, tag)
+        } else {
+            format!(r#""{}""#, tag)
+        }
+    }
+
+    fn remember(&self, path: &str, etag: &str) {
+        let mut served = self.served.lock().unwrap();
+        if served.len() >= MAX_REMEMBERED && !served.contains_key(path) {
+            served.clear();
+        }
+        served.insert(path.to_string(), etag.to_string());
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for ETag {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if req.method().is_safe() {
+            return Either::Right(req);
+        }
+
+        let if_match = match req.headers().get(header::IF_MATCH).and_then(|v| v.to_str().ok()) {
+            Some(if_match) => if_match,
+            None => return Either::Right(req),
+        };
+        let current = self.served.lock().unwrap().get(req.path()).cloned();
+        match current {
+            Some(etag) if !list_matches(if_match, &etag, true) => {
+                Either::Left(MwError::PreconditionFailed.reject(req))
+            }
+            _ => Either::Right(req),
+        }
+    }
+
+    fn post(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let req = resp.request();
+        if !req.method().is_safe() {
+            if resp.status().is_success() {
+                self.served.lock().unwrap().remove(req.path());
+            }
+            return resp;
+        }
+        if (req.method() != Method::GET && req.method() != Method::HEAD) || resp.status() != StatusCode::OK {
+            return resp;
+        }
+
+        let existing = resp
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let (etag, mut resp) = match existing {
+            Some(etag) => (etag, resp),
+            None => {
+                let mut computed = None;
+                let mut resp = resp.map_body(|_, body| {
+                    let (bytes, body) = buffered(body, self.max_body);
+                    computed = bytes.map(|bytes| self.compute(&bytes));
+                    body
+                });
+                match computed {
+                    Some(etag) => {
+                        if let Ok(value) = HeaderValue::from_str(&etag) {
+                            resp.headers_mut().insert(header::ETAG, value);
+                        }
+                        (etag, resp)
+                    }
+                    None => return resp,
+                }
+            }
+        };
+        self.remember(resp.request().path(), &etag);
+
+        let not_modified = resp
+            .request()
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |inm| list_matches(inm, &etag, false));
+        if not_modified {
+            *resp.response_mut().status_mut() = StatusCode::NOT_MODIFIED;
+            let headers = resp.headers_mut();
+            headers.remove(header::CONTENT_TYPE);
+            headers.remove(header::CONTENT_LENGTH);
+            resp = resp.map_body(|_, _| B::from_box_body(BoxBody::new(())));
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::list_matches;
+
+    #[test]
+    fn test_list_matches() {
+        assert!(list_matches(r#""a", W/"b""#, r#""b""#, false));
+        assert!(!list_matches(r#""a", W/"b""#, r#""b""#, true));
+        assert!(list_matches(r#""b""#, r#""b""#, true));
+        assert!(list_matches("*", r#"W/"b""#, true));
+    }
+}
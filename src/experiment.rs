@@ -0,0 +1,211 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+
+use crate::{cookie::CookieSpec, CallInfo, Handler, MwError};
+
+/// The cohort a request was assigned to, e.g. `control` or `canary`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cohort {
+    pub experiment: String,
+    pub name: String,
+}
+
+impl fmt::Display for Cohort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl FromRequest for Cohort {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Cohort>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("experiment").into()),
+        )
+    }
+}
+
+/// What a client is hashed by when it has no assignment cookie yet.
+#[derive(Clone, Debug)]
+pub enum CohortKey {
+    /// The address of the connected peer.
+    PeerIp,
+    /// The `ClientIp` cached by a `ClientIpResolver` or `IpFilter` earlier in the chain,
+    /// else the peer address.
+    ClientIp,
+    /// e.g. a session or device id cookie set elsewhere.
+    Cookie(String),
+    /// e.g. a user id header set by an authenticating proxy.
+    Header(HeaderName),
+}
+
+impl CohortKey {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            CohortKey::PeerIp => req.peer_addr().map(|addr| addr.ip().to_string()),
+            CohortKey::ClientIp => crate::client_addr(req.request()).map(|ip| ip.to_string()),
+            CohortKey::Cookie(name) => req.cookie(name).map(|c| c.value().to_string()),
+            CohortKey::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// FNV-1a, so assignments agree across processes and builds.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Set when the assignment cookie has to be (re)sent.
+#[derive(Clone, Copy)]
+struct Assigned;
+
+/// Splits clients into weighted cohorts for A/B tests and canaries. A client's cohort is
+/// taken from the assignment cookie when it names a configured cohort, and otherwise
+/// chosen by hashing the experiment name and the `CohortKey`, so the same key always
+/// lands in the same cohort while the weights are unchanged.
+///
+/// The cohort is available through the `Cohort` extractor, persisted in the cookie
+/// (`exp_{name}` by default) and echoed in a response header (`x-cohort` by default) that
+/// a CDN can vary on. Requests without a key go to the first cohort.
+#[derive(Clone, Debug)]
+pub struct Experiment {
+    name: String,
+    cohorts: Vec<(String, u32)>,
+    key: CohortKey,
+    cookie: CookieSpec,
+    header: HeaderName,
+}
+
+impl Experiment {
+    pub fn new(name: &str) -> Self {
+        Experiment {
+            name: name.to_string(),
+            cohorts: vec![],
+            key: CohortKey::PeerIp,
+            cookie: CookieSpec::new(format!("exp_{}", name)).max_age(Duration::from_secs(30 * 24 * 60 * 60)),
+            header: HeaderName::from_static("x-cohort"),
+        }
+    }
+
+    /// Cohorts get traffic in proportion to their weights; a weight of zero closes a
+    /// cohort to new clients but keeps clients already assigned to it.
+    pub fn cohort(mut self, name: &str, weight: u32) -> Self {
+        self.cohorts.push((name.to_string(), weight));
+        self
+    }
+
+    pub fn with_key(mut self, key: CohortKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Defaults to `exp_{name}`, kept for 30 days.
+    pub fn with_cookie(mut self, cookie: CookieSpec) -> Self {
+        self.cookie = cookie;
+        self
+    }
+
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    fn by_key(&self, key: &str) -> Option<&str> {
+        let total = self.cohorts.iter().map(|(_, w)| *w as u64).sum::<u64>();
+        if total == 0 {
+            return self.cohorts.first().map(|(name, _)| name.as_str());
+        }
+
+        let mut point = fnv1a(format!("{}:{}", self.name, key).as_bytes()) % total;
+        for (name, weight) in &self.cohorts {
+            if point < *weight as u64 {
+                return Some(name);
+            }
+            point -= *weight as u64;
+        }
+        None
+    }
+
+    fn assign(&self, req: &ServiceRequest) -> Option<(String, bool)> {
+        if let Some(cookie) = req.cookie(self.cookie.name()) {
+            if self.cohorts.iter().any(|(name, _)| name == cookie.value()) {
+                return Some((cookie.value().to_string(), false));
+            }
+        }
+
+        let cohort = match self.key.extract(req) {
+            Some(key) => self.by_key(&key),
+            None => self.cohorts.first().map(|(name, _)| name.as_str()),
+        };
+        cohort.map(|name| (name.to_string(), true))
+    }
+}
+
+impl<B> Handler<B> for Experiment {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if let Some((name, assigned)) = self.assign(&req) {
+            let mut extensions = req.extensions_mut();
+            extensions.insert(Cohort {
+                experiment: self.name.clone(),
+                name,
+            });
+            if assigned {
+                extensions.insert(Assigned);
+            }
+        }
+        Either::Right(req)
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let cohort = resp.request().extensions().get::<Cohort>().cloned();
+        let cohort = match cohort {
+            Some(cohort) => cohort,
+            None => return resp,
+        };
+        let assigned = resp.request().extensions().get::<Assigned>().is_some();
+
+        if let Ok(value) = HeaderValue::from_str(&cohort.name) {
+            resp.headers_mut().insert(self.header.clone(), value);
+        }
+        if assigned {
+            self.cookie.set(&mut resp, cohort.name);
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Experiment;
+
+    #[test]
+    fn test_by_key() {
+        let experiment = Experiment::new("checkout").cohort("control", 90).cohort("canary", 10);
+        let canary = (0..1000)
+            .filter(|i| experiment.by_key(&i.to_string()) == Some("canary"))
+            .count();
+        assert!((50..150).contains(&canary), "{} of 1000 in canary", canary);
+        assert_eq!(experiment.by_key("user-1"), experiment.by_key("user-1"));
+    }
+}
@@ -0,0 +1,218 @@
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, SkipRule};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    RequestId,
+    ElapsedMs,
+    Route,
+    Method,
+    Status,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Field(Placeholder),
+}
+
+/// A header value with placeholders, parsed once when the handler is built.
+#[derive(Clone, Debug)]
+struct Template(Vec<Segment>);
+
+impl Template {
+    /// Panics on an unknown or unterminated placeholder; `{{` is a literal `{`.
+    fn parse(value: &str) -> Self {
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut rest = value;
+        while let Some(i) = rest.find('{') {
+            text.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(after) = rest.strip_prefix('{') {
+                text.push('{');
+                rest = after;
+                continue;
+            }
+
+            let end = rest
+                .find('}')
+                .unwrap_or_else(|| panic!("unterminated placeholder in header value {:?}", value));
+            let field = match &rest[..end] {
+                "request_id" => Placeholder::RequestId,
+                "elapsed_ms" => Placeholder::ElapsedMs,
+                "route" => Placeholder::Route,
+                "method" => Placeholder::Method,
+                "status" => Placeholder::Status,
+                other => panic!("unknown placeholder {{{}}} in header value {:?}", other, value),
+            };
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Field(field));
+            rest = &rest[end + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Template(segments)
+    }
+
+    fn render(&self, resolve: impl Fn(Placeholder) -> String) -> String {
+        let mut value = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => value.push_str(text),
+                Segment::Field(field) => value.push_str(&resolve(*field)),
+            }
+        }
+        value
+    }
+}
+
+/// Response headers added by one `Headers` rule. Values may contain `{request_id}`,
+/// `{elapsed_ms}`, `{route}` (the matched route pattern), `{method}` and `{status}`.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderSet {
+    headers: Vec<(HeaderName, Template, bool)>,
+}
+
+impl HeaderSet {
+    pub fn new() -> Self {
+        HeaderSet::default()
+    }
+
+    /// Replaces any value the route set.
+    pub fn insert(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.push((name, Template::parse(value), true));
+        self
+    }
+
+    /// Adds a value next to those the route set.
+    pub fn append(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.push((name, Template::parse(value), false));
+        self
+    }
+}
+
+/// Indexes of the rules matching a request.
+#[derive(Clone)]
+struct Matched(Vec<usize>);
+
+/// Adds response headers by path, e.g. `X-Robots-Tag: noindex` on `/admin` or
+/// `Server-Timing: app;dur={elapsed_ms}` everywhere, without a handler of their own.
+/// Every matching rule applies, in the order added.
+///
+/// `{request_id}` is the `RequestId` set by `SetRequestId` when the `request-id` feature
+/// is enabled, else the incoming `X-Request-Id`; placeholders without a value render as
+/// an empty string.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    rules: Vec<(SkipRule, HeaderSet)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    pub fn rule(mut self, rule: impl Into<SkipRule>, headers: HeaderSet) -> Self {
+        self.rules.push((rule.into(), headers));
+        self
+    }
+}
+
+fn request_id<B>(resp: &ServiceResponse<B>) -> String {
+    #[cfg(feature = "request-id")]
+    {
+        if let Some(id) = resp.request().extensions().get::<crate::request_id::RequestId>() {
+            return id.0.clone();
+        }
+    }
+
+    resp.request()
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl<B> Handler<B> for Headers {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let matched = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, (rule, _))| rule.matches(&req))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !matched.is_empty() {
+            req.extensions_mut().insert(Matched(matched));
+        }
+        Either::Right(req)
+    }
+
+    fn post(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        let matched = resp.request().extensions_mut().remove::<Matched>();
+        let matched = match matched {
+            Some(Matched(matched)) => matched,
+            None => return resp,
+        };
+
+        let request_id = request_id(&resp);
+        let route = resp.request().match_pattern().unwrap_or_default();
+        let method = resp.request().method().to_string();
+        let status = resp.status().as_u16().to_string();
+        let resolve = |field: Placeholder| match field {
+            Placeholder::RequestId => request_id.clone(),
+            Placeholder::ElapsedMs => info.elapsed.as_millis().to_string(),
+            Placeholder::Route => route.clone(),
+            Placeholder::Method => method.clone(),
+            Placeholder::Status => status.clone(),
+        };
+
+        let mut rendered = vec![];
+        for i in matched {
+            for (name, template, replace) in &self.rules[i].1.headers {
+                match HeaderValue::from_str(&template.render(resolve)) {
+                    Ok(value) => rendered.push((name.clone(), value, *replace)),
+                    Err(_) => log::warn!("header {} rendered to an invalid value", name),
+                }
+            }
+        }
+
+        let headers = resp.headers_mut();
+        for (name, value, replace) in rendered {
+            if replace {
+                headers.insert(name, value);
+            } else {
+                headers.append(name, value);
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Placeholder, Template};
+
+    #[test]
+    fn test_template() {
+        let template = Template::parse("app;dur={elapsed_ms}, {{ {route}");
+        let value = template.render(|field| match field {
+            Placeholder::ElapsedMs => "12".to_string(),
+            Placeholder::Route => "/users/{id}".to_string(),
+            _ => String::new(),
+        });
+        assert_eq!(value, "app;dur=12, { /users/{id}");
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::ContentType, Method},
+    rt, HttpResponse,
+};
+use futures_core::future::BoxFuture;
+use futures_util::future::{join_all, Either};
+
+use crate::{json, FromBoxBody, Handler};
+
+/// A dependency readiness depends on, e.g. a database ping or a queue depth bound.
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// `Err` carries a short reason shown in the readiness report.
+    fn check(&self) -> BoxFuture<'_, Result<(), String>>;
+}
+
+#[derive(Clone, Debug, Default)]
+struct Report {
+    checked_at: Option<Instant>,
+    results: Vec<(String, Result<(), String>)>,
+}
+
+impl Report {
+    fn ready(&self) -> bool {
+        self.checked_at.is_some() && self.results.iter().all(|(_, r)| r.is_ok())
+    }
+
+    fn to_json(&self) -> String {
+        let status = match (self.checked_at, self.ready()) {
+            (None, _) => "pending",
+            (_, true) => "ok",
+            (_, false) => "fail",
+        };
+        let mut body = format!(r#"{{"status":"{}","checks":{{"#, status);
+        for (i, (name, result)) in self.results.iter().enumerate() {
+            if i > 0 {
+                body.push(',');
+            }
+            json::push_str(&mut body, name);
+            match result {
+                Ok(()) => body.push_str(r#":{"status":"ok"}"#),
+                Err(reason) => {
+                    body.push_str(r#":{"status":"fail","error":"#);
+                    json::push_str(&mut body, reason);
+                    body.push('}');
+                }
+            }
+        }
+        body.push_str("}}");
+        body
+    }
+}
+
+struct State {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    check_timeout: Duration,
+    report: Mutex<Report>,
+    refreshing: AtomicBool,
+}
+
+/// Answers liveness and readiness probes from `process`, without calling the inner
+/// service. Liveness is always `200`; readiness is `200` when every `HealthCheck` passed
+/// and `503` otherwise, with a JSON report of each check.
+///
+/// Readiness is served from the last completed run of the checks, so a probe never waits
+/// on a slow dependency. A probe arriving after `interval` starts a new run in the
+/// background; until the first run completes readiness reports `pending` with `503`. Call
+/// `refresh` at startup to have a report before the first probe.
+#[derive(Clone)]
+pub struct Health {
+    live_path: String,
+    ready_path: String,
+    interval: Duration,
+    state: Arc<State>,
+}
+
+impl Health {
+    /// Probes at `/healthz` and `/readyz`; checks are rerun at most every 5 seconds.
+    pub fn new() -> Self {
+        Health {
+            live_path: "/healthz".to_string(),
+            ready_path: "/readyz".to_string(),
+            interval: Duration::from_secs(5),
+            state: Arc::new(State {
+                checks: vec![],
+                check_timeout: Duration::from_secs(2),
+                report: Mutex::new(Report::default()),
+                refreshing: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn check<C: HealthCheck + 'static>(mut self, check: C) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("configure Health before cloning it")
+            .checks
+            .push(Arc::new(check));
+        self
+    }
+
+    /// A check still running after `timeout` fails; defaults to 2 seconds.
+    pub fn check_timeout(mut self, timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("configure Health before cloning it")
+            .check_timeout = timeout;
+        self
+    }
+
+    pub fn paths(mut self, live: &str, ready: &str) -> Self {
+        self.live_path = live.to_string();
+        self.ready_path = ready.to_string();
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Runs every check now and stores the report.
+    pub async fn refresh(&self) {
+        Health::run(self.state.clone()).await
+    }
+
+    async fn run(state: Arc<State>) {
+        let timeout = state.check_timeout;
+        let runs = state.checks.iter().map(|check| async move {
+            let result = match rt::time::timeout(timeout, check.check()).await {
+                Ok(result) => result,
+                Err(_) => Err("timed out".to_string()),
+            };
+            (check.name().to_string(), result)
+        });
+        let results = join_all(runs).await;
+
+        *state.report.lock().unwrap() = Report {
+            checked_at: Some(Instant::now()),
+            results,
+        };
+        state.refreshing.store(false, Ordering::Release);
+    }
+
+    fn readiness(&self) -> Report {
+        let report = self.state.report.lock().unwrap().clone();
+        let stale = report.checked_at.is_none_or(|at| at.elapsed() >= self.interval);
+        if stale && !self.state.refreshing.swap(true, Ordering::AcqRel) {
+            rt::spawn(Health::run(self.state.clone()));
+        }
+        report
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Health")
+            .field("live_path", &self.live_path)
+            .field("ready_path", &self.ready_path)
+            .field("interval", &self.interval)
+            .field("checks", &self.state.checks.iter().map(|c| c.name()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for Health {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        (req.method() != Method::GET && req.method() != Method::HEAD)
+            || (req.path() != self.live_path && req.path() != self.ready_path)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let resp = if req.path() == self.live_path {
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(r#"{"status":"ok"}"#)
+        } else {
+            let report = self.readiness();
+            let mut resp = if report.ready() {
+                HttpResponse::Ok()
+            } else {
+                HttpResponse::ServiceUnavailable()
+            };
+            resp.content_type(ContentType::json()).body(report.to_json())
+        };
+        Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use actix_web::{rt, test, web, App, HttpResponse};
+    use futures_core::future::BoxFuture;
+
+    use super::{Health, HealthCheck};
+    use crate::Factory;
+
+    struct Database(Arc<AtomicBool>);
+
+    impl HealthCheck for Database {
+        fn name(&self) -> &str {
+            "database"
+        }
+
+        fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+            let up = self.0.load(Ordering::Acquire);
+            Box::pin(async move { if up { Ok(()) } else { Err("down".to_string()) } })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_health() {
+        let up = Arc::new(AtomicBool::new(true));
+        let health = Health::new()
+            .check(Database(up.clone()))
+            .interval(Duration::from_secs(3600));
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(health.clone()))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let ready = || test::TestRequest::get().uri("/readyz").to_request();
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(test::read_body(resp).await, r#"{"status":"pending","checks":{}}"#);
+
+        // the probe started a run in the background
+        rt::time::sleep(Duration::from_millis(10)).await;
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(resp.status(), 200);
+
+        up.store(false, Ordering::Release);
+        health.refresh().await;
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(resp.status(), 503);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, r#"{"status":"fail","checks":{"database":{"status":"fail","error":"down"}}}"#);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{fmt, net::IpAddr, sync::Arc, time::Duration};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    rt,
+};
+use futures_util::future::Either;
+
+#[cfg(feature = "ipfilter")]
+use crate::ipfilter::Blocklist;
+use crate::{FromBoxBody, Handler, MwError, SkipRule, SkipSet, Store};
+
+/// Paths no legitimate client of this application asks for.
+const DEFAULT_TRAPS: &[&str] = &[
+    "/wp-login.php",
+    "/wp-admin",
+    "/xmlrpc.php",
+    "/.env",
+    "/.git",
+    "/phpmyadmin",
+    "/cgi-bin",
+];
+
+/// Treats requests for trap paths as hostile: they get `404 Not Found` (so the trap looks
+/// like any missing page) and the client address is recorded in the store as
+/// `{prefix}{ip}` for `ttl`, for other instances and offline analysis.
+///
+/// With the `ipfilter` feature, `feed` also adds the address to a `Blocklist` shared with
+/// an `IpFilter`, so its later requests are rejected by that filter. The address is the
+/// `ClientIp` cached by a `ClientIpResolver` or `IpFilter` earlier in the chain, else the
+/// peer address.
+///
+/// To tarpit scanners, delay the trap's responses with `Factory::reject_delay`.
+#[derive(Clone)]
+pub struct Honeypot {
+    traps: SkipSet,
+    store: Arc<dyn Store>,
+    prefix: String,
+    ttl: Duration,
+    status: StatusCode,
+    #[cfg(feature = "ipfilter")]
+    blocklist: Option<Blocklist>,
+}
+
+impl Honeypot {
+    /// Traps common scanner targets (`/wp-login.php`, `/.env`, `/.git`, ...); addresses
+    /// are kept for an hour.
+    pub fn new<S: Store + 'static>(store: S) -> Self {
+        Honeypot::with_store(Arc::new(store))
+    }
+
+    /// Shares `store` with other middlewares.
+    pub fn with_store(store: Arc<dyn Store>) -> Self {
+        Honeypot {
+            traps: DEFAULT_TRAPS.iter().map(|&path| SkipRule::from(path)).collect(),
+            store,
+            prefix: "honeypot:".to_string(),
+            ttl: Duration::from_secs(60 * 60),
+            status: StatusCode::NOT_FOUND,
+            #[cfg(feature = "ipfilter")]
+            blocklist: None,
+        }
+    }
+
+    pub fn trap(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.traps.push(rule.into());
+        self
+    }
+
+    /// Drops the default traps; call before `trap`.
+    pub fn without_defaults(mut self) -> Self {
+        self.traps.clear();
+        self
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// How long an address stays recorded (and blocked); defaults to an hour.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn reject_with(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    #[cfg(feature = "ipfilter")]
+    pub fn feed(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    fn client_ip(req: &ServiceRequest) -> Option<IpAddr> {
+        crate::client_addr(req.request())
+    }
+
+    fn record(&self, ip: IpAddr, path: &str) {
+        log::warn!("honeypot: {} requested {}", ip, path);
+        #[cfg(feature = "ipfilter")]
+        {
+            if let Some(blocklist) = &self.blocklist {
+                blocklist.block(ip, self.ttl);
+            }
+        }
+
+        let store = self.store.clone();
+        let key = format!("{}{}", self.prefix, ip);
+        let ttl = self.ttl;
+        let path = path.as_bytes().to_vec();
+        rt::spawn(async move {
+            if let Err(err) = store.set(&key, path, Some(ttl)).await {
+                log::warn!("failed to record honeypot hit: {}", err);
+            }
+        });
+    }
+}
+
+impl fmt::Debug for Honeypot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Honeypot")
+            .field("traps", &self.traps)
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for Honeypot {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        !self.traps.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if let Some(ip) = Honeypot::client_ip(&req) {
+            self.record(ip, req.path());
+        }
+        Either::Left(MwError::Rejected(self.status, "not_found").reject(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use actix_web::{rt, test, web, App, HttpResponse};
+
+    use super::Honeypot;
+    use crate::{Factory, InMemoryStore, Store};
+
+    #[actix_web::test]
+    async fn test_honeypot() {
+        let store: Arc<dyn Store> = Arc::new(InMemoryStore::new());
+        let honeypot = Honeypot::with_store(store.clone()).trap("/admin.php");
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(honeypot))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .peer_addr("198.51.100.7:5000".parse().unwrap())
+                .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, get("/")).await.status(), 200);
+        assert!(store.get("honeypot:198.51.100.7").await.unwrap().is_none());
+
+        assert_eq!(test::call_service(&app, get("/.env")).await.status(), 404);
+        assert_eq!(test::call_service(&app, get("/admin.php")).await.status(), 404);
+        // the trap is recorded by a spawned task
+        let mut recorded = None;
+        for _ in 0..100 {
+            recorded = store.get("honeypot:198.51.100.7").await.unwrap();
+            if recorded.is_some() {
+                break;
+            }
+            rt::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(recorded.as_deref(), Some(&b"/admin.php"[..]));
+    }
+}
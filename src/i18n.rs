@@ -0,0 +1,198 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, MwError};
+
+/// The locale chosen for the request, as configured in `I18n` (e.g. `en-US`).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Locale(pub String);
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for Locale {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Locale>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("i18n").into()),
+        )
+    }
+}
+
+/// Marks requests whose locale came from `Accept-Language`, so caches must vary on it.
+#[derive(Clone, Copy)]
+struct Negotiated;
+
+/// `Accept-Language` ranges by descending quality; ranges with `q=0` are dropped.
+fn parse_accept_language(value: &str) -> Vec<(String, f32)> {
+    let mut ranges = value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            if range.is_empty() {
+                return None;
+            }
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (q > 0.0).then(|| (range.to_ascii_lowercase(), q))
+        })
+        .collect::<Vec<_>>();
+    // stable, so equal qualities keep the client's order
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranges
+}
+
+/// Picks a locale for the request: a cookie or query override naming a supported locale
+/// first, then the best `Accept-Language` match, then the default. A range matches a
+/// locale exactly or by primary language (`en` matches `en-US`, and `en-GB` falls back to
+/// `en`). The locale is available through the `Locale` extractor, and responses get a
+/// `Content-Language` header unless the route set one.
+#[derive(Clone, Debug)]
+pub struct I18n {
+    supported: Vec<String>,
+    default: String,
+    cookie: Option<String>,
+    query: Option<String>,
+}
+
+impl I18n {
+    /// `default` is used when nothing matches; it need not be in `supported`.
+    pub fn new(supported: &[&str], default: &str) -> Self {
+        I18n {
+            supported: supported.iter().map(|s| s.to_string()).collect(),
+            default: default.to_string(),
+            cookie: None,
+            query: None,
+        }
+    }
+
+    /// A cookie holding the user's chosen locale, e.g. `lang`.
+    pub fn with_cookie(mut self, name: &str) -> Self {
+        self.cookie = Some(name.to_string());
+        self
+    }
+
+    /// A query parameter overriding everything else, e.g. `?lang=de`.
+    pub fn with_query(mut self, name: &str) -> Self {
+        self.query = Some(name.to_string());
+        self
+    }
+
+    fn supported(&self, tag: &str) -> Option<&str> {
+        self.supported
+            .iter()
+            .find(|s| s.eq_ignore_ascii_case(tag))
+            .map(String::as_str)
+    }
+
+    fn primary(tag: &str) -> &str {
+        tag.split('-').next().unwrap_or(tag)
+    }
+
+    fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        for (range, _) in parse_accept_language(accept_language) {
+            if range == "*" {
+                return self.supported.first().map(String::as_str);
+            }
+            if let Some(exact) = self.supported(&range) {
+                return Some(exact);
+            }
+            let primary = I18n::primary(&range);
+            let partial = self
+                .supported
+                .iter()
+                .find(|s| I18n::primary(s).eq_ignore_ascii_case(primary));
+            if let Some(partial) = partial {
+                return Some(partial);
+            }
+        }
+        None
+    }
+
+    fn override_locale(&self, req: &ServiceRequest) -> Option<&str> {
+        if let Some(param) = &self.query {
+            let query = web::Query::<Vec<(String, String)>>::from_query(req.query_string()).ok();
+            let value = query.and_then(|q| q.0.into_iter().find(|(k, _)| k == param).map(|(_, v)| v));
+            if let Some(locale) = value.as_deref().and_then(|v| self.supported(v)) {
+                return Some(locale);
+            }
+        }
+
+        let cookie = self.cookie.as_deref().and_then(|name| req.cookie(name))?;
+        self.supported(cookie.value())
+    }
+}
+
+impl<B> Handler<B> for I18n {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let locale = match self.override_locale(&req) {
+            Some(locale) => locale.to_string(),
+            None => {
+                let accept = req
+                    .headers()
+                    .get(header::ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok());
+                if accept.is_some() {
+                    req.extensions_mut().insert(Negotiated);
+                }
+                accept
+                    .and_then(|a| self.negotiate(a))
+                    .unwrap_or(&self.default)
+                    .to_string()
+            }
+        };
+        req.extensions_mut().insert(Locale(locale));
+        Either::Right(req)
+    }
+
+    fn post(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let locale = resp.request().extensions().get::<Locale>().cloned();
+        let negotiated = resp.request().extensions().get::<Negotiated>().is_some();
+        let headers = resp.headers_mut();
+        if let Some(Locale(locale)) = locale {
+            if !headers.contains_key(header::CONTENT_LANGUAGE) {
+                if let Ok(value) = HeaderValue::from_str(&locale) {
+                    headers.insert(header::CONTENT_LANGUAGE, value);
+                }
+            }
+        }
+        if negotiated {
+            headers.append(header::VARY, HeaderValue::from_static("Accept-Language"));
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::I18n;
+
+    #[test]
+    fn test_negotiate() {
+        let i18n = I18n::new(&["en-US", "de", "zh-TW"], "en-US");
+        assert_eq!(i18n.negotiate("de-AT, en;q=0.8"), Some("de"));
+        assert_eq!(i18n.negotiate("fr, en;q=0.5, de;q=0.7"), Some("de"));
+        assert_eq!(i18n.negotiate("zh-tw"), Some("zh-TW"));
+        assert_eq!(i18n.negotiate("de;q=0, fr"), None);
+        assert_eq!(i18n.negotiate("*"), Some("en-US"));
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    rt,
+    web::Bytes,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::future::Either;
+
+use crate::{body::buffered, CallInfo, FromBoxBody, Handler, MwContext, MwError, Store, StoreError, Verdict};
+
+/// What a duplicate gets while the first request with its key is still running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// `409 Conflict` at once.
+    Reject,
+    /// Poll the store until the first response is cached, then replay it; `409` after
+    /// the duration.
+    Wait(Duration),
+}
+
+#[derive(Debug)]
+struct Settings {
+    header: HeaderName,
+    methods: Vec<Method>,
+    ttl: Duration,
+    lock_ttl: Duration,
+    max_body: usize,
+    prefix: String,
+    on_conflict: OnConflict,
+}
+
+/// The cached response and lock of one caller's key.
+#[derive(Clone)]
+struct Entry {
+    store: Arc<dyn Store>,
+    settings: Arc<Settings>,
+    key: String,
+}
+
+impl Entry {
+    fn response_key(&self) -> String {
+        format!("{}{}", self.settings.prefix, self.key)
+    }
+
+    fn lock_key(&self) -> String {
+        format!("{}lock:{}", self.settings.prefix, self.key)
+    }
+
+    /// The cached response, or `None` once this request holds the lock.
+    async fn claim(&self) -> Result<Option<HttpResponse>, MwError> {
+        let started_at = Instant::now();
+        loop {
+            if let Some(cached) = self.store.get(&self.response_key()).await? {
+                let resp = decode(&cached).ok_or_else(|| StoreError::new("corrupt idempotency entry"))?;
+                return Ok(Some(resp));
+            }
+
+            if self.store.incr(&self.lock_key(), 1, Some(self.settings.lock_ttl)).await? == 1 {
+                return Ok(None);
+            }
+
+            match self.settings.on_conflict {
+                OnConflict::Wait(wait) if started_at.elapsed() < wait => {
+                    rt::time::sleep(Duration::from_millis(50)).await;
+                }
+                _ => return Err(MwError::Conflict("idempotency_key_in_use")),
+            }
+        }
+    }
+}
+
+/// The operation a request's idempotency key names, waiting for `verify` to look it up.
+struct Pending(String);
+
+/// Set by `verify` once this request holds the lock; `post` caches its response.
+struct Claimed(Entry);
+
+/// The request's idempotency key, if it carried one that `Idempotency` covers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdempotencyKey(pub Option<String>);
+
+impl FromRequest for IdempotencyKey {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(req.extensions().get::<IdempotencyKey>().cloned().unwrap_or(IdempotencyKey(None))))
+    }
+}
+
+/// Whose key it is: the principal when one was authenticated, else the client address, so a
+/// caller cannot replay another caller's responses by reusing their key.
+fn caller(req: &HttpRequest) -> String {
+    let context = MwContext::of(req);
+    if let Some(principal) = context.principal() {
+        return format!("principal:{}", principal);
+    }
+    match context.client_ip().or_else(|| req.peer_addr().map(|addr| addr.ip())) {
+        Some(ip) => format!("ip:{}", ip),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// `status(2) count(2) [name_len(2) name value_len(2) value]* body`, big-endian.
+fn encode(status: StatusCode, headers: &[(HeaderName, HeaderValue)], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 64);
+    out.extend_from_slice(&status.as_u16().to_be_bytes());
+    out.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+    for (name, value) in headers {
+        out.extend_from_slice(&(name.as_str().len() as u16).to_be_bytes());
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(&(value.as_bytes().len() as u16).to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn decode(data: &[u8]) -> Option<HttpResponse> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if data.len() < n {
+            return None;
+        }
+        let (head, rest) = data.split_at(n);
+        *data = rest;
+        Some(head)
+    }
+    fn take_u16(data: &mut &[u8]) -> Option<u16> {
+        take(data, 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    let mut data = data;
+    let status = StatusCode::from_u16(take_u16(&mut data)?).ok()?;
+    let mut resp = HttpResponse::build(status);
+    for _ in 0..take_u16(&mut data)? {
+        let len = take_u16(&mut data)? as usize;
+        let name = HeaderName::from_bytes(take(&mut data, len)?).ok()?;
+        let len = take_u16(&mut data)? as usize;
+        let value = HeaderValue::from_bytes(take(&mut data, len)?).ok()?;
+        resp.append_header((name, value));
+    }
+    resp.insert_header(("idempotent-replayed", "true"));
+    Some(resp.body(Bytes::copy_from_slice(data)))
+}
+
+/// The `Idempotency-Key` pattern: the first `POST`/`PATCH` with a key runs and its
+/// response is cached; later requests from the same caller with the same key get the cached
+/// response from `verify`, before the route runs. Keys are scoped to the authenticated
+/// principal (from `MwContext`), else to the client address, so put `Idempotency` after the
+/// auth middlewares.
+///
+/// Only responses whose body is available at once (not streamed), below the size cap and
+/// not `5xx` are cached, without their `Set-Cookie` headers. A request that fails without a
+/// response keeps its key locked until the lock expires.
+#[derive(Clone)]
+pub struct Idempotency {
+    store: Arc<dyn Store>,
+    settings: Arc<Settings>,
+}
+
+impl Idempotency {
+    pub fn new<S: Store + 'static>(store: S) -> Self {
+        Idempotency::with_store(Arc::new(store))
+    }
+
+    /// Shares `store` with other middlewares.
+    pub fn with_store(store: Arc<dyn Store>) -> Self {
+        Idempotency {
+            store,
+            settings: Arc::new(Settings {
+                header: HeaderName::from_static("idempotency-key"),
+                methods: vec![Method::POST, Method::PATCH],
+                ttl: Duration::from_secs(24 * 60 * 60),
+                lock_ttl: Duration::from_secs(60),
+                max_body: 64 * 1024,
+                prefix: "idem:".to_string(),
+                on_conflict: OnConflict::Reject,
+            }),
+        }
+    }
+
+    fn settings(mut self, f: impl FnOnce(&mut Settings)) -> Self {
+        f(Arc::get_mut(&mut self.settings).expect("configure Idempotency before cloning it"));
+        self
+    }
+
+    pub fn with_header(self, header: HeaderName) -> Self {
+        self.settings(|s| s.header = header)
+    }
+
+    pub fn with_methods(self, methods: Vec<Method>) -> Self {
+        self.settings(|s| s.methods = methods)
+    }
+
+    /// How long responses are replayed; defaults to 24 hours.
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.settings(|s| s.ttl = ttl)
+    }
+
+    /// Upper bound on how long a key stays locked by a request; defaults to 60 seconds.
+    pub fn with_lock_ttl(self, lock_ttl: Duration) -> Self {
+        self.settings(|s| s.lock_ttl = lock_ttl)
+    }
+
+    /// Larger responses are not cached; defaults to 64 KiB.
+    pub fn with_max_body(self, max_body: usize) -> Self {
+        self.settings(|s| s.max_body = max_body)
+    }
+
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.settings(|s| s.prefix = prefix)
+    }
+
+    pub fn on_conflict(self, on_conflict: OnConflict) -> Self {
+        self.settings(|s| s.on_conflict = on_conflict)
+    }
+}
+
+impl fmt::Debug for Idempotency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Idempotency").field("settings", &self.settings).finish()
+    }
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for Idempotency {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        !self.settings.methods.contains(req.method())
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let key = req
+            .headers()
+            .get(&self.settings.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|k| !k.is_empty() && k.len() <= 255);
+        if let Some(key) = key {
+            // the same key on another route is another operation
+            let operation = format!("{} {} {}", req.method(), req.path(), key);
+            let key = IdempotencyKey(Some(key.to_string()));
+            req.extensions_mut().insert(key);
+            req.extensions_mut().insert(Pending(operation));
+        }
+        Either::Right(req)
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let Pending(operation) = req.extensions_mut().remove::<Pending>()?;
+        let store = self.store.clone();
+        let settings = self.settings.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            // resolved only now, once the handlers verified before this one set the principal
+            let entry = Entry {
+                store,
+                settings,
+                key: format!("{} {}", caller(&req), operation),
+            };
+            match entry.claim().await {
+                Ok(None) => {
+                    req.extensions_mut().insert(Claimed(entry));
+                    Ok(())
+                }
+                Ok(Some(cached)) => Err(ServiceResponse::new(req, cached).map_body(|_, body| B::from_box_body(body))),
+                Err(err) => Err(err.respond(req)),
+            }
+        }))
+    }
+
+    fn post(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let claimed = resp.request().extensions_mut().remove::<Claimed>();
+        let Claimed(entry) = match claimed {
+            Some(claimed) => claimed,
+            None => return resp,
+        };
+
+        let status = resp.status();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter(|(name, _)| *name != header::SET_COOKIE)
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        let mut cached = None;
+        let resp = resp.map_body(|_, body| {
+            let (bytes, body) = buffered(body, entry.settings.max_body);
+            cached = bytes
+                .filter(|_| !status.is_server_error())
+                .map(|bytes| encode(status, &headers, &bytes));
+            body
+        });
+
+        rt::spawn(async move {
+            if let Some(cached) = cached {
+                let res = entry
+                    .store
+                    .set(&entry.response_key(), cached, Some(entry.settings.ttl))
+                    .await;
+                if let Err(err) = res {
+                    log::warn!("failed to cache idempotent response: {}", err);
+                }
+            }
+            if let Err(err) = entry.store.delete(&entry.lock_key()).await {
+                log::warn!("failed to release idempotency key: {}", err);
+            }
+        });
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use actix_web::{http::StatusCode, rt, test, web, App};
+
+    use super::Idempotency;
+    use crate::{Factory, InMemoryStore};
+
+    #[actix_web::test]
+    async fn test_idempotency() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(Idempotency::new(InMemoryStore::new())))
+                .route(
+                    "/orders",
+                    web::post().to(move || {
+                        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                        async move { format!("order {}", n) }
+                    }),
+                ),
+        )
+        .await;
+        let post = |peer: &str| {
+            test::TestRequest::post()
+                .uri("/orders")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("idempotency-key", "k1"))
+                .to_request()
+        };
+
+        assert_eq!(test::call_and_read_body(&app, post("192.0.2.1:1000")).await, "order 1");
+        // the response is cached by a spawned task; until then the key is still locked
+        let mut resp = test::call_service(&app, post("192.0.2.1:1000")).await;
+        for _ in 0..100 {
+            if resp.status() != StatusCode::CONFLICT {
+                break;
+            }
+            rt::time::sleep(Duration::from_millis(1)).await;
+            resp = test::call_service(&app, post("192.0.2.1:1000")).await;
+        }
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().contains_key("idempotent-replayed"));
+        assert_eq!(test::read_body(resp).await, "order 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // another caller reusing the key gets its own operation
+        assert_eq!(test::call_and_read_body(&app, post("198.51.100.7:1000")).await, "order 2");
+    }
+}
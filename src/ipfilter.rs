@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+};
+use futures_util::future::Either;
+use ipnet::IpNet;
+
+pub use crate::client_ip::ClientIp;
+use crate::{client_ip::ClientIpResolver, FromBoxBody, Handler, MwError};
+
+/// Size below which `Blocklist` never sweeps expired entries.
+const MIN_SWEEP: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Blocked {
+    until: HashMap<IpAddr, Instant>,
+    /// Expired entries are swept once the map reaches this size; it doubles with the live
+    /// entries left, so sweeps stay rare however many addresses are blocked.
+    sweep_at: usize,
+}
+
+/// Addresses denied until a deadline, shared between `IpFilter` and whatever detects
+/// hostile clients (e.g. `honeypot::Honeypot`). Clones share the same list.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist(Arc<Mutex<Blocked>>);
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Blocklist::default()
+    }
+
+    /// Denies `ip` for `ttl`, extending an earlier block.
+    pub fn block(&self, ip: IpAddr, ttl: Duration) {
+        let now = Instant::now();
+        let mut blocked = self.0.lock().unwrap();
+        if blocked.until.len() >= blocked.sweep_at.max(MIN_SWEEP) {
+            blocked.until.retain(|_, at| *at > now);
+            blocked.sweep_at = blocked.until.len() * 2;
+        }
+        let at = blocked.until.entry(ip).or_insert(now + ttl);
+        *at = (*at).max(now + ttl);
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.lock().unwrap().until.get(ip).map_or(false, |until| *until > Instant::now())
+    }
+}
+
+/// Allow- and denylists of addresses and CIDR ranges.
+///
+/// The client address comes from a `ClientIpResolver` (see there), cached as `ClientIp`.
+/// Without `with_resolver`, one hop of `X-Forwarded-For` is followed
+/// when the peer is a proxy added with `trusted_proxy`.
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    resolver: ClientIpResolver,
+    blocklist: Option<Blocklist>,
+    status: StatusCode,
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        IpFilter {
+            allow: vec![],
+            deny: vec![],
+            resolver: ClientIpResolver::new().trust_depth(1),
+            blocklist: None,
+            status: StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    /// Once any range is allowed, every other address is rejected.
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.allow.push(net);
+        self
+    }
+
+    /// Checked before the allowlist.
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.deny.push(net);
+        self
+    }
+
+    pub fn trusted_proxy(mut self, net: IpNet) -> Self {
+        self.resolver = self.resolver.trusted_proxy(net);
+        self
+    }
+
+    pub fn trust_depth(mut self, depth: usize) -> Self {
+        self.resolver = self.resolver.trust_depth(depth);
+        self
+    }
+
+    /// Replaces the trusted proxies and strategies set so far, e.g. with the resolver
+    /// shared by the rest of the stack.
+    pub fn with_resolver(mut self, resolver: ClientIpResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Also denies addresses currently on `blocklist`.
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// `404 Not Found` hides that the resource exists; defaults to `403 Forbidden`.
+    pub fn reject_with(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// The cached `ClientIp`, resolving and caching it on first use.
+    pub fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        self.resolver.client_ip(req)
+    }
+
+    fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        if self.blocklist.as_ref().map_or(false, |b| b.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for IpFilter {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        // without a peer address (e.g. in unit tests) only an empty allowlist lets requests through
+        let ip = self.client_ip(&req);
+        let permitted = match ip {
+            Some(ip) => self.permits(&ip),
+            None => self.allow.is_empty(),
+        };
+
+        if permitted {
+            return Either::Right(req);
+        }
+        Either::Left(MwError::Rejected(self.status, "ip_denied").reject(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use actix_web::test::TestRequest;
+
+    use super::{Blocklist, IpFilter};
+
+    #[test]
+    fn test_client_ip() {
+        let filter = IpFilter::new()
+            .trusted_proxy("10.0.0.0/8".parse().unwrap())
+            .trust_depth(2);
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.9, 198.51.100.7, 10.0.0.2"))
+            .to_srv_request();
+        assert_eq!(filter.client_ip(&req), Some("198.51.100.7".parse().unwrap()));
+
+        let req = TestRequest::default()
+            .peer_addr("192.0.2.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.9"))
+            .to_srv_request();
+        assert_eq!(filter.client_ip(&req), Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_blocklist_sweep() {
+        let blocklist = Blocklist::new();
+        let expired = |i: u32| IpAddr::from(Ipv4Addr::from(i));
+        for i in 0..super::MIN_SWEEP as u32 {
+            blocklist.block(expired(i), Duration::ZERO);
+        }
+        blocklist.block("192.0.2.1".parse().unwrap(), Duration::from_secs(60));
+        blocklist.block("192.0.2.2".parse().unwrap(), Duration::from_secs(60));
+
+        assert_eq!(blocklist.0.lock().unwrap().until.len(), 2);
+        assert!(blocklist.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!blocklist.contains(&expired(0)));
+    }
+}
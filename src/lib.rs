@@ -1,32 +1,494 @@
+// actix-web is aliased to `actix_web` behind the `actix-web-4` feature, so the core and every
+// middleware module import it by that name. `awc` (mirror) and `actix-session` (csrf-session)
+// follow actix-web 4.
+#[cfg(feature = "actix-web-4")]
+extern crate actix_web_4 as actix_web;
+
+#[cfg(not(feature = "actix-web-4"))]
+compile_error!("the `actix-web-4` feature is required");
+
+#[cfg(feature = "accesslog")]
+pub mod accesslog;
+
+#[cfg(feature = "audit")]
+pub mod audit;
+
+#[cfg(any(feature = "auth-apikey", feature = "auth-basic", feature = "auth-jwt"))]
+pub mod auth;
+
+#[cfg(feature = "body-limit")]
+pub mod body_limit;
+
+#[cfg(feature = "cachecontrol")]
+pub mod cachecontrol;
+
+#[cfg(feature = "circuitbreaker")]
+pub mod circuitbreaker;
+
+#[cfg(feature = "client-ip")]
+pub mod client_ip;
+
+#[cfg(feature = "concurrency")]
+pub mod concurrency;
+
+#[cfg(feature = "config")]
+pub mod config;
+
+#[cfg(feature = "cookies")]
+pub mod cookie;
+
+#[cfg(feature = "cors")]
+pub mod cors;
+
 #[cfg(feature = "csrf")]
 pub mod csrf;
 
+#[cfg(feature = "etag")]
+pub mod etag;
+
+#[cfg(feature = "experiment")]
+pub mod experiment;
+
+#[cfg(feature = "headers")]
+pub mod headers;
+
+#[cfg(feature = "health")]
+pub mod health;
+
+#[cfg(feature = "honeypot")]
+pub mod honeypot;
+
+#[cfg(feature = "i18n")]
+pub mod i18n;
+
+#[cfg(feature = "idempotency")]
+pub mod idempotency;
+
+#[cfg(feature = "ipfilter")]
+pub mod ipfilter;
+
+#[cfg(feature = "maintenance")]
+pub mod maintenance;
+
+#[cfg(feature = "metrics-prometheus")]
+pub mod metrics;
+
+#[cfg(feature = "mirror")]
+pub mod mirror;
+
+#[cfg(feature = "otel")]
+pub mod otel;
+
+#[cfg(feature = "ratelimit")]
+pub mod ratelimit;
+
+#[cfg(feature = "redirect")]
+pub mod redirect;
+
+#[cfg(feature = "replay")]
+pub mod replay;
+
+#[cfg(feature = "request-id")]
+pub mod request_id;
+
+#[cfg(feature = "security-headers")]
+pub mod security_headers;
+
+#[cfg(feature = "signature")]
+pub mod signature;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "timeout")]
+pub mod timeout;
+
+mod body;
+mod context;
+mod deadline;
+mod enforcement;
+mod error;
+mod json;
+mod matcher;
+mod policy;
+#[cfg(any(feature = "csrf", feature = "signature"))]
+mod prefetch;
+mod reload;
+mod secret;
+mod stats;
+mod store;
+mod trace;
+
+pub use body::{collect_up_to, observe, BodyObserver, FromBoxBody, Observed};
+pub use context::MwContext;
+pub use enforcement::EnforcementMode;
+pub use error::MwError;
+pub use matcher::{MatchPattern, SkipRule, SkipSet};
+pub use policy::{CachedPolicies, Identity, Policy, PolicyResolver};
+pub use reload::{ConfigHandle, ReloadableFactory};
+pub use secret::{Secret, SecretError, SecretFn, SecretSource};
+#[cfg(feature = "stats")]
+pub use stats::{MiddlewareStats, StatsSnapshot};
+pub use store::{InMemoryStore, Store, StoreError};
+#[cfg(feature = "redis")]
+pub use store::RedisStore;
+
 use std::{
+    collections::hash_map::RandomState,
     future::{ready, Future, Ready},
+    hash::{BuildHasher, Hasher},
     marker::PhantomData,
+    ops::RangeInclusive,
     pin::Pin,
     rc::Rc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use actix_web::{
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    dev::{forward_ready, Extensions, Service, ServiceRequest, ServiceResponse, Transform},
+    guard::Guard,
+    http::{Method, StatusCode},
+    rt, Error, HttpMessage,
 };
 
 use futures_core::{future::LocalBoxFuture, ready};
 use futures_util::future::Either;
 use pin_project_lite::pin_project;
+use deadline::Deadline;
+use enforcement::Retained;
+use stats::Outcome;
+use trace::{CallSpan, Traced};
+
+/// Timing information about a single pass through the middleware.
+#[derive(Clone, Debug)]
+pub struct CallInfo {
+    /// When `Middleware::call` received the request.
+    pub started_at: Instant,
+    /// Time from `started_at` until the response (or error) was available.
+    pub elapsed: Duration,
+    /// The request bypassed `process`.
+    pub skipped: bool,
+    /// The response was produced by `process` or `verify` rather than the inner service.
+    pub short_circuited: bool,
+    /// The request's context; the only way to reach it from `on_error`.
+    pub context: MwContext,
+    /// The status `process` or `verify` answered with while the factory is in
+    /// `EnforcementMode::ReportOnly`; the request was forwarded regardless.
+    pub reported: Option<StatusCode>,
+}
+
+impl CallInfo {
+    pub(crate) fn finish(started_at: Instant, skipped: bool, short_circuited: bool, context: &MwContext) -> Self {
+        CallInfo {
+            started_at,
+            elapsed: started_at.elapsed(),
+            skipped,
+            short_circuited,
+            context: context.clone(),
+            reported: None,
+        }
+    }
+}
+
+/// The outcome of `Handler::verify`: `Err` answers the request instead of the inner service.
+pub type Verdict<B> = LocalBoxFuture<'static, Result<(), ServiceResponse<B>>>;
 
 pub trait Handler<B> {
+    /// Name used for the request span when the `tracing` feature is enabled.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
     fn skip(&self, _: &ServiceRequest) -> bool {
         false
     }
 
     fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest>;
-    fn post(&self, resp: ServiceResponse<B>) -> ServiceResponse<B> {
+
+    /// An asynchronous check of a request `process` forwarded, e.g. a password hash, a store
+    /// lookup or a signature over the body. The inner service is only called once the verdict
+    /// is `Ok`; an `Err` response is handled like one from `process`. `req` may be changed,
+    /// e.g. to buffer its payload.
+    fn verify(&self, _: &mut ServiceRequest) -> Option<Verdict<B>> {
+        None
+    }
+
+    /// Called on responses from the inner service. Short-circuit responses only reach `post`
+    /// when enabled with `Factory::post_on_short_circuit`.
+    fn post(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        resp
+    }
+
+    /// Called last on every response, including skipped and short-circuited requests;
+    /// `CallInfo` tells them apart.
+    fn finalize(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
         resp
     }
+
+    /// Called when the inner service fails instead of producing a response.
+    fn on_error(&self, _: &Error, _: &CallInfo) {}
+
+    /// Deadline for the inner service on a forwarded request. Once it passes, the inner
+    /// call is dropped and fails with the error from `on_timeout`.
+    fn timeout(&self, _: &ServiceRequest) -> Option<Duration> {
+        None
+    }
+
+    /// The error a timed-out call fails with; actix-web answers with its response, so
+    /// middlewares further out see it in `on_error` rather than as a response, with
+    /// `MwContext::timed_out` set. The request itself is gone with the dropped call: routing
+    /// needs it unshared, so no copy is kept.
+    ///
+    /// Defaults to `MwError::Timeout`, a `504 Gateway Timeout`.
+    fn on_timeout(&self, _: &MwContext) -> Error {
+        MwError::Timeout.into()
+    }
+}
+
+/// A list of boxed handlers, run in order as a single handler.
+pub type Chain<B> = Vec<Box<dyn Handler<B>>>;
+
+impl<B> Handler<B> for Box<dyn Handler<B>> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        (**self).skip(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        (**self).process(req)
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        (**self).verify(req)
+    }
+
+    fn post(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        (**self).post(resp, info)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        (**self).finalize(resp, info)
+    }
+
+    fn on_error(&self, err: &Error, info: &CallInfo) {
+        (**self).on_error(err, info)
+    }
+
+    fn timeout(&self, req: &ServiceRequest) -> Option<Duration> {
+        (**self).timeout(req)
+    }
+
+    fn on_timeout(&self, context: &MwContext) -> Error {
+        (**self).on_timeout(context)
+    }
+}
+
+/// The verdicts of a `Chain`'s handlers, kept from its `process` until its `verify`.
+struct Pending<B>(Vec<Verdict<B>>);
+
+/// The positions of the handlers of a `Chain` whose `process` ran, pinned for its hooks.
+#[derive(Clone)]
+struct Ran(Rc<[usize]>);
+
+/// Every handler runs its own `skip`/`process` in order and the first short-circuit wins.
+/// `post`, `finalize` and `on_error` are applied in reverse order by the handlers whose
+/// `process` ran, i.e. not by those that skipped the request or came after a short-circuit.
+/// When the whole chain is skipped, all handlers see the request in `finalize`.
+impl<B: 'static> Handler<B> for Vec<Box<dyn Handler<B>>> {
+    fn name(&self) -> &'static str {
+        "chain"
+    }
+
+    fn process(&self, mut req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let context = MwContext::of(&req);
+        let mut positions = vec![];
+        let mut verdicts = vec![];
+        for (i, h) in self.iter().enumerate() {
+            if h.skip(&req) {
+                continue;
+            }
+
+            positions.push(i);
+            match h.process(req) {
+                Either::Left(res) => {
+                    context.pin(ran_slot(self), || Ran(positions.into()));
+                    return Either::Left(res);
+                }
+                Either::Right(r) => req = r,
+            }
+            verdicts.extend(h.verify(&mut req));
+        }
+
+        context.pin(ran_slot(self), || Ran(positions.into()));
+        if !verdicts.is_empty() {
+            req.extensions_mut().insert(Pending(verdicts));
+        }
+        Either::Right(req)
+    }
+
+    /// The verdicts of the handlers whose `process` ran, awaited in order; the first
+    /// rejection wins.
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let Pending(verdicts) = req.extensions_mut().remove::<Pending<B>>()?;
+        Some(Box::pin(async move {
+            for verdict in verdicts {
+                verdict.await?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn post(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        for i in ran(self, &info.context).iter().rev() {
+            resp = self[*i].post(resp, info);
+        }
+        resp
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        if info.skipped {
+            for h in self.iter().rev() {
+                resp = h.finalize(resp, info);
+            }
+            return resp;
+        }
+        for i in ran(self, &info.context).iter().rev() {
+            resp = self[*i].finalize(resp, info);
+        }
+        resp
+    }
+
+    fn on_error(&self, err: &Error, info: &CallInfo) {
+        for i in ran(self, &info.context).iter().rev() {
+            self[*i].on_error(err, info);
+        }
+    }
+
+    /// The shortest deadline of any handler.
+    fn timeout(&self, req: &ServiceRequest) -> Option<Duration> {
+        let (i, timeout) = self
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| Some((i, h.timeout(req)?)))
+            .min_by_key(|(_, timeout)| *timeout)?;
+        MwContext::of(req).pin(self.as_ptr() as usize, || i);
+        Some(timeout)
+    }
+
+    /// The error of the handler whose deadline passed.
+    fn on_timeout(&self, context: &MwContext) -> Error {
+        let i = context.pin(self.as_ptr() as usize, || 0);
+        match self.get(i) {
+            Some(h) => h.on_timeout(context),
+            None => MwError::Timeout.into(),
+        }
+    }
+}
+
+/// Odd, so it never meets the slot `Chain::timeout` pins under `as_ptr`.
+fn ran_slot<B>(chain: &Chain<B>) -> usize {
+    chain.as_ptr() as usize | 1
+}
+
+fn ran<B>(chain: &Chain<B>, context: &MwContext) -> Rc<[usize]> {
+    context.pin(ran_slot(chain), || Ran(Rc::from([]))).0
+}
+
+/// How long short-circuit responses are held back; see `Factory::reject_delay`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RejectDelay {
+    min: Duration,
+    max: Duration,
+}
+
+impl RejectDelay {
+    /// A delay drawn uniformly from `min..=max` for each response.
+    pub fn jitter(min: Duration, max: Duration) -> Self {
+        RejectDelay {
+            min: min.min(max),
+            max: max.max(min),
+        }
+    }
+
+    fn pick(&self) -> Duration {
+        let spread = (self.max - self.min).as_nanos() as u64;
+        if spread == 0 {
+            return self.min;
+        }
+        // not for cryptography; only so attackers cannot time responses exactly
+        let random = RandomState::new().build_hasher().finish();
+        self.min + Duration::from_nanos(random % (spread + 1))
+    }
+}
+
+impl From<Duration> for RejectDelay {
+    fn from(delay: Duration) -> Self {
+        RejectDelay::jitter(delay, delay)
+    }
+}
+
+impl From<RangeInclusive<Duration>> for RejectDelay {
+    fn from(range: RangeInclusive<Duration>) -> Self {
+        RejectDelay::jitter(*range.start(), *range.end())
+    }
+}
+
+type InsertFn = dyn Fn(&mut Extensions);
+
+/// Settings configured on a `Factory` and shared by every request it handles.
+#[derive(Clone, Default)]
+pub struct Options {
+    guards: Vec<(Rc<dyn Guard>, bool)>,
+    skip_rules: SkipSet,
+    skip_upgrades: bool,
+    post_on_short_circuit: bool,
+    extensions: Vec<Rc<InsertFn>>,
+    reject_delay: Option<RejectDelay>,
+    enforcement: EnforcementMode,
+    #[cfg(feature = "stats")]
+    stats: MiddlewareStats,
+}
+
+impl Options {
+    #[allow(unused_variables)]
+    fn record(&self, outcome: Outcome) {
+        #[cfg(feature = "stats")]
+        self.stats.record(outcome);
+    }
+
+    fn extend(&self, req: &ServiceRequest) {
+        if self.extensions.is_empty() {
+            return;
+        }
+
+        let mut ext = req.extensions_mut();
+        for insert in &self.extensions {
+            insert(&mut ext);
+        }
+    }
+
+    fn bypass(&self, req: &ServiceRequest) -> bool {
+        if self.skip_upgrades && (req.head().upgrade() || req.method() == Method::CONNECT) {
+            return true;
+        }
+
+        if self.skip_rules.matches(req) {
+            return true;
+        }
+
+        if self.guards.is_empty() {
+            return false;
+        }
+
+        let ctx = req.guard_ctx();
+        self.guards
+            .iter()
+            .any(|(guard, expected)| guard.check(&ctx) != *expected)
+    }
 }
 
 pub struct Factory<T, B>
@@ -34,6 +496,7 @@ where
     T: Handler<B>,
 {
     inner: Rc<T>,
+    opts: Options,
     _phantom: PhantomData<B>,
 }
 
@@ -44,16 +507,170 @@ where
     pub fn new(h: T) -> Self {
         Factory {
             inner: Rc::new(h),
+            opts: Options::default(),
             _phantom: PhantomData,
         }
     }
+
+    pub fn builder(h: T) -> FactoryBuilder<T, B> {
+        FactoryBuilder {
+            factory: Factory::new(h),
+        }
+    }
+
+    /// Only run the handler when `guard` matches; other requests are forwarded untouched.
+    /// Multiple calls to `when`/`unless` must all hold.
+    pub fn when<G: Guard + 'static>(mut self, guard: G) -> Self {
+        self.opts.guards.push((Rc::new(guard), true));
+        self
+    }
+
+    /// Only run the handler when `guard` does not match.
+    pub fn unless<G: Guard + 'static>(mut self, guard: G) -> Self {
+        self.opts.guards.push((Rc::new(guard), false));
+        self
+    }
+
+    /// Forward requests under any of the path prefixes `paths` without running the handler,
+    /// e.g. health checks and the metrics endpoint. Checked before `Handler::skip`, so the
+    /// same exclusions apply to every scope the factory wraps.
+    pub fn skip_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.opts.skip_rules.extend(paths.into_iter().map(SkipRule::path));
+        self
+    }
+
+    /// Forward requests matching `rule` without running the handler.
+    pub fn skip_when(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.opts.skip_rules.push(rule.into());
+        self
+    }
+
+    /// Forward `Connection: Upgrade` (e.g. WebSocket handshakes) and `CONNECT` requests
+    /// without calling `process`.
+    pub fn skip_upgrades(mut self, skip: bool) -> Self {
+        self.opts.skip_upgrades = skip;
+        self
+    }
+
+    /// Holds back responses produced by `process` or `verify` (rejections, but also e.g. health probes
+    /// or redirects answered by the handler) by a fixed delay or a jittered range, without
+    /// blocking the worker. Slows down attackers probing protected endpoints; forwarded
+    /// requests are not delayed.
+    pub fn reject_delay(mut self, delay: impl Into<RejectDelay>) -> Self {
+        self.opts.reject_delay = Some(delay.into());
+        self
+    }
+
+    /// Also run `Handler::post` on responses produced by `process` or `verify`.
+    pub fn post_on_short_circuit(mut self, enable: bool) -> Self {
+        self.opts.post_on_short_circuit = enable;
+        self
+    }
+
+    /// With `EnforcementMode::ReportOnly`, a response from `process` or `verify` is logged,
+    /// counted as `reported` and dropped, and the request is forwarded with its body. `post`,
+    /// `finalize` and `on_error` then see the dropped status in `CallInfo::reported`. In a
+    /// `Chain`, the handlers after the one that answered do not run.
+    pub fn enforcement(mut self, mode: EnforcementMode) -> Self {
+        self.opts.enforcement = mode;
+        self
+    }
+
+    /// Counters for this factory's middlewares.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> MiddlewareStats {
+        self.opts.stats.clone()
+    }
+
+    /// Record into `stats` instead of a fresh set of counters, e.g. to aggregate all workers.
+    #[cfg(feature = "stats")]
+    pub fn with_stats(mut self, stats: MiddlewareStats) -> Self {
+        self.opts.stats = stats;
+        self
+    }
+}
+
+pub struct FactoryBuilder<T, B>
+where
+    T: Handler<B>,
+{
+    factory: Factory<T, B>,
+}
+
+impl<T, B> FactoryBuilder<T, B>
+where
+    T: Handler<B>,
+{
+    /// Inserts a clone of `ext` into the request extensions before `process` runs, so both
+    /// the handler and route handlers can read it. Skipped requests receive it too.
+    pub fn insert_extension<E: Clone + 'static>(mut self, ext: E) -> Self {
+        self.factory
+            .opts
+            .extensions
+            .push(Rc::new(move |extensions: &mut Extensions| {
+                extensions.insert(ext.clone());
+            }));
+        self
+    }
+
+    pub fn when<G: Guard + 'static>(mut self, guard: G) -> Self {
+        self.factory = self.factory.when(guard);
+        self
+    }
+
+    pub fn unless<G: Guard + 'static>(mut self, guard: G) -> Self {
+        self.factory = self.factory.unless(guard);
+        self
+    }
+
+    pub fn skip_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.factory = self.factory.skip_paths(paths);
+        self
+    }
+
+    pub fn skip_when(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.factory = self.factory.skip_when(rule);
+        self
+    }
+
+    pub fn skip_upgrades(mut self, skip: bool) -> Self {
+        self.factory = self.factory.skip_upgrades(skip);
+        self
+    }
+
+    pub fn post_on_short_circuit(mut self, enable: bool) -> Self {
+        self.factory = self.factory.post_on_short_circuit(enable);
+        self
+    }
+
+    pub fn reject_delay(mut self, delay: impl Into<RejectDelay>) -> Self {
+        self.factory = self.factory.reject_delay(delay);
+        self
+    }
+
+    pub fn enforcement(mut self, mode: EnforcementMode) -> Self {
+        self.factory = self.factory.enforcement(mode);
+        self
+    }
+
+    pub fn build(self) -> Factory<T, B> {
+        self.factory
+    }
 }
 
 impl<S, T, B> Transform<S, ServiceRequest> for Factory<T, B>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
-    T: Handler<B>,
+    T: Handler<B> + 'static,
     B: 'static,
 {
     type Response = ServiceResponse<B>;
@@ -64,8 +681,9 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(Middleware {
-            service,
+            service: Rc::new(service),
             inner: self.inner.clone(),
+            opts: Rc::new(self.opts.clone()),
             _phantom: PhantomData,
         }))
     }
@@ -75,51 +693,119 @@ pub struct Middleware<S, T, B>
 where
     T: Handler<B>,
 {
-    service: S,
+    service: Rc<S>,
     inner: Rc<T>,
+    opts: Rc<Options>,
     _phantom: PhantomData<B>,
 }
 
 impl<S, T, B> Service<ServiceRequest> for Middleware<S, T, B>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
-    T: Handler<B>,
+    T: Handler<B> + 'static,
     B: 'static,
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
-    type Future = HandlerFuture<S::Future, T, B>;
+    type Future = HandlerFuture<Traced<Deadline<S::Future, T, B>>, T, B>;
 
     forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        if self.inner.skip(&req) {
-            return HandlerFuture::SkipFuture {
-                fut: self.service.call(req),
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let started_at = Instant::now();
+        let span = CallSpan::new(self.inner.name());
+        let context = MwContext::of(&req);
+        self.opts.extend(&req);
+
+        if span.in_scope(|| self.opts.bypass(&req) || self.inner.skip(&req)) {
+            span.outcome("skip");
+            self.opts.record(Outcome::Skipped);
+            return HandlerFuture::Skip {
+                fut: span.instrument(Deadline::new(self.service.call(req), None)),
+                inner: self.inner.clone(),
+                opts: self.opts.clone(),
+                started_at,
+                context,
             };
         }
 
-        match self.inner.process(req) {
-            Either::Left(res) => {
-                let h = self.inner.clone();
-                HandlerFuture::ErrorHandlerFuture {
-                    fut: Box::pin(async move { Ok(res) }),
-                    inner: h,
-                }
+        let retained = match self.opts.enforcement {
+            EnforcementMode::Enforce => None,
+            EnforcementMode::ReportOnly => Some(Retained::wrap(&mut req)),
+        };
+
+        let mut reported = None;
+        let req = match (span.in_scope(|| self.inner.process(req)), retained) {
+            (Either::Left(res), Some(retained)) => {
+                report(self.inner.name(), &res);
+                span.outcome("report_only");
+                self.opts.record(Outcome::Reported);
+                reported = Some(res.status());
+                Either::Right(retained.restore(res))
             }
-            Either::Right(req) => {
-                let h = self.inner.clone();
+            (res, _) => res,
+        };
 
-                HandlerFuture::HandlerFuture {
-                    fut: self.service.call(req),
-                    inner: h,
-                }
+        let mut req = match req {
+            Either::Left(res) => {
+                span.outcome("short_circuit");
+                self.opts.record(Outcome::Rejected);
+                return HandlerFuture::rejected(res, self.inner.clone(), self.opts.clone(), started_at, context);
             }
+            Either::Right(req) => req,
+        };
+
+        let h = self.inner.clone();
+        let timeout = h.timeout(&req).map(|timeout| (timeout, h.clone(), context.clone()));
+
+        // a request already let through by `ReportOnly` is not checked any further
+        let verdict = match reported {
+            None => span.in_scope(|| h.verify(&mut req)),
+            Some(_) => None,
+        };
+        if let Some(verdict) = verdict {
+            let service = self.service.clone();
+            let call_span = span.clone();
+            return HandlerFuture::Verify {
+                verdict,
+                req: Some(req),
+                call: Some(Box::new(move |req| {
+                    call_span.instrument(Deadline::new(service.call(req), timeout))
+                })),
+                span,
+                inner: h,
+                opts: self.opts.clone(),
+                started_at,
+                context,
+            };
+        }
+
+        if reported.is_none() {
+            span.outcome("forward");
+            self.opts.record(Outcome::Forwarded);
+        }
+        HandlerFuture::Handler {
+            fut: span.instrument(Deadline::new(self.service.call(req), timeout)),
+            inner: h,
+            opts: self.opts.clone(),
+            started_at,
+            context,
+            reported,
         }
     }
 }
 
+fn report<B>(handler: &str, res: &ServiceResponse<B>) {
+    log::warn!(
+        "{} would reject {} {} with {}",
+        handler,
+        res.request().method(),
+        res.request().path(),
+        res.status()
+    );
+}
+
 pin_project! {
     #[project = HandlerProj]
     pub enum HandlerFuture<Fut, T, B>
@@ -127,23 +813,70 @@ pin_project! {
         Fut: Future,
         T: Handler<B>,
     {
-        SkipFuture {
+        Skip {
             #[pin]
             fut: Fut,
+            inner: Rc<T>,
+            opts: Rc<Options>,
+            started_at: Instant,
+            context: MwContext,
+        },
+
+        Verify {
+            verdict: Verdict<B>,
+            req: Option<ServiceRequest>,
+            call: Option<Box<dyn FnOnce(ServiceRequest) -> Fut>>,
+            span: CallSpan,
+            inner: Rc<T>,
+            opts: Rc<Options>,
+            started_at: Instant,
+            context: MwContext,
         },
 
-        HandlerFuture {
+        Handler {
             #[pin]
             fut: Fut,
             inner: Rc<T>,
+            opts: Rc<Options>,
+            started_at: Instant,
+            context: MwContext,
+            reported: Option<StatusCode>,
         },
-        ErrorHandlerFuture {
-            fut: LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>,
+        ErrorHandler {
+            #[pin]
+            delay: Option<rt::time::Sleep>,
+            res: Option<ServiceResponse<B>>,
             inner: Rc<T>,
+            opts: Rc<Options>,
+            started_at: Instant,
+            context: MwContext,
         },
     }
 }
 
+impl<Fut, T, B> HandlerFuture<Fut, T, B>
+where
+    Fut: Future,
+    T: Handler<B>,
+{
+    fn rejected(
+        res: ServiceResponse<B>,
+        inner: Rc<T>,
+        opts: Rc<Options>,
+        started_at: Instant,
+        context: MwContext,
+    ) -> Self {
+        HandlerFuture::ErrorHandler {
+            delay: opts.reject_delay.map(|delay| rt::time::sleep(delay.pick())),
+            res: Some(res),
+            inner,
+            opts,
+            started_at,
+            context,
+        }
+    }
+}
+
 impl<Fut, T, B> Future for HandlerFuture<Fut, T, B>
 where
     Fut: Future<Output = Result<ServiceResponse<B>, Error>>,
@@ -152,28 +885,285 @@ where
     type Output = Result<ServiceResponse<B>, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.as_mut().project() {
-            HandlerProj::SkipFuture { fut } => Poll::Ready(Ok(ready!(fut.poll(cx))?)),
-            HandlerProj::HandlerFuture { fut, inner } => {
-                let res = ready!(fut.poll(cx))?;
-                let res = inner.post(res);
-                Poll::Ready(Ok(res))
-            }
-            HandlerProj::ErrorHandlerFuture { fut, inner } => {
-                let res = ready!(fut.as_mut().poll(cx))?;
-                let res = inner.post(res);
-                Poll::Ready(Ok(res))
-            }
+        loop {
+            let next = match self.as_mut().project() {
+                HandlerProj::Skip {
+                    fut,
+                    inner,
+                    opts,
+                    started_at,
+                    context,
+                } => {
+                    let res = ready!(fut.poll(cx));
+                    let info = CallInfo::finish(*started_at, true, false, context);
+                    return match res {
+                        Ok(res) => Poll::Ready(Ok(inner.finalize(res, &info))),
+                        Err(err) => {
+                            opts.record(Outcome::Errored);
+                            inner.on_error(&err, &info);
+                            Poll::Ready(Err(err))
+                        }
+                    };
+                }
+                HandlerProj::Verify {
+                    verdict,
+                    req,
+                    call,
+                    span,
+                    inner,
+                    opts,
+                    started_at,
+                    context,
+                } => {
+                    match ready!(verdict.as_mut().poll(cx)) {
+                        Err(res) if opts.enforcement.is_enforced() => {
+                            span.outcome("short_circuit");
+                            opts.record(Outcome::Rejected);
+                            HandlerFuture::rejected(res, inner.clone(), opts.clone(), *started_at, context.clone())
+                        }
+                        verdict => {
+                            let reported = match verdict {
+                                Ok(()) => {
+                                    span.outcome("forward");
+                                    opts.record(Outcome::Forwarded);
+                                    None
+                                }
+                                Err(res) => {
+                                    report(inner.name(), &res);
+                                    span.outcome("report_only");
+                                    opts.record(Outcome::Reported);
+                                    Some(res.status())
+                                }
+                            };
+                            let req = req.take().expect("HandlerFuture polled after completion");
+                            let call = call.take().expect("HandlerFuture polled after completion");
+                            HandlerFuture::Handler {
+                                fut: call(req),
+                                inner: inner.clone(),
+                                opts: opts.clone(),
+                                started_at: *started_at,
+                                context: context.clone(),
+                                reported,
+                            }
+                        }
+                    }
+                }
+                HandlerProj::Handler {
+                    fut,
+                    inner,
+                    opts,
+                    started_at,
+                    context,
+                    reported,
+                } => {
+                    let res = ready!(fut.poll(cx));
+                    let mut info = CallInfo::finish(*started_at, false, false, context);
+                    info.reported = *reported;
+                    return match res {
+                        Ok(res) => {
+                            let res = inner.post(res, &info);
+                            Poll::Ready(Ok(inner.finalize(res, &info)))
+                        }
+                        Err(err) => {
+                            opts.record(Outcome::Errored);
+                            inner.on_error(&err, &info);
+                            Poll::Ready(Err(err))
+                        }
+                    };
+                }
+                HandlerProj::ErrorHandler {
+                    delay,
+                    res,
+                    inner,
+                    opts,
+                    started_at,
+                    context,
+                } => {
+                    if let Some(delay) = delay.as_pin_mut() {
+                        ready!(delay.poll(cx));
+                    }
+                    let mut res = res.take().expect("HandlerFuture polled after completion");
+                    let info = CallInfo::finish(*started_at, false, true, context);
+                    if opts.post_on_short_circuit {
+                        res = inner.post(res, &info);
+                    }
+                    return Poll::Ready(Ok(inner.finalize(res, &info)));
+                }
+            };
+            self.set(next);
         }
     }
 }
 
+/// The client address resolved by a `ClientIpResolver` or `IpFilter` earlier in the chain,
+/// else the peer address. `Forwarded`/`X-Forwarded-For` are never believed on their own.
+#[cfg(any(
+    feature = "accesslog",
+    feature = "audit",
+    feature = "csrf",
+    feature = "experiment",
+    feature = "honeypot",
+    feature = "ratelimit"
+))]
+pub(crate) fn client_addr(req: &actix_web::HttpRequest) -> Option<std::net::IpAddr> {
+    #[cfg(feature = "client-ip")]
+    return client_ip::cached_or_peer(req);
+    #[cfg(not(feature = "client-ip"))]
+    req.peer_addr().map(|addr| addr.ip())
+}
+
 pub fn match_uri(test_uri: &str, check: &str) -> bool {
     if test_uri == check {
         return true;
     }
 
-    let mut check_with_right_boundary = check.to_string();
-    check_with_right_boundary.push('/');
-    return test_uri.starts_with(&check_with_right_boundary);
+    test_uri
+        .strip_prefix(check)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::{
+        body::BoxBody,
+        dev::{ServiceRequest, ServiceResponse},
+        guard,
+        http::header::{HeaderName, HeaderValue},
+        test, web, App, HttpResponse,
+    };
+    use futures_util::future::Either;
+
+    use super::{CallInfo, Chain, EnforcementMode, Factory, FromBoxBody, Handler};
+
+    /// Answers every request with a `403`.
+    struct Deny;
+
+    impl<B: FromBoxBody> Handler<B> for Deny {
+        fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+            let resp = req.into_response(HttpResponse::Forbidden().finish());
+            Either::Left(resp.map_body(|_, body| B::from_box_body(body)))
+        }
+
+        fn post(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+            if let Some(status) = info.reported {
+                let value = HeaderValue::from_str(status.as_str()).unwrap();
+                resp.headers_mut().insert(HeaderName::from_static("x-reported"), value);
+            }
+            resp
+        }
+    }
+
+    /// Forwards every request and appends its name to `x-tag` in `post`.
+    struct Tag(&'static str);
+
+    impl<B> Handler<B> for Tag {
+        fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+            Either::Right(req)
+        }
+
+        fn post(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+            let value = HeaderValue::from_static(self.0);
+            resp.headers_mut().append(HeaderName::from_static("x-tag"), value);
+            resp
+        }
+    }
+
+    /// Skips every request, yet would tag it in `post`.
+    struct Idle;
+
+    impl<B> Handler<B> for Idle {
+        fn skip(&self, _: &ServiceRequest) -> bool {
+            true
+        }
+
+        fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+            Either::Right(req)
+        }
+
+        fn post(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+            Tag("idle").post(resp, info)
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_enforcement() {
+        let echo = web::post().to(|body: String| async move { body });
+        let app = test::init_service(App::new().wrap(Factory::new(Deny)).route("/", echo)).await;
+        let resp = test::call_service(&app, test::TestRequest::post().set_payload("kept").to_request()).await;
+        assert_eq!(resp.status(), 403);
+
+        let echo = web::post().to(|body: String| async move { body });
+        let factory = Factory::new(Deny).enforcement(EnforcementMode::ReportOnly);
+        let app = test::init_service(App::new().wrap(factory).route("/", echo)).await;
+        let resp = test::call_service(&app, test::TestRequest::post().set_payload("kept").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-reported").unwrap(), "403");
+        assert_eq!(test::read_body(resp).await, "kept");
+    }
+
+    #[actix_web::test]
+    async fn test_guards() {
+        let factory = Factory::new(Deny)
+            .when(guard::Post())
+            .unless(guard::Header("x-internal", "1"));
+        let app = test::init_service(App::new().wrap(factory).default_service(web::to(HttpResponse::Ok))).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let resp = test::call_service(&app, test::TestRequest::post().to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let req = test::TestRequest::post().insert_header(("x-internal", "1")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_chain() {
+        let chain: Chain<BoxBody> = vec![Box::new(Tag("outer")), Box::new(Tag("inner"))];
+        let app = App::new().wrap(Factory::new(chain)).default_service(web::to(HttpResponse::Ok));
+        let app = test::init_service(app).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        let tags = resp.headers().get_all("x-tag").collect::<Vec<_>>();
+        assert_eq!(tags, ["inner", "outer"]);
+
+        // the first short-circuit wins, later handlers do not run
+        let chain: Chain<BoxBody> = vec![Box::new(Deny), Box::new(Tag("after"))];
+        let app = App::new().wrap(Factory::new(chain)).default_service(web::to(HttpResponse::Ok));
+        let app = test::init_service(app).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 403);
+        assert!(!resp.headers().contains_key("x-tag"));
+
+        // hooks only run for handlers that processed the request
+        let chain: Chain<BoxBody> = vec![
+            Box::new(Tag("outer")),
+            Box::new(Idle),
+            Box::new(Deny),
+            Box::new(Tag("after")),
+        ];
+        let factory = Factory::new(chain).post_on_short_circuit(true);
+        let app = test::init_service(App::new().wrap(factory).default_service(web::to(HttpResponse::Ok))).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 403);
+        assert_eq!(resp.headers().get_all("x-tag").collect::<Vec<_>>(), ["outer"]);
+    }
+
+    #[actix_web::test]
+    async fn test_reject_delay() {
+        let factory = Factory::new(Deny)
+            .when(guard::Post())
+            .reject_delay(Duration::from_millis(200));
+        let app = test::init_service(App::new().wrap(factory).default_service(web::to(HttpResponse::Ok))).await;
+
+        let start = Instant::now();
+        let resp = test::call_service(&app, test::TestRequest::post().to_request()).await;
+        assert_eq!(resp.status(), 403);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let start = Instant::now();
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
 }
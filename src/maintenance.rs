@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    future::ready,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, ContentType, HeaderValue},
+        Method,
+    },
+    web, HttpRequest, HttpResponse, ResponseError, Route,
+};
+use futures_util::future::Either;
+
+use crate::{FromBoxBody, Handler, MwError, SkipRule, SkipSet};
+
+/// Shared on/off switch; clones observe the same state.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceFlag(Arc<AtomicBool>);
+
+impl MaintenanceFlag {
+    pub fn new() -> Self {
+        MaintenanceFlag::default()
+    }
+
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Answers every non-exempt request with `503 Service Unavailable` while the flag is set.
+#[derive(Clone, Debug)]
+pub struct Maintenance {
+    flag: MaintenanceFlag,
+    exempt: SkipSet,
+    body: Option<(ContentType, String)>,
+    retry_after: Option<Duration>,
+}
+
+impl Maintenance {
+    pub fn new(flag: MaintenanceFlag) -> Self {
+        Maintenance {
+            flag,
+            exempt: SkipSet::new(),
+            body: None,
+            retry_after: None,
+        }
+    }
+
+    /// Requests matching `rule` pass through, e.g. health checks and the toggle route.
+    pub fn exempt(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.exempt.push(rule.into());
+        self
+    }
+
+    /// Replaces the `MwError` JSON error, e.g. with an HTML maintenance page.
+    pub fn with_body(mut self, content_type: ContentType, body: impl Into<String>) -> Self {
+        self.body = Some((content_type, body.into()));
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for Maintenance {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        !self.flag.is_enabled() || self.exempt.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let mut resp = match &self.body {
+            Some((content_type, body)) => HttpResponse::ServiceUnavailable()
+                .content_type(content_type.clone())
+                .body(body.clone()),
+            None => MwError::Unavailable("maintenance").error_response(),
+        };
+        if let Some(retry_after) = self.retry_after {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+        }
+        Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)))
+    }
+}
+
+/// A route reporting the flag as `{"maintenance": bool}`; `POST`/`PUT` with
+/// `?enabled=true|false` switch it first. Protect it, and exempt its path from `Maintenance`:
+///
+/// `App::new().route("/admin/maintenance", maintenance::toggle_route(&flag))`
+pub fn toggle_route(flag: &MaintenanceFlag) -> Route {
+    let flag = flag.clone();
+    web::route().to(move |req: HttpRequest| {
+        let mut resp = HttpResponse::Ok();
+        if req.method() == Method::POST || req.method() == Method::PUT {
+            let query = web::Query::<HashMap<String, String>>::from_query(req.query_string());
+            match query.ok().and_then(|q| q.get("enabled").map(|v| v.parse::<bool>())) {
+                Some(Ok(true)) => flag.enable(),
+                Some(Ok(false)) => flag.disable(),
+                _ => resp = HttpResponse::BadRequest(),
+            }
+        } else if req.method() != Method::GET {
+            resp = HttpResponse::MethodNotAllowed();
+        }
+
+        let body = format!(r#"{{"maintenance":{}}}"#, flag.is_enabled());
+        ready(resp.content_type(ContentType::json()).body(body))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{http::header, test, web, App, HttpResponse};
+
+    use super::{toggle_route, Maintenance, MaintenanceFlag};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_maintenance() {
+        let flag = MaintenanceFlag::new();
+        let maintenance = Maintenance::new(flag.clone())
+            .exempt("/admin")
+            .with_retry_after(Duration::from_secs(120));
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(maintenance))
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/admin/maintenance", toggle_route(&flag)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 200);
+
+        let req = test::TestRequest::post().uri("/admin/maintenance?enabled=true").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, r#"{"maintenance":true}"#);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "120");
+
+        let req = test::TestRequest::post().uri("/admin/maintenance?enabled=false").to_request();
+        test::call_service(&app, req).await;
+        assert!(!flag.is_enabled());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::Split,
+    sync::Arc,
+};
+
+use actix_web::{dev::ServiceRequest, http::Method};
+
+use crate::match_uri;
+
+/// An actix route template such as `/users/{id}/settings` or `/static/{tail}*`.
+///
+/// Handlers wrapped around a whole `App` or `Scope` run before routing, when
+/// `req.match_pattern()` is still `None`; the template is then matched against the path
+/// itself, with `{name}` standing for one segment and a trailing `{name}*` or `{name:.*}`
+/// for the rest. Custom segment regexes (`{id:\d+}`) are treated as plain parameters.
+/// Once the resource is known (handlers on a `Resource`, or `post`/`finalize`), the
+/// matched pattern is compared with the template instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchPattern(String);
+
+impl MatchPattern {
+    pub fn new(template: impl Into<String>) -> Self {
+        MatchPattern(template.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn matches(&self, req: &ServiceRequest) -> bool {
+        match req.match_pattern() {
+            Some(pattern) => pattern == self.0,
+            None => self.matches_path(req.path()),
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        let mut segments = path.trim_start_matches('/').split('/');
+        let mut parts = self.0.trim_start_matches('/').split('/').peekable();
+        while let Some(part) = parts.next() {
+            if is_param(part) && is_tail(part) && parts.peek().is_none() {
+                return true;
+            }
+            match segments.next() {
+                Some(segment) if is_param(part) => {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                }
+                Some(segment) if segment == part => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+type Predicate = dyn Fn(&ServiceRequest) -> bool + Send + Sync;
+
+/// Describes requests a handler should leave alone. Every configured part must match:
+/// a path prefix (see `match_uri`), one of the methods, and the predicate.
+#[derive(Clone, Default)]
+pub struct SkipRule {
+    path: Option<String>,
+    pattern: Option<MatchPattern>,
+    methods: Vec<Method>,
+    predicate: Option<Arc<Predicate>>,
+}
+
+impl SkipRule {
+    /// Matches `path` and everything below it, for any method.
+    pub fn path(path: impl Into<String>) -> Self {
+        SkipRule {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Matches requests for the route template, e.g. `/users/{id}/settings`; see
+    /// `MatchPattern`.
+    pub fn pattern(template: impl Into<String>) -> Self {
+        SkipRule {
+            pattern: Some(MatchPattern::new(template)),
+            ..Default::default()
+        }
+    }
+
+    /// Matches every path; narrow it with `method` or `when`.
+    pub fn any_path() -> Self {
+        SkipRule::default()
+    }
+
+    /// Restricts the rule to `method`; may be called several times.
+    pub fn method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    /// Restricts the rule to requests accepted by `predicate`, e.g. ones carrying a
+    /// webhook signature header.
+    pub fn when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn matches(&self, req: &ServiceRequest) -> bool {
+        if let Some(path) = &self.path {
+            if !match_uri(req.path(), path) {
+                return false;
+            }
+        }
+
+        if let Some(pattern) = &self.pattern {
+            if !pattern.matches(req) {
+                return false;
+            }
+        }
+
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return false;
+        }
+
+        match &self.predicate {
+            Some(predicate) => predicate(req),
+            None => true,
+        }
+    }
+}
+
+impl From<String> for SkipRule {
+    fn from(path: String) -> Self {
+        SkipRule::path(path)
+    }
+}
+
+impl From<&str> for SkipRule {
+    fn from(path: &str) -> Self {
+        SkipRule::path(path)
+    }
+}
+
+impl fmt::Debug for SkipRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipRule")
+            .field("path", &self.path)
+            .field("pattern", &self.pattern)
+            .field("methods", &self.methods)
+            .field("predicate", &self.predicate.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+fn is_param(part: &str) -> bool {
+    part.starts_with('{') && (part.ends_with('}') || part.ends_with("}*"))
+}
+
+fn is_tail(part: &str) -> bool {
+    part.ends_with("}*") || part.ends_with(":.*}")
+}
+
+/// A trie over path segments.
+#[derive(Clone, Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    param: Option<Box<Node>>,
+    /// A path prefix ends here: this path and everything below it match.
+    prefix: bool,
+    /// A template ends here.
+    exact: bool,
+    /// A template's trailing `{name}*` is here: everything below matches.
+    tail: bool,
+}
+
+impl Node {
+    fn insert_prefix(&mut self, path: &str) {
+        let mut node = self;
+        for segment in path.split('/') {
+            node = node.children.entry(segment.to_string()).or_default();
+        }
+        node.prefix = true;
+    }
+
+    /// Same result as `match_uri(path, prefix)` for each inserted prefix.
+    fn has_prefix_of(&self, path: &str) -> bool {
+        let mut node = self;
+        for segment in path.split('/') {
+            match node.children.get(segment) {
+                Some(child) => node = child,
+                None => return false,
+            }
+            if node.prefix {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn insert_template(&mut self, template: &str) {
+        let mut node = self;
+        let mut parts = template.trim_start_matches('/').split('/').peekable();
+        while let Some(part) = parts.next() {
+            if is_param(part) && is_tail(part) && parts.peek().is_none() {
+                node.tail = true;
+                return;
+            }
+            node = if is_param(part) {
+                node.param.get_or_insert_with(Default::default).as_mut()
+            } else {
+                node.children.entry(part.to_string()).or_default()
+            };
+        }
+        node.exact = true;
+    }
+
+    /// Same result as `MatchPattern::matches_path` for each inserted template.
+    fn has_template_for(&self, mut segments: Split<'_, char>) -> bool {
+        if self.tail {
+            return true;
+        }
+        let segment = match segments.next() {
+            Some(segment) => segment,
+            None => return self.exact,
+        };
+        if let Some(child) = self.children.get(segment) {
+            if child.has_template_for(segments.clone()) {
+                return true;
+            }
+        }
+        match &self.param {
+            Some(param) if !segment.is_empty() => param.has_template_for(segments),
+            _ => false,
+        }
+    }
+}
+
+/// A set of `SkipRule`s compiled for lookup, for handlers that check many rules on every
+/// request. Plain path prefixes and plain templates are looked up in segment tries
+/// without allocating; rules with methods or a predicate are still checked one by one.
+#[derive(Clone, Debug, Default)]
+pub struct SkipSet {
+    prefixes: Node,
+    templates: Node,
+    patterns: HashSet<String>,
+    rest: Vec<SkipRule>,
+    len: usize,
+}
+
+impl SkipSet {
+    pub fn new() -> Self {
+        SkipSet::default()
+    }
+
+    pub fn push(&mut self, rule: impl Into<SkipRule>) {
+        let rule = rule.into();
+        self.len += 1;
+        if !rule.methods.is_empty() || rule.predicate.is_some() {
+            self.rest.push(rule);
+            return;
+        }
+        match (&rule.path, &rule.pattern) {
+            (Some(path), None) => self.prefixes.insert_prefix(path),
+            (None, Some(pattern)) => {
+                self.templates.insert_template(pattern.as_str());
+                self.patterns.insert(pattern.as_str().to_string());
+            }
+            _ => self.rest.push(rule),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = SkipSet::default();
+    }
+
+    /// Whether any rule matches `req`.
+    pub fn matches(&self, req: &ServiceRequest) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        if self.prefixes.has_prefix_of(req.path()) {
+            return true;
+        }
+        let by_template = match req.match_pattern() {
+            Some(pattern) => self.patterns.contains(&pattern),
+            None => self.templates.has_template_for(req.path().trim_start_matches('/').split('/')),
+        };
+        by_template || self.rest.iter().any(|rule| rule.matches(req))
+    }
+}
+
+impl<R: Into<SkipRule>> FromIterator<R> for SkipSet {
+    fn from_iter<I: IntoIterator<Item = R>>(rules: I) -> Self {
+        let mut set = SkipSet::new();
+        set.extend(rules);
+        set
+    }
+}
+
+impl<R: Into<SkipRule>> Extend<R> for SkipSet {
+    fn extend<I: IntoIterator<Item = R>>(&mut self, rules: I) {
+        for rule in rules {
+            self.push(rule);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{MatchPattern, SkipRule, SkipSet};
+
+    #[test]
+    fn test_matches_path() {
+        let settings = MatchPattern::new("/users/{id}/settings");
+        assert!(settings.matches_path("/users/42/settings"));
+        assert!(!settings.matches_path("/users/42"));
+        assert!(!settings.matches_path("/users//settings"));
+        assert!(!settings.matches_path("/users/42/settings/extra"));
+
+        let tail = MatchPattern::new("/static/{tail}*");
+        assert!(tail.matches_path("/static/css/app.css"));
+        assert!(!tail.matches_path("/assets/app.css"));
+    }
+
+    #[test]
+    fn test_skip_set() {
+        let set = ["/health", "/static/"]
+            .into_iter()
+            .map(SkipRule::from)
+            .chain([SkipRule::pattern("/users/{id}/avatar"), SkipRule::pattern("/files/{tail}*")])
+            .collect::<SkipSet>();
+
+        for (path, expected) in [
+            ("/health", true),
+            ("/health/live", true),
+            ("/healthz", false),
+            ("/static/", true),
+            ("/static/app.css", false),
+            ("/users/42/avatar", true),
+            ("/users//avatar", false),
+            ("/files", true),
+            ("/files/a/b", true),
+            ("/", false),
+        ] {
+            let req = TestRequest::with_uri(path).to_srv_request();
+            assert_eq!(set.matches(&req), expected, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{fmt, future::ready};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    web, HttpMessage, HttpRequest, HttpResponse, Route,
+};
+use futures_util::future::Either;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::{CallInfo, Handler};
+
+/// Label for requests that matched no route, so unknown paths share one series.
+const UNMATCHED: &str = "<unmatched>";
+
+/// Held in the request extensions while the request is in flight; dropped in `finalize`,
+/// or with the request when the inner service fails.
+struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Records `http_requests_total`, `http_requests_in_flight` and
+/// `http_request_duration_seconds`, labeled by method, route pattern and status.
+///
+/// The route label is the pattern from `HttpRequest::match_pattern` (e.g. `/users/{id}`),
+/// never the raw path, so label cardinality stays bounded by the number of routes.
+/// Requests bypassing the handler through `Factory::when`/`unless` are counted but not
+/// reflected in the in-flight gauge.
+#[derive(Clone)]
+pub struct PrometheusMetrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    in_flight: IntGaugeVec,
+    duration: HistogramVec,
+}
+
+impl PrometheusMetrics {
+    /// Registers the metrics, prefixed with `namespace`, in a new registry.
+    pub fn new(namespace: &str) -> Self {
+        PrometheusMetrics::with_registry(namespace, Registry::new())
+    }
+
+    /// Registers the metrics in `registry`, e.g. one shared with application metrics.
+    ///
+    /// Panics if metrics with the same names are already registered.
+    pub fn with_registry(namespace: &str, registry: Registry) -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests").namespace(namespace),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let in_flight = IntGaugeVec::new(
+            Opts::new("http_requests_in_flight", "Number of HTTP requests in flight").namespace(namespace),
+            &["method"],
+        )
+        .unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency").namespace(namespace),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        PrometheusMetrics {
+            registry,
+            requests,
+            in_flight,
+            duration,
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// The registry in the text exposition format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusMetrics").finish_non_exhaustive()
+    }
+}
+
+impl<B> Handler<B> for PrometheusMetrics {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let gauge = self.in_flight.with_label_values(&[req.method().as_str()]);
+        gauge.inc();
+        req.extensions_mut().insert(InFlight(gauge));
+        Either::Right(req)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        let req = resp.request();
+        req.extensions_mut().remove::<InFlight>();
+
+        let route = req.match_pattern();
+        let labels = [
+            req.method().as_str(),
+            route.as_deref().unwrap_or(UNMATCHED),
+            resp.status().as_str(),
+        ];
+        self.requests.with_label_values(&labels).inc();
+        self.duration
+            .with_label_values(&labels)
+            .observe(info.elapsed.as_secs_f64());
+        resp
+    }
+}
+
+/// A route serving the metrics in the text exposition format:
+///
+/// `App::new().route("/metrics", metrics::metrics_route(&metrics))`
+pub fn metrics_route(metrics: &PrometheusMetrics) -> Route {
+    let metrics = metrics.clone();
+    web::get().to(move |_: HttpRequest| {
+        let body = metrics.render();
+        ready(
+            HttpResponse::Ok()
+                .content_type(prometheus::TEXT_FORMAT)
+                .body(body),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::{metrics_route, PrometheusMetrics};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let metrics = PrometheusMetrics::new("app");
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(metrics.clone()))
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+                .route("/metrics", metrics_route(&metrics)),
+        )
+        .await;
+
+        for uri in ["/users/1", "/users/2", "/missing"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let requests = |labels: &[&str]| metrics.requests.with_label_values(labels).get();
+        assert_eq!(requests(&["GET", "/users/{id}", "200"]), 2);
+        assert_eq!(requests(&["GET", "<unmatched>", "404"]), 1);
+        assert_eq!(metrics.in_flight.with_label_values(&["GET"]).get(), 0);
+
+        let body = test::call_and_read_body(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("app_http_requests_total"));
+    }
+}
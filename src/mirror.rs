@@ -0,0 +1,289 @@
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method,
+    },
+    rt,
+    web::{Bytes, BytesMut},
+};
+use futures_core::Stream;
+use futures_util::future::Either;
+
+use crate::{body::has_request_body, Handler, SkipRule, SkipSet};
+
+/// Headers that describe the connection to this server rather than the request.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+];
+
+/// A sampled request, waiting for its body before it is sent to the shadow.
+struct Shot {
+    client: awc::Client,
+    method: Method,
+    url: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Shot {
+    fn fire(self, body: Bytes) {
+        rt::spawn(async move {
+            let mut req = self.client.request(self.method, &self.url);
+            for (name, value) in self.headers {
+                req = req.append_header((name, value));
+            }
+            match req.send_body(body).await {
+                Ok(resp) if resp.status().is_server_error() => {
+                    log::debug!("shadow answered {} with {}", self.url, resp.status())
+                }
+                Ok(_) => {}
+                Err(err) => log::debug!("failed to mirror request to {}: {}", self.url, err),
+            }
+        });
+    }
+}
+
+/// The request payload, copied aside up to `max_body` bytes as the route reads it. The
+/// copy is mirrored once the payload ends; a payload that is not read to the end or
+/// passes the cap is not mirrored.
+struct Tee {
+    inner: Payload,
+    copy: BytesMut,
+    max_body: usize,
+    shot: Option<Shot>,
+}
+
+impl Stream for Tee {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let item = Pin::new(&mut this.inner).poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) if this.shot.is_some() => {
+                if this.copy.len() + chunk.len() > this.max_body {
+                    log::debug!("request body over {} bytes, not mirrored", this.max_body);
+                    this.shot = None;
+                    this.copy.clear();
+                } else {
+                    this.copy.extend_from_slice(chunk);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.shot = None,
+            Poll::Ready(None) => {
+                if let Some(shot) = this.shot.take() {
+                    shot.fire(this.copy.split().freeze());
+                }
+            }
+            _ => {}
+        }
+        item
+    }
+}
+
+/// Replays a sample of requests to a shadow deployment, e.g. a new backend under test,
+/// without waiting for it: the primary response is unaffected by the shadow's latency or
+/// failures, and the shadow's responses are discarded.
+///
+/// The mirrored request keeps the method, path, query and end-to-end headers, with
+/// `x-mirrored: true` added. Bodies are copied while the route reads them and the request
+/// is sent once the body is complete, so requests whose body the route does not read to
+/// the end, or that are larger than the cap, are not mirrored.
+///
+/// `awc::Client` is not `Send`: build `Mirror` inside the `HttpServer::new` closure.
+#[derive(Clone)]
+pub struct Mirror {
+    client: awc::Client,
+    base_url: String,
+    sample_every: u64,
+    counter: Arc<AtomicU64>,
+    max_body: usize,
+    exempt: SkipSet,
+}
+
+impl Mirror {
+    /// Mirrors every request to `base_url`, e.g. `http://shadow.internal:8080`; shadow
+    /// requests time out after 5 seconds.
+    pub fn new(base_url: &str) -> Self {
+        Mirror {
+            client: awc::Client::builder().timeout(Duration::from_secs(5)).finish(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            sample_every: 1,
+            counter: Arc::new(AtomicU64::new(0)),
+            max_body: 64 * 1024,
+            exempt: SkipSet::new(),
+        }
+    }
+
+    pub fn with_client(mut self, client: awc::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Mirror one request in `n`.
+    pub fn sample_every(mut self, n: u64) -> Self {
+        self.sample_every = n.max(1);
+        self
+    }
+
+    /// Requests with larger bodies are not mirrored; defaults to 64 KiB.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Requests matching `rule` are never mirrored, e.g. payments with side effects the
+    /// shadow cannot undo.
+    pub fn exempt(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.exempt.push(rule.into());
+        self
+    }
+
+    fn content_length(req: &ServiceRequest) -> Option<u64> {
+        req.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+    }
+
+    fn shot(&self, req: &ServiceRequest) -> Shot {
+        let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        let mut headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        headers.push((HeaderName::from_static("x-mirrored"), HeaderValue::from_static("true")));
+
+        Shot {
+            client: self.client.clone(),
+            method: req.method().clone(),
+            url: format!("{}{}", self.base_url, path_and_query),
+            headers,
+        }
+    }
+}
+
+impl fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirror")
+            .field("base_url", &self.base_url)
+            .field("sample_every", &self.sample_every)
+            .field("max_body", &self.max_body)
+            .field("exempt", &self.exempt)
+            .finish()
+    }
+}
+
+impl<B> Handler<B> for Mirror {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.exempt.matches(req)
+    }
+
+    fn process(&self, mut req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if self.counter.fetch_add(1, Ordering::Relaxed) % self.sample_every != 0 {
+            return Either::Right(req);
+        }
+
+        let shot = self.shot(&req);
+        if !has_request_body(&req) {
+            shot.fire(Bytes::new());
+            return Either::Right(req);
+        }
+        if Mirror::content_length(&req).map_or(false, |length| length > self.max_body as u64) {
+            return Either::Right(req);
+        }
+
+        let inner = req.take_payload();
+        req.set_payload(Payload::Stream {
+            payload: Box::pin(Tee {
+                inner,
+                copy: BytesMut::new(),
+                max_body: self.max_body,
+                shot: Some(shot),
+            }),
+        });
+        Either::Right(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use actix_web::{rt, test, web, App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::Mirror;
+    use crate::Factory;
+
+    type Seen = Arc<Mutex<Vec<(String, bool, web::Bytes)>>>;
+
+    #[actix_web::test]
+    async fn test_mirror() {
+        let seen = Seen::default();
+        let shadow_seen = seen.clone();
+        let shadow = HttpServer::new(move || {
+            let seen = shadow_seen.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+                let mirrored = req.headers().contains_key("x-mirrored");
+                seen.lock().unwrap().push((req.uri().to_string(), mirrored, body));
+                async { HttpResponse::Ok().finish() }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = shadow.addrs()[0];
+        let shadow = shadow.run();
+        let handle = shadow.handle();
+        rt::spawn(shadow);
+
+        let mirror = Mirror::new(&format!("http://{}", addr)).exempt("/pay");
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(mirror))
+                .default_service(web::to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) })),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/orders?id=1").set_payload("abc").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "abc");
+        let req = test::TestRequest::post().uri("/pay").set_payload("card").to_request();
+        test::call_service(&app, req).await;
+
+        for _ in 0..100 {
+            if !seen.lock().unwrap().is_empty() {
+                break;
+            }
+            rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        rt::time::sleep(Duration::from_millis(50)).await;
+        handle.stop(false).await;
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(*seen, vec![("/orders?id=1".to_string(), true, web::Bytes::from_static(b"abc"))]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{borrow::Cow, str::FromStr};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage,
+};
+use futures_util::future::Either;
+use opentelemetry::{
+    global,
+    trace::{
+        SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
+    },
+    Context, KeyValue,
+};
+
+use crate::{CallInfo, Handler};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// Parses a W3C `traceparent` (`00-<trace id>-<parent id>-<flags>`). Unknown future versions
+/// are read as version `00`, as the specification asks.
+fn parse_traceparent(value: &str, tracestate: Option<&str>) -> Option<SpanContext> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+
+    let trace_id = TraceId::from_hex(trace_id).ok().filter(|id| *id != TraceId::INVALID)?;
+    let span_id = SpanId::from_hex(span_id).ok().filter(|id| *id != SpanId::INVALID)?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    let state = tracestate
+        .and_then(|s| TraceState::from_str(s).ok())
+        .unwrap_or_default();
+    Some(SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::new(flags & TraceFlags::SAMPLED.to_u8()),
+        true,
+        state,
+    ))
+}
+
+fn format_traceparent(cx: &SpanContext) -> String {
+    format!(
+        "00-{}-{}-{:02x}",
+        cx.trace_id(),
+        cx.span_id(),
+        cx.trace_flags().to_u8()
+    )
+}
+
+/// The server span of a request. Ended in `finalize`, or, when the inner service fails and
+/// no response reaches `finalize`, with an error status when the request is dropped.
+struct ServerSpan {
+    cx: Context,
+    finished: bool,
+}
+
+impl Drop for ServerSpan {
+    fn drop(&mut self) {
+        let span = self.cx.span();
+        if !self.finished {
+            span.set_status(Status::error("inner service failed"));
+        }
+        span.end();
+    }
+}
+
+/// Continues the caller's trace: reads `traceparent`/`tracestate`, opens a server span
+/// around the inner call with the global tracer, and echoes the server span's context in
+/// the `traceparent` (and `tracestate`) response headers.
+///
+/// Route handlers find the server span's context as `OtelContext` in the request
+/// extensions, to parent their own spans on it.
+#[derive(Clone, Debug)]
+pub struct Otel {
+    tracer_name: Cow<'static, str>,
+    inject_response: bool,
+}
+
+/// The context of the request's server span, in the request extensions.
+#[derive(Clone, Debug)]
+pub struct OtelContext(pub Context);
+
+impl Otel {
+    pub fn new(tracer_name: impl Into<Cow<'static, str>>) -> Self {
+        Otel {
+            tracer_name: tracer_name.into(),
+            inject_response: true,
+        }
+    }
+
+    /// Whether to add `traceparent` to responses; on by default.
+    pub fn inject_response(mut self, inject: bool) -> Self {
+        self.inject_response = inject;
+        self
+    }
+
+    fn parent(req: &ServiceRequest) -> Context {
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+        match header(TRACEPARENT).and_then(|tp| parse_traceparent(tp, header(TRACESTATE))) {
+            Some(remote) => Context::new().with_remote_span_context(remote),
+            None => Context::new(),
+        }
+    }
+}
+
+impl<B> Handler<B> for Otel {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let parent = Otel::parent(&req);
+        let tracer = global::tracer(self.tracer_name.clone());
+        let span = tracer
+            // renamed to `<method> <route>` in `post`; raw paths would make names unbounded
+            .span_builder(req.method().to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.request.method", req.method().to_string()),
+                KeyValue::new("url.path", req.path().to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let mut ext = req.extensions_mut();
+        ext.insert(OtelContext(cx.clone()));
+        ext.insert(ServerSpan { cx, finished: false });
+        drop(ext);
+        Either::Right(req)
+    }
+
+    fn post(&self, resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        // the route is only known once the inner service has routed the request
+        if let Some(route) = resp.request().match_pattern() {
+            if let Some(server) = resp.request().extensions().get::<ServerSpan>() {
+                let span = server.cx.span();
+                span.update_name(format!("{} {}", resp.request().method(), route));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+        }
+        resp
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let server = resp.request().extensions_mut().remove::<ServerSpan>();
+        let mut server = match server {
+            Some(server) => server,
+            None => return resp,
+        };
+
+        let status = resp.status();
+        let span = server.cx.span();
+        span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+        if status.is_server_error() {
+            span.set_status(Status::error(status.to_string()));
+        }
+        server.finished = true;
+
+        let span_context = span.span_context().clone();
+        if self.inject_response && span_context.is_valid() {
+            let headers = resp.headers_mut();
+            if let Ok(value) = HeaderValue::from_str(&format_traceparent(&span_context)) {
+                headers.insert(HeaderName::from_static(TRACEPARENT), value);
+            }
+            let state = span_context.trace_state().header();
+            if !state.is_empty() {
+                if let Ok(value) = HeaderValue::from_str(&state) {
+                    headers.insert(HeaderName::from_static(TRACESTATE), value);
+                }
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_traceparent, parse_traceparent};
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let cx = parse_traceparent(value, Some("congo=t61rcWkgMzE")).unwrap();
+        assert!(cx.is_sampled());
+        assert!(cx.is_remote());
+        assert_eq!(format_traceparent(&cx), value);
+
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None).is_none());
+        assert!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra", None).is_none());
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra", None).is_some());
+    }
+}
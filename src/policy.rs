@@ -0,0 +1,253 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::rt;
+use futures_core::future::BoxFuture;
+
+use crate::StoreError;
+
+/// Who a policy applies to, e.g. the identity an API key resolved to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// Limits and permissions of one identity, e.g. from its SaaS plan. Unset fields fall back
+/// to the middleware's own configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// `(capacity, period)` for `RateLimit`.
+    pub rate_limit: Option<(u32, Duration)>,
+    /// Scopes granted to the identity; `None` grants every scope.
+    pub scopes: Option<HashSet<String>>,
+}
+
+impl Policy {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(scope))
+    }
+}
+
+/// Looks up the policy of an identity, e.g. in a tenants database.
+pub trait PolicyResolver: Send + Sync {
+    fn policy_for<'a>(&'a self, key: &'a Identity) -> BoxFuture<'a, Result<Policy, StoreError>>;
+}
+
+const DEFAULT_CAPACITY: usize = 10_000;
+const DEFAULT_MAX_LOADS: usize = 16;
+
+struct Entry {
+    policy: Option<Policy>,
+    fetched_at: Instant,
+    refreshing: bool,
+    /// Key of the entry in `Cache::order`.
+    used: u64,
+}
+
+/// The entries and the order they were last used in, least recent first.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Identity, Entry>,
+    order: BTreeMap<u64, Identity>,
+    clock: u64,
+}
+
+impl Cache {
+    /// The entry of `key`, marked as the most recently used. A new entry evicts the least
+    /// recently used ones once `capacity` is reached.
+    fn touch(&mut self, key: &Identity, capacity: usize, now: Instant) -> &mut Entry {
+        self.clock += 1;
+        let used = self.clock;
+        match self.entries.get(key) {
+            Some(entry) => {
+                self.order.remove(&entry.used);
+            }
+            None => {
+                while self.entries.len() >= capacity {
+                    match self.order.pop_first() {
+                        Some((_, oldest)) => self.entries.remove(&oldest),
+                        None => break,
+                    };
+                }
+                let entry = Entry {
+                    policy: None,
+                    fetched_at: now,
+                    refreshing: false,
+                    used,
+                };
+                self.entries.insert(key.clone(), entry);
+            }
+        }
+        self.order.insert(used, key.clone());
+
+        let entry = self.entries.get_mut(key).expect("entry was just inserted");
+        entry.used = used;
+        entry
+    }
+}
+
+/// Serves policies from memory for the synchronous `Handler::process`, and refreshes them
+/// from a `PolicyResolver` in the background once they are older than `ttl`. Until the
+/// first lookup of an identity finishes, `get` returns `None`; `load` fills the cache
+/// eagerly, e.g. for known tenants at startup. A failed refresh keeps the previous policy
+/// and is retried after another `ttl`.
+///
+/// At most `with_capacity` identities are kept, the least recently used is evicted first,
+/// and at most `with_max_loads` background lookups run at once; an unknown identity
+/// beyond that gets `None` without a lookup. Only pass authenticated identities.
+#[derive(Clone)]
+pub struct CachedPolicies {
+    resolver: Arc<dyn PolicyResolver>,
+    ttl: Duration,
+    capacity: usize,
+    max_loads: usize,
+    loads: Arc<AtomicUsize>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl CachedPolicies {
+    pub fn new<R: PolicyResolver + 'static>(resolver: R, ttl: Duration) -> Self {
+        CachedPolicies {
+            resolver: Arc::new(resolver),
+            ttl,
+            capacity: DEFAULT_CAPACITY,
+            max_loads: DEFAULT_MAX_LOADS,
+            loads: Arc::default(),
+            cache: Arc::default(),
+        }
+    }
+
+    /// Defaults to 10 000 identities.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Defaults to 16 lookups.
+    pub fn with_max_loads(mut self, max_loads: usize) -> Self {
+        self.max_loads = max_loads.max(1);
+        self
+    }
+
+    /// Takes one of the `max_loads` slots for a background lookup.
+    fn start_load(&self) -> bool {
+        self.loads
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < self.max_loads).then_some(n + 1))
+            .is_ok()
+    }
+
+    /// The cached policy of `key`, starting a background refresh when it is missing or
+    /// stale; must be called on the actix runtime.
+    pub fn get(&self, key: &Identity) -> Option<Policy> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        let known = cache.entries.contains_key(key);
+        if !known && !self.start_load() {
+            return None;
+        }
+
+        let entry = cache.touch(key, self.capacity, now);
+        let stale = entry.policy.is_none() || now.saturating_duration_since(entry.fetched_at) >= self.ttl;
+        if !known || (stale && !entry.refreshing && self.start_load()) {
+            entry.refreshing = true;
+            let policies = self.clone();
+            let key = key.clone();
+            rt::spawn(async move {
+                if let Err(err) = policies.load(&key).await {
+                    log::warn!("failed to refresh policy of {}: {}", key.0, err);
+                }
+                policies.loads.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+        entry.policy.clone()
+    }
+
+    /// Resolves `key` now and caches the result.
+    pub async fn load(&self, key: &Identity) -> Result<Policy, StoreError> {
+        let result = self.resolver.policy_for(key).await;
+
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.touch(key, self.capacity, Instant::now());
+        entry.refreshing = false;
+        entry.fetched_at = Instant::now();
+        if let Ok(policy) = &result {
+            entry.policy = Some(policy.clone());
+        }
+        result
+    }
+}
+
+impl fmt::Debug for CachedPolicies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedPolicies")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("max_loads", &self.max_loads)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_core::future::BoxFuture;
+
+    use super::{CachedPolicies, Identity, Policy, PolicyResolver};
+    use crate::StoreError;
+
+    struct Plans;
+
+    impl PolicyResolver for Plans {
+        fn policy_for<'a>(&'a self, key: &'a Identity) -> BoxFuture<'a, Result<Policy, StoreError>> {
+            Box::pin(async move {
+                match key.0.as_str() {
+                    "pro" => Ok(Policy {
+                        rate_limit: Some((1000, Duration::from_secs(60))),
+                        scopes: None,
+                    }),
+                    _ => Err(StoreError::new("unknown tenant")),
+                }
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_cached_policies() {
+        let policies = CachedPolicies::new(Plans, Duration::from_secs(60));
+        let pro = Identity("pro".to_string());
+        assert_eq!(policies.get(&pro), None);
+
+        policies.load(&pro).await.unwrap();
+        assert_eq!(policies.get(&pro).unwrap().rate_limit, Some((1000, Duration::from_secs(60))));
+        assert!(policies.load(&Identity("free".to_string())).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_bounded() {
+        let policies = CachedPolicies::new(Plans, Duration::from_secs(60))
+            .with_capacity(2)
+            .with_max_loads(1);
+        let pro = Identity("pro".to_string());
+        policies.load(&pro).await.unwrap();
+
+        // one lookup in flight: the next unknown identity is not even cached
+        assert_eq!(policies.get(&Identity("a".to_string())), None);
+        assert_eq!(policies.get(&Identity("b".to_string())), None);
+        assert_eq!(policies.cache.lock().unwrap().entries.len(), 2);
+
+        actix_web::rt::task::yield_now().await;
+        assert_eq!(policies.loads.load(std::sync::atomic::Ordering::Acquire), 0);
+        assert!(policies.get(&pro).is_some());
+        assert_eq!(policies.get(&Identity("c".to_string())), None);
+        let cache = policies.cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.entries.contains_key(&pro));
+        assert!(!cache.entries.contains_key(&Identity("a".to_string())));
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    http::StatusCode,
+    web::{Bytes, BytesMut},
+};
+use futures_core::Stream;
+use futures_util::StreamExt;
+
+use crate::MwError;
+
+/// Reads the request body, up to `limit` bytes, ahead of the inner service, which then gets
+/// the buffered copy; for `Handler::verify` checks over the body.
+pub(crate) fn prefetch(req: &mut ServiceRequest, limit: usize) -> impl Future<Output = Result<Bytes, MwError>> {
+    let mut payload = req.take_payload();
+    let slot = Rc::new(RefCell::new(None));
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(Prefetched(slot.clone())),
+    });
+
+    async move {
+        let mut body = BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|_| MwError::Rejected(StatusCode::BAD_REQUEST, "invalid_body"))?;
+            if body.len() + chunk.len() > limit {
+                return Err(MwError::PayloadTooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = body.freeze();
+        *slot.borrow_mut() = Some(body.clone());
+        Ok(body)
+    }
+}
+
+/// The body read by `prefetch`, handed out as a single chunk.
+struct Prefetched(Rc<RefCell<Option<Bytes>>>);
+
+impl Stream for Prefetched {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.0.borrow_mut().take().filter(|body| !body.is_empty()).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test::TestRequest, web::Bytes};
+    use futures_util::StreamExt;
+
+    use super::prefetch;
+    use crate::MwError;
+
+    #[actix_web::test]
+    async fn test_prefetch() {
+        let mut req = TestRequest::post().set_payload("signed body").to_srv_request();
+        let body = prefetch(&mut req, 64).await.unwrap();
+        assert_eq!(body, "signed body");
+        let chunk = req.take_payload().next().await.unwrap().unwrap();
+        assert_eq!(chunk, Bytes::from_static(b"signed body"));
+
+        let mut req = TestRequest::post().set_payload("too long").to_srv_request();
+        assert!(matches!(prefetch(&mut req, 4).await, Err(MwError::PayloadTooLarge)));
+    }
+}
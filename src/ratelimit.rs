@@ -0,0 +1,420 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    HttpMessage, HttpResponse, ResponseError,
+};
+use futures_util::future::Either;
+
+use crate::{
+    CachedPolicies, CallInfo, EnforcementMode, FromBoxBody, Handler, Identity, MwContext, MwError, Store, Verdict,
+};
+
+const LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Stale buckets are swept whenever a shard grows by this many keys.
+const SWEEP_EVERY: usize = 1024;
+
+type KeyFn = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
+
+/// Where the bucket key of a request comes from. Requests without a key are not limited.
+#[derive(Clone)]
+pub enum KeySource {
+    /// The address of the connected peer.
+    PeerIp,
+    /// The `ClientIp` cached by a `ClientIpResolver` or `IpFilter` earlier in the chain,
+    /// else the peer address; forwarding headers are not read without a resolver.
+    ClientIp,
+    Header(HeaderName),
+    /// The principal in `MwContext`, e.g. the identity of an API key checked earlier in
+    /// the chain.
+    Principal,
+    Custom(Arc<KeyFn>),
+}
+
+impl KeySource {
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            KeySource::PeerIp => req.peer_addr().map(|addr| addr.ip().to_string()),
+            KeySource::ClientIp => crate::client_addr(req.request()).map(|ip| ip.to_string()),
+            KeySource::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            KeySource::Principal => MwContext::of(req).principal(),
+            KeySource::Custom(f) => f(req),
+        }
+    }
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::PeerIp => f.write_str("PeerIp"),
+            KeySource::ClientIp => f.write_str("ClientIp"),
+            KeySource::Header(name) => f.debug_tuple("Header").field(name).finish(),
+            KeySource::Principal => f.write_str("Principal"),
+            KeySource::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Result of taking one token from a bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the next token when denied, until the bucket is full otherwise.
+    pub reset: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Process-local buckets, sharded to reduce lock contention between workers.
+#[derive(Debug)]
+pub struct MemoryStore {
+    shards: Vec<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl MemoryStore {
+    pub fn new(shards: usize) -> Self {
+        MemoryStore {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, Bucket>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Refills the bucket of `key` at `rate` tokens per second and takes one token.
+    pub fn take(&self, key: &str, capacity: u32, rate: f64, now: Instant) -> Decision {
+        let capacity_f = f64::from(capacity);
+        let mut shard = self.shard(key).lock().unwrap();
+
+        if !shard.contains_key(key) && shard.len() % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            // a bucket that would be full again holds no state worth keeping
+            shard.retain(|_, b| b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * rate < capacity_f);
+        }
+
+        let bucket = shard.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity_f,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity_f);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let missing = if allowed { capacity_f - bucket.tokens } else { 1.0 - bucket.tokens };
+        Decision {
+            allowed,
+            limit: capacity,
+            remaining: bucket.tokens as u32,
+            reset: Duration::from_secs_f64(missing / rate),
+        }
+    }
+}
+
+/// The bucket key and limits of a request, counted in `verify` against the shared store.
+struct Pending {
+    key: String,
+    capacity: u32,
+    period: Duration,
+}
+
+/// Token-bucket rate limiting: each key may burst up to `capacity` requests, and regains
+/// `capacity` tokens every `period`. Rejected requests get `429 Too Many Requests` with
+/// `Retry-After`; every limited response carries `X-RateLimit-*` headers.
+///
+/// With `with_shared_store`, requests are counted in a `Store` instead, e.g. a `RedisStore`
+/// shared by every instance: at most `capacity` requests per fixed window of `period`.
+///
+/// With `with_policies`, the authenticated principal in `MwContext` is looked up as an
+/// `Identity` and its `Policy::rate_limit` replaces the defaults, e.g. for per-plan quotas.
+/// Bucket keys are never looked up, since clients can make them up.
+#[derive(Clone)]
+pub struct RateLimit {
+    source: KeySource,
+    capacity: u32,
+    period: Duration,
+    store: Arc<MemoryStore>,
+    shared: Option<Arc<dyn Store>>,
+    policies: Option<CachedPolicies>,
+    enforcement: EnforcementMode,
+}
+
+impl RateLimit {
+    /// Panics if `capacity` or `period` is zero.
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        assert!(capacity > 0 && !period.is_zero(), "rate limit capacity and period must be positive");
+        RateLimit {
+            source: KeySource::PeerIp,
+            capacity,
+            period,
+            store: Arc::new(MemoryStore::new(16)),
+            shared: None,
+            policies: None,
+            enforcement: EnforcementMode::Enforce,
+        }
+    }
+
+    pub fn with_key(mut self, source: KeySource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Replaces the store, e.g. to share buckets between several `RateLimit`s.
+    pub fn with_store(mut self, store: Arc<MemoryStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Counts requests in `store` under `ratelimit:<key>:<window>` keys with `Store::incr`;
+    /// the local buckets of `with_store` are not used. Store failures answer `503`.
+    pub fn with_shared_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.shared = Some(store);
+        self
+    }
+
+    /// Per-principal limits; anonymous callers and principals without a (cached) policy get
+    /// the defaults.
+    pub fn with_policies(mut self, policies: CachedPolicies) -> Self {
+        self.policies = Some(policies);
+        self
+    }
+
+    /// With `EnforcementMode::ReportOnly`, keys over their limit are logged and let through;
+    /// tokens are still taken, so the log shows what enforcing would reject.
+    pub fn with_enforcement(mut self, mode: EnforcementMode) -> Self {
+        self.enforcement = mode;
+        self
+    }
+
+    /// `(capacity, period)` for the caller of `req`.
+    fn limits(&self, req: &ServiceRequest) -> (u32, Duration) {
+        let policy = match (&self.policies, MwContext::of(req).principal()) {
+            (Some(policies), Some(principal)) => policies.get(&Identity(principal)),
+            _ => None,
+        };
+        match policy.and_then(|policy| policy.rate_limit) {
+            Some((capacity, period)) if capacity > 0 && !period.is_zero() => (capacity, period),
+            _ => (self.capacity, self.period),
+        }
+    }
+
+    /// Lets an allowed request through with its decision, else answers or reports it.
+    fn decide<B: FromBoxBody>(
+        &self,
+        req: ServiceRequest,
+        key: &str,
+        decision: Decision,
+    ) -> Result<ServiceRequest, ServiceResponse<B>> {
+        if decision.allowed {
+            req.extensions_mut().insert(decision);
+            return Ok(req);
+        }
+        if !self.enforcement.is_enforced() {
+            log::warn!("rate limit of {} would reject {} {}", key, req.method(), req.path());
+            return Ok(req);
+        }
+        Err(req.into_response(rejection(&decision)).map_body(|_, body| B::from_box_body(body)))
+    }
+}
+
+fn rejection(decision: &Decision) -> HttpResponse {
+    let mut resp = MwError::RateLimited.error_response();
+    set_headers(resp.headers_mut(), decision);
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(decision.reset.as_secs_f64().ceil() as u64));
+    resp
+}
+
+/// Counts one request of `key` in the current window of `period`.
+async fn count(store: &dyn Store, key: &str, capacity: u32, period: Duration) -> Result<Decision, MwError> {
+    let period_ms = period.as_millis().max(1);
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let window = now_ms / period_ms;
+    let reset = Duration::from_millis(((window + 1) * period_ms - now_ms) as u64);
+
+    let count = store
+        .incr(&format!("ratelimit:{}:{}", key, window), 1, Some(reset))
+        .await
+        .map_err(MwError::Store)?;
+    Ok(Decision {
+        allowed: count <= i64::from(capacity),
+        limit: capacity,
+        remaining: (i64::from(capacity) - count).max(0) as u32,
+        reset,
+    })
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("source", &self.source)
+            .field("capacity", &self.capacity)
+            .field("period", &self.period)
+            .field("shared", &self.shared.is_some())
+            .field("policies", &self.policies)
+            .field("enforcement", &self.enforcement)
+            .finish()
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RESET, HeaderValue::from(decision.reset.as_secs_f64().ceil() as u64));
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for RateLimit {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let key = match self.source.extract(&req) {
+            Some(key) => key,
+            None => return Either::Right(req),
+        };
+
+        let (capacity, period) = self.limits(&req);
+        if self.shared.is_some() {
+            req.extensions_mut().insert(Pending { key, capacity, period });
+            return Either::Right(req);
+        }
+
+        let rate = f64::from(capacity) / period.as_secs_f64();
+        let decision = self.store.take(&key, capacity, rate, Instant::now());
+        match self.decide(req, &key, decision) {
+            Ok(req) => Either::Right(req),
+            Err(resp) => Either::Left(resp),
+        }
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let pending = req.extensions_mut().remove::<Pending>()?;
+        let store = self.shared.clone()?;
+        let limit = self.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            let decision = match count(&*store, &pending.key, pending.capacity, pending.period).await {
+                Ok(decision) => decision,
+                Err(err) => return Err(err.respond(req)),
+            };
+            limit.decide(ServiceRequest::from_request(req), &pending.key, decision).map(drop)
+        }))
+    }
+
+    fn post(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let decision = resp.request().extensions().get::<Decision>().copied();
+        if let Some(decision) = decision {
+            set_headers(resp.headers_mut(), &decision);
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::MemoryStore;
+
+    #[test]
+    fn test_token_bucket() {
+        let store = MemoryStore::new(4);
+        let now = Instant::now();
+
+        for remaining in (0..3).rev() {
+            let decision = store.take("a", 3, 1.0, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        let denied = store.take("a", 3, 1.0, now);
+        assert!(!denied.allowed);
+        assert_eq!(denied.reset, Duration::from_secs(1));
+        assert!(store.take("b", 3, 1.0, now).allowed);
+
+        assert!(store.take("a", 3, 1.0, now + Duration::from_secs(1)).allowed);
+    }
+
+    #[actix_web::test]
+    async fn test_client_ip() {
+        use actix_web::{test, web, App, HttpResponse};
+
+        use super::{KeySource, RateLimit};
+        use crate::Factory;
+
+        let limit = RateLimit::new(1, Duration::from_secs(3600)).with_key(KeySource::ClientIp);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(limit))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // without a resolver, a made-up X-Forwarded-For does not buy a fresh bucket
+        for (forwarded, status) in [("203.0.113.1", 200), ("203.0.113.2", 429)] {
+            let req = test::TestRequest::get()
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .insert_header(("x-forwarded-for", forwarded))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_shared_store() {
+        use std::sync::Arc;
+
+        use actix_web::{test, web, App, HttpResponse};
+
+        use super::{KeySource, RateLimit};
+        use crate::{Factory, InMemoryStore};
+
+        let store = Arc::new(InMemoryStore::new());
+        let limit = RateLimit::new(2, Duration::from_secs(3600))
+            .with_key(KeySource::Header("x-user".parse().unwrap()))
+            .with_shared_store(store);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(limit))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in ["1", "0"] {
+            let req = test::TestRequest::get().insert_header(("x-user", "a")).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("x-ratelimit-remaining").unwrap(), remaining);
+        }
+        let req = test::TestRequest::get().insert_header(("x-user", "a")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("retry-after"));
+
+        let req = test::TestRequest::get().insert_header(("x-user", "b")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    HttpResponse,
+};
+use futures_util::future::Either;
+
+use crate::{FromBoxBody, Handler, SkipRule, SkipSet};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Leave paths alone.
+    #[default]
+    Keep,
+    /// `/docs` -> `/docs/`.
+    Add,
+    /// `/docs/` -> `/docs`; `/` is left alone.
+    Strip,
+}
+
+/// Redirects to the canonical URL of a request: HTTPS, the canonical host and the
+/// canonical trailing slash, in one hop. `GET` and `HEAD` get `301 Moved Permanently`,
+/// other methods `308 Permanent Redirect` so clients repeat them with the same body.
+///
+/// The scheme comes from `ConnectionInfo`, which trusts `Forwarded` and
+/// `X-Forwarded-Proto`; only use `https` behind a proxy that sets them. The host is the
+/// `Host` header (or the HTTP/2 authority), falling back to the server's configured host;
+/// `X-Forwarded-Host` is ignored, so clients cannot choose where they are sent. A redirect
+/// to the request's own URL, or through a cycle in the host map, is never sent.
+#[derive(Clone, Debug, Default)]
+pub struct Redirect {
+    https: bool,
+    trailing_slash: TrailingSlash,
+    hosts: HashMap<String, String>,
+    exempt: SkipSet,
+}
+
+impl Redirect {
+    pub fn new() -> Self {
+        Redirect::default()
+    }
+
+    pub fn https(mut self, https: bool) -> Self {
+        self.https = https;
+        self
+    }
+
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    /// Requests for `from` go to `to`, e.g. `example.com` -> `www.example.com`. Hosts are
+    /// compared case-insensitively and without port. Chains are followed to their end in
+    /// one hop.
+    pub fn host(mut self, from: &str, to: &str) -> Self {
+        self.hosts.insert(from.to_ascii_lowercase(), to.to_ascii_lowercase());
+        self
+    }
+
+    /// Requests matching `rule` are never redirected, e.g. health checks probed over HTTP.
+    pub fn exempt(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.exempt.push(rule.into());
+        self
+    }
+
+    fn canonical_host(&self, host: &str) -> Option<String> {
+        let (name, port) = match host.rsplit_once(':') {
+            // not an IPv6 literal without port
+            Some((name, port)) if !port.contains(']') => (name, Some(port)),
+            _ => (host, None),
+        };
+
+        let name = name.to_ascii_lowercase();
+        let mut to = self.hosts.get(&name)?;
+        let mut visited = HashSet::from([&name]);
+        while let Some(next) = self.hosts.get(to) {
+            if !visited.insert(to) {
+                log::warn!("redirect host map has a cycle through {}", to);
+                return None;
+            }
+            to = next;
+        }
+        Some(match port {
+            Some(port) => format!("{}:{}", to, port),
+            None => to.clone(),
+        })
+    }
+
+    fn canonical_path(&self, path: &str) -> Option<String> {
+        match self.trailing_slash {
+            TrailingSlash::Add if !path.ends_with('/') => Some(format!("{}/", path)),
+            TrailingSlash::Strip if path.len() > 1 && path.ends_with('/') => {
+                let stripped = path.trim_end_matches('/');
+                Some(if stripped.is_empty() { "/".to_string() } else { stripped.to_string() })
+            }
+            _ => None,
+        }
+    }
+
+    /// `Host` (or the HTTP/2 authority), never a forwarded host.
+    fn request_host(req: &ServiceRequest) -> String {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+            .unwrap_or_else(|| req.app_config().host())
+            .to_string()
+    }
+
+    fn location(&self, req: &ServiceRequest) -> Option<String> {
+        let info = req.connection_info();
+        let scheme = info.scheme();
+        let host = Self::request_host(req);
+        let host = host.as_str();
+
+        let new_scheme = (self.https && scheme == "http").then_some("https");
+        let new_host = self.canonical_host(host);
+        let new_path = self.canonical_path(req.path());
+        if new_scheme.is_none() && new_host.is_none() && new_path.is_none() {
+            return None;
+        }
+
+        let mut location = format!(
+            "{}://{}{}",
+            new_scheme.unwrap_or(scheme),
+            new_host.as_deref().unwrap_or(host),
+            new_path.as_deref().unwrap_or(req.path())
+        );
+        if !req.query_string().is_empty() {
+            location.push('?');
+            location.push_str(req.query_string());
+        }
+
+        let target = req.uri().path_and_query().map_or(req.path(), |pq| pq.as_str());
+        let current = format!("{}://{}{}", scheme, host, target);
+        (location != current).then_some(location)
+    }
+}
+
+impl<B: FromBoxBody> Handler<B> for Redirect {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.exempt.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let location = match self.location(&req) {
+            Some(location) => location,
+            None => return Either::Right(req),
+        };
+
+        let mut resp = if req.method() == Method::GET || req.method() == Method::HEAD {
+            HttpResponse::MovedPermanently()
+        } else {
+            HttpResponse::PermanentRedirect()
+        };
+        let resp = resp.insert_header((header::LOCATION, location)).finish();
+        Either::Left(req.into_response(resp).map_body(|_, body| B::from_box_body(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test::TestRequest, web, App, HttpResponse};
+
+    use super::{Redirect, TrailingSlash};
+    use crate::Factory;
+
+    #[test]
+    fn test_canonical_host() {
+        let redirect = Redirect::new()
+            .host("example.com", "example.net")
+            .host("example.net", "www.example.net")
+            .host("a.test", "b.test")
+            .host("b.test", "c.test")
+            .host("c.test", "a.test");
+        assert_eq!(redirect.canonical_host("Example.com:8080").unwrap(), "www.example.net:8080");
+        assert!(redirect.canonical_host("www.example.net").is_none());
+        assert!(redirect.canonical_host("a.test").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_redirect() {
+        use actix_web::test;
+
+        let redirect = Redirect::new()
+            .host("example.com", "www.example.com")
+            .trailing_slash(TrailingSlash::Strip);
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(redirect))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/docs/?page=2")
+            .insert_header((header::HOST, "example.com"))
+            .insert_header(("x-forwarded-host", "evil.example"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "http://www.example.com/docs?page=2");
+
+        let req = TestRequest::post()
+            .uri("/docs/")
+            .insert_header((header::HOST, "www.example.com"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 308);
+
+        let req = TestRequest::get()
+            .uri("/docs")
+            .insert_header((header::HOST, "www.example.com"))
+            .insert_header(("x-forwarded-host", "example.com"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
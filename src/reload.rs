@@ -0,0 +1,160 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Factory, Handler, MwContext, Verdict};
+
+/// A shared, swappable handler. Clones point at the same slot, so a handle kept by the
+/// application (e.g. in a SIGHUP listener) updates every worker at once.
+pub struct ConfigHandle<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for ConfigHandle<T> {
+    fn clone(&self) -> Self {
+        ConfigHandle {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<T> ConfigHandle<T> {
+    pub fn new(h: T) -> Self {
+        ConfigHandle {
+            current: Arc::new(RwLock::new(Arc::new(h))),
+        }
+    }
+
+    /// The handler in effect right now.
+    pub fn load(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Atomically replaces the handler; in-flight requests finish with the one they started with.
+    pub fn store(&self, h: T) {
+        let h = Arc::new(h);
+        match self.current.write() {
+            Ok(mut current) => *current = h,
+            Err(poisoned) => *poisoned.into_inner() = h,
+        }
+    }
+}
+
+impl<T: 'static> ConfigHandle<T> {
+    /// The handler the request of `context` started with, loaded once per request so that
+    /// every hook sees the same one.
+    fn pinned(&self, context: &MwContext) -> Arc<T> {
+        context.pin(Arc::as_ptr(&self.current) as usize, || self.load())
+    }
+}
+
+impl<T, B> Handler<B> for ConfigHandle<T>
+where
+    T: Handler<B> + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.load().name()
+    }
+
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.pinned(&MwContext::of(req)).skip(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        self.pinned(&MwContext::of(&req)).process(req)
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        self.pinned(&MwContext::of(req)).verify(req)
+    }
+
+    fn post(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        self.pinned(&info.context).post(resp, info)
+    }
+
+    fn finalize(&self, resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        self.pinned(&info.context).finalize(resp, info)
+    }
+
+    fn on_error(&self, err: &Error, info: &CallInfo) {
+        self.pinned(&info.context).on_error(err, info)
+    }
+
+    fn timeout(&self, req: &ServiceRequest) -> Option<Duration> {
+        self.pinned(&MwContext::of(req)).timeout(req)
+    }
+
+    fn on_timeout(&self, context: &MwContext) -> Error {
+        self.pinned(context).on_timeout(context)
+    }
+}
+
+pub type ReloadableFactory<T, B> = Factory<ConfigHandle<T>, B>;
+
+impl<T, B> Factory<ConfigHandle<T>, B>
+where
+    T: Handler<B> + 'static,
+{
+    /// Builds a factory around `handle`. Pass clones of the same handle to every worker.
+    pub fn reloadable(handle: ConfigHandle<T>) -> Self {
+        Factory::new(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::{ServiceRequest, ServiceResponse},
+        http::header::{HeaderName, HeaderValue},
+        test, web, App, HttpResponse,
+    };
+    use futures_util::future::Either;
+
+    use super::ConfigHandle;
+    use crate::{CallInfo, Factory, Handler};
+
+    struct Tag(&'static str);
+
+    impl<B> Handler<B> for Tag {
+        fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+            Either::Right(req)
+        }
+
+        fn post(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+            let value = HeaderValue::from_static(self.0);
+            resp.headers_mut().insert(HeaderName::from_static("x-tag"), value);
+            resp
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_store_mid_request() {
+        let handle = ConfigHandle::new(Tag("old"));
+        let swap = handle.clone();
+        let app = test::init_service(App::new().wrap(Factory::reloadable(handle)).route(
+            "/",
+            web::get().to(move || {
+                swap.store(Tag("new"));
+                async { HttpResponse::Ok().finish() }
+            }),
+        ))
+        .await;
+
+        // the request that swapped the handler finishes with the one it started with
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.headers().get("x-tag").unwrap(), "old");
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.headers().get("x-tag").unwrap(), "new");
+    }
+}
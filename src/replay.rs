@@ -0,0 +1,214 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::HeaderName,
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+
+use crate::{FromBoxBody, Handler, MwError, SkipRule, SkipSet, Store, Verdict};
+
+const MAX_NONCE_LEN: usize = 128;
+
+/// A nonce and timestamp that passed `process`, waiting for `verify` to claim the nonce.
+struct Pending {
+    key: String,
+    nonce: String,
+    window: Duration,
+}
+
+/// The request's nonce, claimed in the `Store`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Nonce(pub String);
+
+impl FromRequest for Nonce {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Nonce>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("Replay guard").into()),
+        )
+    }
+}
+
+/// Requires a nonce and a Unix timestamp header on every request, and accepts each nonce
+/// at most once within the window. `process` rejects a missing nonce or a timestamp outside
+/// the allowed skew with `401`; `verify` claims the nonce in the `Store` and rejects one
+/// seen before with `409 Conflict`, before the route runs.
+#[derive(Clone)]
+pub struct ReplayGuard {
+    store: Arc<dyn Store>,
+    nonce_header: HeaderName,
+    timestamp_header: HeaderName,
+    max_skew: Duration,
+    window: Option<Duration>,
+    prefix: String,
+    skip_rules: SkipSet,
+}
+
+impl ReplayGuard {
+    pub fn new<S: Store + 'static>(store: S) -> Self {
+        ReplayGuard::with_store(Arc::new(store))
+    }
+
+    /// Shares `store` with other middlewares.
+    pub fn with_store(store: Arc<dyn Store>) -> Self {
+        ReplayGuard {
+            store,
+            nonce_header: HeaderName::from_static("x-nonce"),
+            timestamp_header: HeaderName::from_static("x-timestamp"),
+            max_skew: Duration::from_secs(300),
+            window: None,
+            prefix: "replay:".to_string(),
+            skip_rules: SkipSet::new(),
+        }
+    }
+
+    pub fn with_nonce_header(mut self, name: HeaderName) -> Self {
+        self.nonce_header = name;
+        self
+    }
+
+    pub fn with_timestamp_header(mut self, name: HeaderName) -> Self {
+        self.timestamp_header = name;
+        self
+    }
+
+    /// How far the timestamp may be from the local clock; defaults to 5 minutes.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// How long a nonce is remembered; defaults to twice the skew, so a nonce cannot
+    /// expire while its timestamp is still accepted.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Prefix of the store keys; defaults to `replay:`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn with_skip_rule(mut self, rule: SkipRule) -> Self {
+        self.skip_rules.push(rule);
+        self
+    }
+
+    fn check(&self, req: &ServiceRequest) -> Result<Pending, &'static str> {
+        let nonce = req
+            .headers()
+            .get(&self.nonce_header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|n| !n.is_empty() && n.len() <= MAX_NONCE_LEN)
+            .ok_or("missing_nonce")?;
+        let timestamp = req
+            .headers()
+            .get(&self.timestamp_header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .ok_or("missing_timestamp")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.abs_diff(timestamp) > self.max_skew.as_secs() {
+            return Err("stale_timestamp");
+        }
+
+        Ok(Pending {
+            key: format!("{}{}", self.prefix, nonce),
+            nonce: nonce.to_string(),
+            window: self.window.unwrap_or(self.max_skew * 2),
+        })
+    }
+}
+
+impl fmt::Debug for ReplayGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayGuard")
+            .field("nonce_header", &self.nonce_header)
+            .field("timestamp_header", &self.timestamp_header)
+            .field("max_skew", &self.max_skew)
+            .field("window", &self.window)
+            .field("prefix", &self.prefix)
+            .field("skip_rules", &self.skip_rules)
+            .finish()
+    }
+}
+
+impl<B: FromBoxBody + 'static> Handler<B> for ReplayGuard {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.skip_rules.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        match self.check(&req) {
+            Ok(pending) => {
+                req.extensions_mut().insert(pending);
+                Either::Right(req)
+            }
+            Err(reason) => Either::Left(MwError::Unauthorized(reason).reject(req)),
+        }
+    }
+
+    fn verify(&self, req: &mut ServiceRequest) -> Option<Verdict<B>> {
+        let pending = req.extensions_mut().remove::<Pending>()?;
+        let store = self.store.clone();
+        let req = req.request().clone();
+        Some(Box::pin(async move {
+            match store.incr(&pending.key, 1, Some(pending.window)).await {
+                Ok(1) => {
+                    req.extensions_mut().insert(Nonce(pending.nonce));
+                    Ok(())
+                }
+                Ok(_) => Err(MwError::Conflict("replayed_nonce").respond(req)),
+                Err(err) => Err(MwError::Store(err).respond(req)),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    use super::ReplayGuard;
+    use crate::{Factory, InMemoryStore};
+
+    #[actix_web::test]
+    async fn test_replay_guard() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(ReplayGuard::new(InMemoryStore::new())))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let post = |nonce: &str, timestamp: u64| {
+            test::TestRequest::post()
+                .insert_header(("x-nonce", nonce))
+                .insert_header(("x-timestamp", timestamp.to_string()))
+                .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, post("n1", now)).await.status(), StatusCode::OK);
+        // the route does not extract `Nonce`, so only the middleware can catch the replay
+        assert_eq!(test::call_service(&app, post("n1", now)).await.status(), StatusCode::CONFLICT);
+        assert_eq!(test::call_service(&app, post("n2", now - 600)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::call_service(&app, post("n2", now)).await.status(), StatusCode::OK);
+    }
+}
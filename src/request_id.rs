@@ -0,0 +1,158 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, MwContext, MwError};
+
+/// Incoming ids longer than this are replaced.
+const MAX_INCOMING_LEN: usize = 128;
+
+/// Id of the current request, for route handlers and log layers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<RequestId>()
+                .cloned()
+                .ok_or_else(|| MwError::not_installed("request id").into()),
+        )
+    }
+}
+
+/// Takes the request id from the incoming header or generates a ULID, stores it as
+/// `RequestId` and echoes it on the response.
+#[derive(Clone)]
+pub struct SetRequestId {
+    header_name: HeaderName,
+    trust_incoming: bool,
+    generator: Arc<dyn Fn() -> String + Send + Sync>,
+}
+
+impl SetRequestId {
+    pub fn new() -> Self {
+        SetRequestId {
+            header_name: HeaderName::from_static("x-request-id"),
+            trust_incoming: true,
+            generator: Arc::new(|| ulid::Ulid::new().to_string()),
+        }
+    }
+
+    pub fn with_header_name(mut self, name: HeaderName) -> Self {
+        self.header_name = name;
+        self
+    }
+
+    /// When false, incoming ids are ignored, e.g. on an edge facing untrusted clients.
+    pub fn trust_incoming(mut self, trust: bool) -> Self {
+        self.trust_incoming = trust;
+        self
+    }
+
+    pub fn with_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.generator = Arc::new(generator);
+        self
+    }
+
+    fn incoming(&self, req: &ServiceRequest) -> Option<String> {
+        if !self.trust_incoming {
+            return None;
+        }
+
+        let id = req.headers().get(&self.header_name)?.to_str().ok()?;
+        let valid = !id.is_empty() && id.len() <= MAX_INCOMING_LEN && id.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| id.to_string())
+    }
+}
+
+impl Default for SetRequestId {
+    fn default() -> Self {
+        SetRequestId::new()
+    }
+}
+
+impl fmt::Debug for SetRequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SetRequestId")
+            .field("header_name", &self.header_name)
+            .field("trust_incoming", &self.trust_incoming)
+            .finish()
+    }
+}
+
+impl<B> Handler<B> for SetRequestId {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        let id = self.incoming(&req).unwrap_or_else(|| (self.generator)());
+        MwContext::of(&req).set_request_id(id.clone());
+        req.extensions_mut().insert(RequestId(id));
+        Either::Right(req)
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, _: &CallInfo) -> ServiceResponse<B> {
+        let id = resp.request().extensions().get::<RequestId>().cloned();
+        if let Some(value) = id.and_then(|id| HeaderValue::from_str(&id.0).ok()) {
+            resp.headers_mut().insert(self.header_name.clone(), value);
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App};
+
+    use super::{RequestId, SetRequestId};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(SetRequestId::new().with_generator(|| "generated".to_string())))
+                .route("/", web::get().to(|id: RequestId| async move { id.0 })),
+        )
+        .await;
+
+        let req = test::TestRequest::get().insert_header(("x-request-id", "abc-1")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc-1");
+        assert_eq!(test::read_body(resp).await, "abc-1");
+
+        let req = test::TestRequest::get().insert_header(("x-request-id", "a b")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "generated");
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(SetRequestId::new().trust_incoming(false)))
+                .route("/", web::get().to(|id: RequestId| async move { id.0 })),
+        )
+        .await;
+        let req = test::TestRequest::get().insert_header(("x-request-id", "abc-1")).to_request();
+        let id = test::call_and_read_body(&app, req).await;
+        assert_eq!(id.len(), 26);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+/// A secret that could not be loaded, e.g. an unset variable or unreadable file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecretError {
+    source: String,
+    reason: String,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to load secret from {}: {}", self.source, self.reason)
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+pub type SecretFn = dyn Fn() -> Result<Vec<u8>, String> + Send + Sync;
+
+/// Where a signing secret (CSRF key, JWT HMAC key, webhook secret) comes from, so it need
+/// not be hardcoded. Resolve it with `resolve` while building the middleware: factories
+/// cannot fail at init, so a missing secret is reported before the server starts.
+#[derive(Clone)]
+pub enum SecretSource {
+    Inline(Vec<u8>),
+    /// The value of an environment variable.
+    Env(String),
+    /// The contents of a file, e.g. a mounted Kubernetes secret; one trailing newline is
+    /// dropped.
+    File(PathBuf),
+    /// e.g. a lookup in a secret manager at startup.
+    Callback(Arc<SecretFn>),
+}
+
+impl SecretSource {
+    pub fn callback<F>(f: F) -> Self
+    where
+        F: Fn() -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        SecretSource::Callback(Arc::new(f))
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            SecretSource::Inline(_) => "inline value".to_string(),
+            SecretSource::Env(var) => format!("environment variable {}", var),
+            SecretSource::File(path) => format!("file {}", path.display()),
+            SecretSource::Callback(_) => "callback".to_string(),
+        }
+    }
+
+    /// Empty secrets are rejected.
+    pub fn resolve(&self) -> Result<Secret, SecretError> {
+        let error = |reason: String| SecretError {
+            source: self.describe(),
+            reason,
+        };
+
+        let bytes = match self {
+            SecretSource::Inline(bytes) => bytes.clone(),
+            SecretSource::Env(var) => std::env::var(var).map_err(|e| error(e.to_string()))?.into_bytes(),
+            SecretSource::File(path) => {
+                let mut bytes = std::fs::read(path).map_err(|e| error(e.to_string()))?;
+                if bytes.ends_with(b"\n") {
+                    bytes.pop();
+                    if bytes.ends_with(b"\r") {
+                        bytes.pop();
+                    }
+                }
+                bytes
+            }
+            SecretSource::Callback(f) => f().map_err(error)?,
+        };
+        if bytes.is_empty() {
+            return Err(error("secret is empty".to_string()));
+        }
+        Ok(Secret(bytes))
+    }
+}
+
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Inline(_) => f.write_str("Inline(..)"),
+            SecretSource::Env(var) => f.debug_tuple("Env").field(var).finish(),
+            SecretSource::File(path) => f.debug_tuple("File").field(path).finish(),
+            SecretSource::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// Secret bytes; never printed, and wiped on drop with the `zeroize` feature.
+#[derive(Clone)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    #[cfg(feature = "auth-jwt")]
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Secret(bytes)
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretSource;
+
+    #[test]
+    fn test_resolve() {
+        let secret = SecretSource::callback(|| Ok(b"s3cret".to_vec())).resolve().unwrap();
+        assert_eq!(secret.expose(), b"s3cret");
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+
+        let err = SecretSource::Env("ACTIX_MW_TEST_UNSET_SECRET".to_string()).resolve().unwrap_err();
+        assert!(err.to_string().contains("ACTIX_MW_TEST_UNSET_SECRET"));
+        assert!(SecretSource::Inline(vec![]).resolve().is_err());
+    }
+}
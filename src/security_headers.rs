@@ -0,0 +1,291 @@
+use std::fmt;
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderName, HeaderValue, InvalidHeaderValue},
+    HttpMessage,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, SkipRule, SkipSet};
+
+/// Content-Security-Policy built from directives, e.g.
+/// `ContentSecurityPolicy::new().default_src(&["'self'"]).img_src(&["'self'", "data:"])`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(String, Vec<String>)>,
+    report_only: bool,
+}
+
+impl ContentSecurityPolicy {
+    pub fn new() -> Self {
+        ContentSecurityPolicy::default()
+    }
+
+    /// Adds `sources` to `name`, creating the directive on first use.
+    pub fn directive(mut self, name: &str, sources: &[&str]) -> Self {
+        let sources = sources.iter().map(|s| s.to_string());
+        match self.directives.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => existing.extend(sources),
+            None => self.directives.push((name.to_string(), sources.collect())),
+        }
+        self
+    }
+
+    pub fn default_src(self, sources: &[&str]) -> Self {
+        self.directive("default-src", sources)
+    }
+
+    pub fn script_src(self, sources: &[&str]) -> Self {
+        self.directive("script-src", sources)
+    }
+
+    pub fn style_src(self, sources: &[&str]) -> Self {
+        self.directive("style-src", sources)
+    }
+
+    pub fn img_src(self, sources: &[&str]) -> Self {
+        self.directive("img-src", sources)
+    }
+
+    pub fn connect_src(self, sources: &[&str]) -> Self {
+        self.directive("connect-src", sources)
+    }
+
+    pub fn frame_ancestors(self, sources: &[&str]) -> Self {
+        self.directive("frame-ancestors", sources)
+    }
+
+    pub fn report_uri(self, uri: &str) -> Self {
+        self.directive("report-uri", &[uri])
+    }
+
+    /// Sends `Content-Security-Policy-Report-Only` instead, to trial a policy.
+    pub fn report_only(mut self, report_only: bool) -> Self {
+        self.report_only = report_only;
+        self
+    }
+
+    fn header_name(&self) -> HeaderName {
+        if self.report_only {
+            header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+        } else {
+            header::CONTENT_SECURITY_POLICY
+        }
+    }
+}
+
+impl fmt::Display for ContentSecurityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, sources)) in self.directives.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            f.write_str(name)?;
+            for source in sources {
+                write!(f, " {}", source)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOptions {
+    Deny,
+    SameOrigin,
+}
+
+/// Collects the headers of a `SecurityHeaders`; see `SecurityHeaders::builder`.
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeadersBuilder {
+    headers: Vec<(HeaderName, String)>,
+    overrides: Vec<(SkipRule, SecurityHeadersBuilder)>,
+    skip_rules: SkipSet,
+}
+
+impl SecurityHeadersBuilder {
+    /// HSTS for a year including subdomains, `nosniff`, `DENY` framing,
+    /// `strict-origin-when-cross-origin` and `default-src 'self'`.
+    pub fn recommended() -> Self {
+        SecurityHeadersBuilder::default()
+            .hsts(31_536_000, true, false)
+            .no_sniff()
+            .frame_options(FrameOptions::Deny)
+            .referrer_policy("strict-origin-when-cross-origin")
+            .content_security_policy(ContentSecurityPolicy::new().default_src(&["'self'"]))
+    }
+
+    fn set(mut self, name: HeaderName, value: String) -> Self {
+        self.headers.retain(|(n, _)| *n != name);
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Only takes effect over HTTPS; browsers ignore it on plain HTTP.
+    pub fn hsts(self, max_age: u64, include_subdomains: bool, preload: bool) -> Self {
+        let mut value = format!("max-age={}", max_age);
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if preload {
+            value.push_str("; preload");
+        }
+        self.set(header::STRICT_TRANSPORT_SECURITY, value)
+    }
+
+    pub fn no_sniff(self) -> Self {
+        self.set(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string())
+    }
+
+    pub fn frame_options(self, options: FrameOptions) -> Self {
+        let value = match options {
+            FrameOptions::Deny => "DENY",
+            FrameOptions::SameOrigin => "SAMEORIGIN",
+        };
+        self.set(header::X_FRAME_OPTIONS, value.to_string())
+    }
+
+    pub fn referrer_policy(self, policy: &str) -> Self {
+        self.set(header::REFERRER_POLICY, policy.to_string())
+    }
+
+    /// e.g. `"camera=(), geolocation=(self)"`.
+    pub fn permissions_policy(self, policy: &str) -> Self {
+        self.set(HeaderName::from_static("permissions-policy"), policy.to_string())
+    }
+
+    pub fn content_security_policy(self, csp: ContentSecurityPolicy) -> Self {
+        self.set(csp.header_name(), csp.to_string())
+    }
+
+    /// Requests matching `rule` get the headers of `headers` instead; the first matching
+    /// override wins.
+    pub fn override_for(mut self, rule: impl Into<SkipRule>, headers: SecurityHeadersBuilder) -> Self {
+        self.overrides.push((rule.into(), headers));
+        self
+    }
+
+    /// Requests matching `rule` get no headers at all.
+    pub fn skip(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.skip_rules.push(rule.into());
+        self
+    }
+
+    fn header_values(headers: Vec<(HeaderName, String)>) -> Result<Vec<(HeaderName, HeaderValue)>, InvalidHeaderValue> {
+        headers
+            .into_iter()
+            .map(|(name, value)| Ok((name, HeaderValue::from_str(&value)?)))
+            .collect()
+    }
+
+    pub fn build(self) -> Result<SecurityHeaders, InvalidHeaderValue> {
+        let overrides = self
+            .overrides
+            .into_iter()
+            .map(|(rule, builder)| Ok((rule, Self::header_values(builder.headers)?)))
+            .collect::<Result<_, InvalidHeaderValue>>()?;
+
+        Ok(SecurityHeaders {
+            headers: Self::header_values(self.headers)?,
+            overrides,
+            skip_rules: self.skip_rules,
+        })
+    }
+}
+
+/// Index of the override chosen for a request.
+#[derive(Clone, Copy)]
+struct Override(usize);
+
+/// Adds security headers to every response that does not already carry them, including
+/// responses short-circuited by handlers further in.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    overrides: Vec<(SkipRule, Vec<(HeaderName, HeaderValue)>)>,
+    skip_rules: SkipSet,
+}
+
+impl SecurityHeaders {
+    pub fn builder() -> SecurityHeadersBuilder {
+        SecurityHeadersBuilder::default()
+    }
+
+    pub fn recommended() -> Self {
+        SecurityHeadersBuilder::recommended()
+            .build()
+            .expect("recommended security headers are valid")
+    }
+}
+
+impl<B> Handler<B> for SecurityHeaders {
+    fn skip(&self, req: &ServiceRequest) -> bool {
+        self.skip_rules.matches(req)
+    }
+
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        if let Some(i) = self.overrides.iter().position(|(rule, _)| rule.matches(&req)) {
+            req.extensions_mut().insert(Override(i));
+        }
+        Either::Right(req)
+    }
+
+    fn finalize(&self, mut resp: ServiceResponse<B>, info: &CallInfo) -> ServiceResponse<B> {
+        if info.skipped {
+            return resp;
+        }
+
+        let chosen = resp.request().extensions().get::<Override>().copied();
+        let headers = match chosen {
+            Some(Override(i)) => &self.overrides[i].1,
+            None => &self.headers,
+        };
+
+        let resp_headers = resp.headers_mut();
+        for (name, value) in headers {
+            if !resp_headers.contains_key(name) {
+                resp_headers.insert(name.clone(), value.clone());
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App, HttpResponse};
+
+    use super::{SecurityHeaders, SecurityHeadersBuilder};
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_security_headers() {
+        let headers = SecurityHeadersBuilder::recommended()
+            .override_for("/embed", SecurityHeaders::builder().no_sniff())
+            .skip("/raw")
+            .build()
+            .unwrap();
+        let app = test::init_service(
+            App::new().wrap(Factory::new(headers)).default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((header::REFERRER_POLICY, "no-referrer"))
+                    .finish()
+            })),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.headers().get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(resp.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(), "default-src 'self'");
+        // set by the route, so kept
+        assert_eq!(resp.headers().get(header::REFERRER_POLICY).unwrap(), "no-referrer");
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/embed").to_request()).await;
+        assert_eq!(resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert!(!resp.headers().contains_key(header::X_FRAME_OPTIONS));
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/raw").to_request()).await;
+        assert!(!resp.headers().contains_key(header::X_CONTENT_TYPE_OPTIONS));
+    }
+}
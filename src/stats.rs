@@ -0,0 +1,101 @@
+#[cfg(feature = "stats")]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+pub(crate) enum Outcome {
+    Forwarded,
+    Skipped,
+    Rejected,
+    Reported,
+    Errored,
+}
+
+/// Levels kept by a handler rather than counted per request.
+#[cfg(feature = "stats")]
+pub(crate) enum Gauge {
+    InFlight,
+    Queued,
+}
+
+/// Shared request counters for one middleware. Cloning is cheap and every clone
+/// observes the same counters, so one handle can be given to all workers and to
+/// `App::app_data` for an exporter to read.
+#[cfg(feature = "stats")]
+#[derive(Clone, Debug, Default)]
+pub struct MiddlewareStats {
+    counters: Arc<Counters>,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    skipped: AtomicU64,
+    rejected: AtomicU64,
+    reported: AtomicU64,
+    errored: AtomicU64,
+    in_flight: AtomicU64,
+    queued: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub forwarded: u64,
+    pub skipped: u64,
+    pub rejected: u64,
+    /// Rejections let through by `EnforcementMode::ReportOnly`.
+    pub reported: u64,
+    pub errored: u64,
+    /// Requests holding a `ConcurrencyLimit` slot, when the limit keeps these stats.
+    pub in_flight: u64,
+    /// Requests waiting for one.
+    pub queued: u64,
+}
+
+#[cfg(feature = "stats")]
+impl MiddlewareStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            forwarded: self.counters.forwarded.load(Ordering::Relaxed),
+            skipped: self.counters.skipped.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            reported: self.counters.reported.load(Ordering::Relaxed),
+            errored: self.counters.errored.load(Ordering::Relaxed),
+            in_flight: self.counters.in_flight.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Forwarded => &self.counters.forwarded,
+            Outcome::Skipped => &self.counters.skipped,
+            Outcome::Rejected => &self.counters.rejected,
+            Outcome::Reported => &self.counters.reported,
+            Outcome::Errored => &self.counters.errored,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn gauge(&self, gauge: Gauge) -> &AtomicU64 {
+        match gauge {
+            Gauge::InFlight => &self.counters.in_flight,
+            Gauge::Queued => &self.counters.queued,
+        }
+    }
+
+    pub(crate) fn raise(&self, gauge: Gauge) {
+        self.gauge(gauge).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn lower(&self, gauge: Gauge) {
+        self.gauge(gauge).fetch_sub(1, Ordering::Relaxed);
+    }
+}
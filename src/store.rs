@@ -0,0 +1,339 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use futures_core::future::BoxFuture;
+
+/// Failure of a `Store` backend, e.g. a lost connection.
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl StoreError {
+    pub fn new(message: impl fmt::Display) -> Self {
+        StoreError(message.to_string())
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "store error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Shared key-value state for stateful middlewares (rate limits, replay protection,
+/// idempotency, API keys), so one backend can serve all of them. Middlewares consult it
+/// in `Handler::verify`, before the inner service runs.
+pub trait Store: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, StoreError>>;
+
+    /// Overwrites `key`. With a `ttl` the entry disappears after it.
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// Adds `delta` to the decimal counter at `key`, creating it at zero. `ttl` only
+    /// applies when the counter is created, so the window is fixed from the first hit.
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, StoreError>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>>;
+}
+
+/// Eviction order of an entry: soonest expiry first, then entries without a ttl, oldest
+/// first.
+type Rank = (bool, Option<Instant>, u64);
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    rank: Rank,
+}
+
+impl Entry {
+    fn live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|at| now < at)
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    ranks: BTreeMap<Rank, String>,
+    inserted: u64,
+}
+
+impl Entries {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.map.remove(key) {
+            self.ranks.remove(&entry.rank);
+        }
+    }
+
+    /// Sets `key`, first evicting the entry that expires soonest (an expired one if there
+    /// is any) when a new key would grow the map past `capacity`.
+    fn insert(&mut self, capacity: usize, key: &str, value: Vec<u8>, expires_at: Option<Instant>) {
+        self.remove(key);
+        if self.map.len() >= capacity {
+            if let Some((_, evicted)) = self.ranks.pop_first() {
+                self.map.remove(&evicted);
+            }
+        }
+
+        self.inserted += 1;
+        let rank = (expires_at.is_none(), expires_at, self.inserted);
+        self.ranks.insert(rank, key.to_string());
+        self.map.insert(key.to_string(), Entry { value, expires_at, rank });
+    }
+}
+
+/// Process-local `Store` of at most `capacity` entries. Expired entries are dropped when
+/// touched; a new key in a full store evicts the entry that expires soonest, else the
+/// oldest one without a ttl. Not to be confused with `ratelimit::MemoryStore`, which only
+/// holds token buckets.
+pub struct InMemoryStore {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore::with_capacity(10_000)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        InMemoryStore {
+            capacity: capacity.max(1),
+            entries: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        InMemoryStore::new()
+    }
+}
+
+impl fmt::Debug for InMemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryStore").field("capacity", &self.capacity).finish()
+    }
+}
+
+impl Store for InMemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, StoreError>> {
+        let mut entries = self.lock();
+        let value = match entries.get(key) {
+            Some(e) if e.live(Instant::now()) => Some(e.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.lock().insert(self.capacity, key, value, ttl.map(|ttl| Instant::now() + ttl));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, StoreError>> {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        let result = match entries.map.get_mut(key) {
+            Some(e) if e.live(now) => std::str::from_utf8(&e.value)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or_else(|| StoreError::new(format!("{} is not a counter", key)))
+                .map(|v| {
+                    e.value = (v + delta).to_string().into_bytes();
+                    v + delta
+                }),
+            _ => {
+                entries.insert(self.capacity, key, delta.to_string().into_bytes(), ttl.map(|ttl| now + ttl));
+                Ok(delta)
+            }
+        };
+        Box::pin(async move { result })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.lock().remove(key);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Creates the counter with its expiry, or adds to it, in one step: a separate `PEXPIRE`
+/// could be lost between the two commands and leave a counter that never expires.
+#[cfg(feature = "redis")]
+const INCR_SCRIPT: &str = r"
+if ARGV[2] ~= '0' and redis.call('EXISTS', KEYS[1]) == 0 then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return tonumber(ARGV[1])
+end
+return redis.call('INCRBY', KEYS[1], ARGV[1])
+";
+
+/// `Store` on Redis through a `deadpool-redis` connection pool.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    pool: deadpool_redis::Pool,
+    prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    pub fn new(pool: deadpool_redis::Pool) -> Self {
+        RedisStore {
+            pool,
+            prefix: String::new(),
+        }
+    }
+
+    /// Prepended to every key, to share one database between applications.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    async fn conn(&self) -> Result<deadpool_redis::Connection, StoreError> {
+        self.pool.get().await.map_err(StoreError::new)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Store for RedisStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, StoreError>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            deadpool_redis::redis::cmd("GET")
+                .arg(format!("{}{}", self.prefix, key))
+                .query_async(&mut conn)
+                .await
+                .map_err(StoreError::new)
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let mut cmd = deadpool_redis::redis::cmd("SET");
+            cmd.arg(format!("{}{}", self.prefix, key)).arg(value);
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(ttl.as_millis() as u64);
+            }
+            cmd.query_async(&mut conn).await.map_err(StoreError::new)
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, StoreError>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            // 0 for no expiry; a ttl under a millisecond still expires
+            let ttl = ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1));
+            deadpool_redis::redis::Script::new(INCR_SCRIPT)
+                .key(format!("{}{}", self.prefix, key))
+                .arg(delta)
+                .arg(ttl)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(StoreError::new)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            deadpool_redis::redis::cmd("DEL")
+                .arg(format!("{}{}", self.prefix, key))
+                .query_async(&mut conn)
+                .await
+                .map_err(StoreError::new)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::rt;
+
+    use super::{InMemoryStore, Store};
+
+    #[actix_web::test]
+    async fn test_memory_store() {
+        let store = InMemoryStore::with_capacity(2);
+        assert_eq!(store.incr("a", 1, Some(Duration::from_millis(20))).await.unwrap(), 1);
+        assert_eq!(store.incr("a", 2, None).await.unwrap(), 3);
+        store.set("b", b"x".to_vec(), None).await.unwrap();
+        assert_eq!(store.get("b").await.unwrap(), Some(b"x".to_vec()));
+        assert!(store.incr("b", 1, None).await.is_err());
+
+        rt::time::sleep(Duration::from_millis(30)).await;
+        // a new counter in a full store evicts the expired one
+        assert_eq!(store.incr("c", 1, None).await.unwrap(), 1);
+        assert!(store.lock().get("a").is_none());
+        assert_eq!(store.incr("a", 1, None).await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_capacity() {
+        let store = InMemoryStore::with_capacity(2);
+        store.set("a", b"1".to_vec(), None).await.unwrap();
+        store.set("b", b"2".to_vec(), Some(Duration::from_secs(60))).await.unwrap();
+        store.set("c", b"3".to_vec(), None).await.unwrap();
+        // all live: the one expiring soonest goes first, then the oldest without a ttl
+        assert_eq!(store.get("b").await.unwrap(), None);
+        store.set("d", b"4".to_vec(), None).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.get("c").await.unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get("d").await.unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.lock().map.len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Instant;
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    test::TestRequest,
+    HttpResponse,
+};
+use futures_util::future::Either;
+
+use crate::{CallInfo, Handler, MwContext};
+
+/// What a handler did with a request, without running an inner service.
+pub enum HandlerOutcome<B> {
+    Skipped(ServiceRequest),
+    ShortCircuit(ServiceResponse<B>),
+    Forwarded(ServiceRequest),
+}
+
+impl<B> HandlerOutcome<B> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HandlerOutcome::Skipped(_) => "skipped",
+            HandlerOutcome::ShortCircuit(_) => "short-circuit",
+            HandlerOutcome::Forwarded(_) => "forwarded",
+        }
+    }
+}
+
+/// Runs `skip` and `process` the same way `Middleware::call` does.
+pub fn call_handler<H, B>(handler: &H, req: TestRequest) -> HandlerOutcome<B>
+where
+    H: Handler<B>,
+{
+    let req = req.to_srv_request();
+    if handler.skip(&req) {
+        return HandlerOutcome::Skipped(req);
+    }
+
+    match handler.process(req) {
+        Either::Left(res) => HandlerOutcome::ShortCircuit(res),
+        Either::Right(req) => HandlerOutcome::Forwarded(req),
+    }
+}
+
+/// Runs `post` on `resp` as if the inner service had returned it for `req`.
+pub fn call_post<H, B>(handler: &H, req: TestRequest, resp: HttpResponse<B>) -> ServiceResponse<B>
+where
+    H: Handler<B>,
+{
+    let resp = ServiceResponse::new(req.to_http_request(), resp);
+    let context = MwContext::of(resp.request());
+    handler.post(resp, &CallInfo::finish(Instant::now(), false, false, &context))
+}
+
+/// Asserts the handler short-circuits `req` and evaluates to the response.
+#[macro_export]
+macro_rules! assert_short_circuits {
+    ($handler:expr, $req:expr) => {
+        match $crate::testing::call_handler(&$handler, $req) {
+            $crate::testing::HandlerOutcome::ShortCircuit(res) => res,
+            other => panic!("expected short-circuit, got {}", other.kind()),
+        }
+    };
+}
+
+/// Asserts the handler forwards `req` and evaluates to the forwarded request.
+#[macro_export]
+macro_rules! assert_forwards {
+    ($handler:expr, $req:expr) => {
+        match $crate::testing::call_handler(&$handler, $req) {
+            $crate::testing::HandlerOutcome::Forwarded(req) => req,
+            other => panic!("expected forwarded, got {}", other.kind()),
+        }
+    };
+}
+
+/// Asserts `post` sets `header` on `resp` and evaluates to its value.
+#[macro_export]
+macro_rules! assert_post_sets_header {
+    ($handler:expr, $req:expr, $resp:expr, $header:expr) => {{
+        let res = $crate::testing::call_post(&$handler, $req, $resp);
+        match res.headers().get($header) {
+            Some(value) => value.clone(),
+            None => panic!("expected post to set header {:?}", $header),
+        }
+    }};
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::header::ContentType,
+    Error, HttpResponse,
+};
+use futures_util::future::Either;
+
+use crate::{Handler, MwContext, MwError, SkipRule};
+
+/// Answers `504 Gateway Timeout` when the inner service takes longer than the deadline
+/// for the request path. The call fails with the timeout error, which middlewares further
+/// out see in `on_error`.
+#[derive(Clone, Debug)]
+pub struct Timeout {
+    default: Option<Duration>,
+    overrides: Vec<(SkipRule, Option<Duration>)>,
+    body: Option<String>,
+}
+
+impl Timeout {
+    pub fn new(default: Duration) -> Self {
+        Timeout {
+            default: Some(default),
+            overrides: vec![],
+            body: None,
+        }
+    }
+
+    /// Requests matching `rule` get `timeout` instead of the default; the first matching
+    /// override wins.
+    pub fn with_override(mut self, rule: impl Into<SkipRule>, timeout: Duration) -> Self {
+        self.overrides.push((rule.into(), Some(timeout)));
+        self
+    }
+
+    /// Requests matching `rule` have no deadline, e.g. long-polling or upload routes.
+    pub fn without_timeout(mut self, rule: impl Into<SkipRule>) -> Self {
+        self.overrides.push((rule.into(), None));
+        self
+    }
+
+    /// A plain-text body instead of the `MwError::Timeout` JSON error.
+    pub fn with_body(mut self, body: impl Into<String>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+}
+
+impl<B> Handler<B> for Timeout {
+    fn process(&self, req: ServiceRequest) -> Either<ServiceResponse<B>, ServiceRequest> {
+        Either::Right(req)
+    }
+
+    fn timeout(&self, req: &ServiceRequest) -> Option<Duration> {
+        match self.overrides.iter().find(|(rule, _)| rule.matches(req)) {
+            Some((_, timeout)) => *timeout,
+            None => self.default,
+        }
+    }
+
+    fn on_timeout(&self, _: &MwContext) -> Error {
+        match &self.body {
+            Some(body) => {
+                let resp = HttpResponse::GatewayTimeout()
+                    .content_type(ContentType::plaintext())
+                    .body(body.clone());
+                InternalError::from_response(MwError::Timeout, resp).into()
+            }
+            None => MwError::Timeout.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{body, dev::Service, rt, test, web, App, HttpResponse};
+
+    use super::Timeout;
+    use crate::Factory;
+
+    #[actix_web::test]
+    async fn test_timeout() {
+        let slow = || async {
+            rt::time::sleep(Duration::from_millis(50)).await;
+            HttpResponse::Ok().finish()
+        };
+        let timeout = Timeout::new(Duration::from_millis(10))
+            .without_timeout("/upload")
+            .with_body("too slow");
+        let app = test::init_service(
+            App::new()
+                .wrap(Factory::new(timeout))
+                .route("/", web::get().to(slow))
+                .route("/upload", web::get().to(slow)),
+        )
+        .await;
+
+        let err = app.call(test::TestRequest::get().to_request()).await.unwrap_err();
+        let resp = err.error_response();
+        assert_eq!(resp.status(), 504);
+        assert_eq!(body::to_bytes(resp.into_body()).await.ok().unwrap(), "too slow");
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/upload").to_request()).await;
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,50 @@
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) type Traced<F> = tracing::instrument::Instrumented<F>;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) type Traced<F> = F;
+
+/// The per-request span opened by `Middleware::call`; a no-op without the `tracing` feature.
+#[derive(Clone)]
+pub struct CallSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl CallSpan {
+    #[allow(unused_variables)]
+    pub(crate) fn new(handler: &str) -> Self {
+        CallSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "middleware",
+                handler,
+                outcome = tracing::field::Empty
+            ),
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn outcome(&self, outcome: &'static str) {
+        #[cfg(feature = "tracing")]
+        self.span.record("outcome", outcome);
+    }
+
+    pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        return self.span.in_scope(f);
+
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+
+    pub(crate) fn instrument<F: Future>(&self, fut: F) -> Traced<F> {
+        #[cfg(feature = "tracing")]
+        return tracing::Instrument::instrument(fut, self.span.clone());
+
+        #[cfg(not(feature = "tracing"))]
+        fut
+    }
+}